@@ -0,0 +1,50 @@
+use std::time::{Duration, Instant};
+
+use bytecode;
+use parser::interpret_line;
+use rpn::{self, Stack};
+
+/// Scripts the benchmark runs: each one is a short body repeated many
+/// times, the shape a loop takes once unrolled.
+fn scripts() -> Vec<(&'static str, String)> {
+    vec![
+        ("sum", format!("0{}", " 1 +".repeat(1000))),
+        ("neg", format!("true{}", " ~".repeat(1000))),
+        ("mixed", format!("0{}", " 3 + 2 <-> ~ <-> + 7 + ~".repeat(200))),
+        ("compare", "1 1 = ~ ".repeat(500)),
+    ]
+}
+
+fn time<F: FnMut() -> rpn::Result<()>>(iterations: usize, mut f: F) -> rpn::Result<Duration> {
+    let start = Instant::now();
+    for _ in 0..iterations {
+        f()?;
+    }
+    Ok(start.elapsed())
+}
+
+/// Runs every script `iterations` times through the token evaluator and
+/// through the compiled bytecode, and prints the timings side by side.
+pub fn run_benchmark(iterations: usize) -> rpn::Result<()> {
+    println!("{:<10}{:>15}{:>15}{:>10}", "script", "evaluator", "bytecode", "speedup");
+
+    for (name, script) in scripts() {
+        let interpreted = time(iterations, || {
+            interpret_line(&mut Stack::new(), &script)
+        })?;
+
+        let mut stack = Stack::new();
+        let program = bytecode::compile(&script, stack.words_mut())?;
+        let compiled = time(iterations, || {
+            bytecode::run(&program, &mut Stack::new())
+        })?;
+
+        println!("{:<10}{:>13.2?}{:>15.2?}{:>9.1}x",
+                 name,
+                 interpreted,
+                 compiled,
+                 interpreted.as_secs_f64() / compiled.as_secs_f64());
+    }
+
+    Ok(())
+}
@@ -0,0 +1,1360 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs;
+use std::io;
+use std::iter;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use parser::{get_operation, get_value, op_token};
+use rpn::{self, Elt, Op, Snapshot, Stack};
+use stdlib;
+use matrix::Matrix;
+use units::Unit;
+
+#[derive(Debug, Clone, PartialEq)]
+/// A single bytecode instruction.
+pub enum Instr {
+    /// Pushes a constant onto the stack.
+    Push(Elt),
+    /// Evaluates a builtin operation on the stack.
+    Op(Op),
+    /// Runs the user word stored at the given address of the dictionary.
+    Call(usize),
+    /// Pops a value into the variable in the given slot of the dictionary.
+    Store(usize),
+    /// Pushes the value of the variable in the given slot.
+    Load(usize),
+    /// Gives the number on top a unit.
+    Unit(Unit),
+    /// Converts the quantity on top to a unit.
+    Convert(Unit),
+    /// Starts a `try` block: an error before the matching `EndTry` restores
+    /// the stack and jumps to the handler at the given index.
+    Try(usize),
+    /// Ends a `try` block that raised no error, jumping over its handler
+    /// to the given index.
+    EndTry(usize),
+    /// Starts a `sample` block: pops a range `from to step`, then runs the
+    /// block once for each x in it, up to the matching `EndSample`, before
+    /// going on at the given index with the matrix of points.
+    Sample(usize),
+    /// Ends a run of a `sample` block, taking the value on top as the y
+    /// of its x.
+    EndSample,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+/// A compiled sequence of instructions, ready to be run by the `Vm`.
+pub struct Program {
+    pub code: Vec<Instr>,
+    /// For each instruction, the index of the token it was compiled from
+    /// in its source line.
+    pub positions: Vec<usize>,
+}
+
+impl Program {
+    /// Appends an instruction compiled from the token at `pos`.
+    pub fn push(&mut self, instr: Instr, pos: usize) {
+        self.code.push(instr);
+        self.positions.push(pos);
+    }
+}
+
+/// The user words and variables known to a stack.
+///
+/// Words are compiled once at definition and addressed by their index, so
+/// calls never go back to the name. Redefining a word gives it a new
+/// address: code compiled against the old definition keeps running it.
+/// Variables get a slot the first time they are stored to (`!name`), and
+/// keep it from then on.
+#[derive(Debug, Clone, Default)]
+pub struct Dictionary {
+    names: HashMap<String, usize>,
+    bodies: Vec<(String, Program)>,
+    var_names: HashMap<String, usize>,
+    vars: Vec<(String, Option<Elt>)>,
+    /// The most definitions and variables it takes, see `Limits::words`.
+    max: Option<usize>,
+}
+
+impl Dictionary {
+    /// Creates an empty dictionary.
+    pub fn new() -> Dictionary {
+        Dictionary::default()
+    }
+
+    /// Bounds the definitions and variables it takes; past that, new ones
+    /// fail with `Error::WordLimit`.
+    pub fn set_max(&mut self, max: Option<usize>) {
+        self.max = max;
+    }
+
+    /// Fails unless there is room for one more definition or variable.
+    fn check_room(&self) -> rpn::Result<()> {
+        match self.max {
+            Some(max) if self.bodies.len() + self.vars.len() >= max => Err(rpn::Error::WordLimit),
+            _ => Ok(()),
+        }
+    }
+
+    /// Adds (or replaces) a word and returns its address.
+    pub fn define(&mut self, name: &str, body: Program) -> rpn::Result<usize> {
+        self.check_room()?;
+        let addr = self.bodies.len();
+        self.bodies.push((name.to_string(), body));
+        self.names.insert(name.to_string(), addr);
+        Ok(addr)
+    }
+
+    /// Resolves a word name to its current address.
+    pub fn lookup(&self, name: &str) -> Option<usize> {
+        self.names.get(name).cloned()
+    }
+
+    /// The body of the word stored at `addr`.
+    pub fn body(&self, addr: usize) -> &Program {
+        &self.bodies[addr].1
+    }
+
+    /// The name the word at `addr` was defined with.
+    pub fn name(&self, addr: usize) -> &str {
+        &self.bodies[addr].0
+    }
+
+    /// The names of the words defined, in no order.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.names.keys().map(String::as_str)
+    }
+
+    /// Every definition made so far, by address, including the ones a
+    /// later definition replaced.
+    pub fn definitions(&self) -> impl Iterator<Item = (&str, &Program)> {
+        self.bodies.iter().map(|(name, body)| (name.as_str(), body))
+    }
+
+    /// The slot of a variable, creating it if it does not exist yet.
+    pub fn declare(&mut self, name: &str) -> rpn::Result<usize> {
+        if let Some(slot) = self.variable(name) {
+            return Ok(slot);
+        }
+        self.check_room()?;
+        let slot = self.vars.len();
+        self.vars.push((name.to_string(), None));
+        self.var_names.insert(name.to_string(), slot);
+        Ok(slot)
+    }
+
+    /// Resolves a variable name to its slot.
+    pub fn variable(&self, name: &str) -> Option<usize> {
+        self.var_names.get(name).cloned()
+    }
+
+    /// The name of the variable in `slot`.
+    pub fn var_name(&self, slot: usize) -> &str {
+        &self.vars[slot].0
+    }
+
+    /// The value of the variable in `slot`, `None` until it is stored to.
+    pub fn value(&self, slot: usize) -> Option<&Elt> {
+        self.vars[slot].1.as_ref()
+    }
+
+    /// Stores a value in the variable in `slot`.
+    pub fn set(&mut self, slot: usize, val: Elt) {
+        self.vars[slot].1 = Some(val);
+    }
+
+    /// Forgets the values of all variables, which keep their slots.
+    pub fn unset_all(&mut self) {
+        for var in &mut self.vars {
+            var.1 = None;
+        }
+    }
+
+    /// Every variable that holds a value, with its name.
+    pub fn variables(&self) -> impl Iterator<Item = (&str, &Elt)> {
+        self.vars.iter().filter_map(|(name, val)| {
+            val.as_ref().map(|v| (name.as_str(), v))
+        })
+    }
+}
+
+/// The words of the syntax of definitions, `try` blocks and modules.
+const KEYWORDS: [&str; 9] = [":", ";", "try", "catch", "sample", "[", "]", "include", "namespace"];
+
+/// The most points a `sample` block takes.
+pub const MAX_SAMPLES: usize = 10_000;
+
+/// Whether `name` can name a new word or variable: it must not read as a
+/// builtin operation, a literal or a store. A namespace and a name may be
+/// joined with a colon, `math:sq`.
+pub fn is_new_name(name: &str) -> bool {
+    !KEYWORDS.contains(&name) && !name.starts_with('!')
+        && name.split(':').all(|part| !part.is_empty())
+        && get_operation(name).is_err() && get_value(name).is_err()
+}
+
+/// Compiles a line of input into a `Program`.
+///
+/// Every token is resolved exactly once: literals become `Push`, builtin
+/// operations become `Op`, user words become `Call` with the word's
+/// address and units become `Unit`, or `Convert` after a `>` (`>m`).
+/// Definitions (`: name body ;`) are compiled into the dictionary as they
+/// are met and emit no code themselves.
+///
+/// `try [ body ] catch [ handler ]` runs the body, and if it fails
+/// restores the stack as it was before the block, pushes what went wrong
+/// and runs the handler: the thrown value for `throw`, else the kind of
+/// the error as a string (`"Underflow"`). Blocks nest, and may be used in
+/// definitions.
+///
+/// `include "file.rpn"` compiles a file in place, see `Loader`, and
+/// `namespace name` puts the words and variables defined in the rest of
+/// the line or file in a namespace: after `namespace math`, `: sq ... ;`
+/// defines `math:sq`, which the code that follows may call `sq`. Without a
+/// loader, only the standard library can be included.
+pub fn compile(buf: &str, words: &mut Dictionary) -> rpn::Result<Program> {
+    compile_located(buf, words).map_err(|e| e.error)
+}
+
+#[derive(Debug)]
+/// An error along with the position of the token of the line it comes
+/// from, when known.
+pub struct Located {
+    pub error: rpn::Error,
+    pub pos: Option<usize>,
+}
+
+impl Located {
+    fn at(pos: usize) -> impl Fn(rpn::Error) -> Located {
+        move |error| Located { error, pos: Some(pos) }
+    }
+}
+
+impl From<rpn::Error> for Located {
+    fn from(error: rpn::Error) -> Located {
+        Located { error, pos: None }
+    }
+}
+
+/// Splits a line into tokens at whitespace, along with their positions.
+/// A matrix literal is one token however many spaces it holds: it runs
+/// from a word starting with `[[` to the bracket closing it, and takes the
+/// position of its first word. So is a string literal, from a word
+/// starting with `"` to the next one ending with a `"` that is not escaped.
+/// Comments, from a `(` to the next word ending with `)`, are left out.
+pub fn tokenize(buf: &str) -> Vec<(usize, &str)> {
+    let offset = |word: &str| word.as_ptr() as usize - buf.as_ptr() as usize;
+    let depth = |word: &str| word.matches('[').count() as isize - word.matches(']').count() as isize;
+
+    let mut tokens = Vec::new();
+    let mut words = buf.split_whitespace().enumerate();
+    while let Some((pos, word)) = words.next() {
+        let start = offset(word);
+        let mut end = start + word.len();
+        if word == "(" {
+            words.by_ref().find(|&(_, w)| w.ends_with(')'));
+            continue;
+        } else if word.starts_with("[[") {
+            let mut open = depth(word);
+            while open > 0 {
+                match words.next() {
+                    Some((_, w)) => {
+                        open += depth(w);
+                        end = offset(w) + w.len();
+                    },
+                    None => break,
+                }
+            }
+        } else if word.starts_with('"') && !closes_string(&word[1..]) {
+            for (_, w) in words.by_ref() {
+                end = offset(w) + w.len();
+                if closes_string(w) {
+                    break;
+                }
+            }
+        }
+        tokens.push((pos, &buf[start..end]));
+    }
+    tokens
+}
+
+/// Whether a word of a string literal ends it: it ends with a quote that
+/// is not escaped by a backslash.
+fn closes_string(word: &str) -> bool {
+    match word.strip_suffix('"') {
+        Some(rest) => (rest.len() - rest.trim_end_matches('\\').len()).is_multiple_of(2),
+        None => false,
+    }
+}
+
+/// Like `compile`, telling which token a syntax error is at. An unfinished
+/// definition fails at its `:`, an unfinished `try` block at its `try`.
+pub fn compile_located(buf: &str, words: &mut Dictionary) -> Result<Program, Located> {
+    compile_with(buf, words, &Loader::default())
+}
+
+/// Like `compile_located`, including files through `loader`. Errors in an
+/// included file are at its `include`, and so is the code compiled from it.
+pub fn compile_with(buf: &str, words: &mut Dictionary, loader: &Loader) -> Result<Program, Located> {
+    let mut compiler = Compiler { words, loader, files: Vec::new(), namespace: None, at: None };
+    let mut program = Program::default();
+    compiler.block(&mut tokenize(buf).into_iter(), &mut program, None)?;
+    Ok(program)
+}
+
+#[derive(Debug, Clone, Default)]
+/// Where `include` finds files: next to the file including them (in the
+/// current directory for a line of input), then in the directories of the
+/// path, in order, then in the standard library. Only the standard library
+/// is there unless `io` is on.
+pub struct Loader {
+    pub path: Vec<PathBuf>,
+    pub io: bool,
+}
+
+impl Loader {
+    /// Finds files on the include path of `stack`, if its limits allow I/O.
+    pub fn of(stack: &Stack) -> Loader {
+        Loader { path: stack.include_path().to_vec(), io: stack.limits().io }
+    }
+
+    /// Reads the file `name` included from the directory `dir`. Returns
+    /// the path telling the file apart from the others, and its contents.
+    fn load(&self, name: &str, dir: &Path) -> rpn::Result<(PathBuf, String)> {
+        if self.io {
+            let found = iter::once(dir).chain(self.path.iter().map(PathBuf::as_path))
+                .map(|dir| dir.join(name))
+                .find(|path| path.is_file());
+            if let Some(path) = found {
+                let text = fs::read_to_string(&path).map_err(rpn::Error::file(&path))?;
+                let path = path.canonicalize().map_err(rpn::Error::file(&path))?;
+                return Ok((path, text));
+            }
+        }
+        match stdlib::get(name) {
+            Some(text) => Ok((Path::new(stdlib::DIR).join(name), text.to_string())),
+            None if self.io => Err(rpn::Error::file(name)(io::ErrorKind::NotFound.into())),
+            None => Err(rpn::Error::file(name)(io::ErrorKind::PermissionDenied.into())),
+        }
+    }
+}
+
+fn syntax(pos: usize) -> Located {
+    Located::at(pos)(rpn::Error::Syntax)
+}
+
+/// Compiles a line and the files it includes into the dictionary.
+struct Compiler<'a> {
+    words: &'a mut Dictionary,
+    loader: &'a Loader,
+    /// The files being included, outermost first.
+    files: Vec<PathBuf>,
+    /// The namespace of the line or file being compiled.
+    namespace: Option<String>,
+    /// The position of the `include` of the file being compiled, which
+    /// its code and errors take.
+    at: Option<usize>,
+}
+
+impl<'a> Compiler<'a> {
+    /// The next token, at the position of the `include` it comes from if
+    /// any.
+    fn next<'t, I>(&self, tokens: &mut I) -> Option<(usize, &'t str)>
+        where I: Iterator<Item = (usize, &'t str)>
+    {
+        tokens.next().map(|(pos, t)| (self.at.unwrap_or(pos), t))
+    }
+
+    /// The full name of `name` in the current namespace.
+    fn qualify(&self, name: &str) -> String {
+        match self.namespace {
+            Some(ref namespace) if !name.contains(':') => format!("{}:{}", namespace, name),
+            _ => name.to_string(),
+        }
+    }
+
+    /// Compiles tokens into `out` up to the end of the source, or when
+    /// `end` is given up to its token, which closes a block opened at its
+    /// position. Definitions, includes and namespaces can only be made
+    /// outside of blocks.
+    fn block<'t, I>(&mut self, tokens: &mut I, out: &mut Program,
+                    end: Option<(&str, usize)>) -> Result<(), Located>
+        where I: Iterator<Item = (usize, &'t str)>
+    {
+        while let Some((pos, t)) = self.next(tokens) {
+            match t {
+                t if end.is_some_and(|(e, _)| e == t) => return Ok(()),
+                ":" if end.is_none() => {
+                    let (name_pos, name) = self.next(tokens).ok_or_else(|| syntax(pos))?;
+                    let full = self.qualify(name);
+                    if !is_new_name(name) || !is_new_name(&full)
+                        || self.words.variable(&full).is_some() {
+                        return Err(syntax(name_pos));
+                    }
+
+                    let mut body = Program::default();
+                    self.block(tokens, &mut body, Some((";", pos)))?;
+                    self.words.define(&full, body).map_err(Located::at(pos))?;
+                },
+                "include" if end.is_none() => self.include(tokens, out, pos)?,
+                "namespace" if end.is_none() => {
+                    let (name_pos, name) = self.next(tokens).ok_or_else(|| syntax(pos))?;
+                    if !is_new_name(name) || name.contains(':') {
+                        return Err(syntax(name_pos));
+                    }
+                    self.namespace = Some(name.to_string());
+                },
+                "try" => self.try_block(tokens, out, pos)?,
+                "sample" => self.sample_block(tokens, out, pos)?,
+                t if KEYWORDS.contains(&t) => return Err(syntax(pos)),
+                t => out.push(self.token(t).map_err(Located::at(pos))?, pos),
+            }
+        }
+
+        match end {
+            Some((_, start)) => Err(syntax(start)),
+            None => Ok(()),
+        }
+    }
+
+    /// Compiles the file named after an `include` at `pos` into `out`.
+    /// The file starts outside of any namespace.
+    fn include<'t, I>(&mut self, tokens: &mut I, out: &mut Program,
+                      pos: usize) -> Result<(), Located>
+        where I: Iterator<Item = (usize, &'t str)>
+    {
+        let name = match self.next(tokens) {
+            Some((name_pos, t)) => match get_value(t) {
+                Ok(Elt::Str(name)) => name,
+                _ => return Err(syntax(name_pos)),
+            },
+            None => return Err(syntax(pos)),
+        };
+        let dir = self.files.last()
+            .and_then(|file| file.parent())
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+        let (path, text) = self.loader.load(&name, &dir).map_err(Located::at(pos))?;
+        if self.files.contains(&path) {
+            return Err(Located::at(pos)(rpn::Error::Cycle(path)));
+        }
+
+        let namespace = self.namespace.take();
+        let at = self.at.replace(pos);
+        self.files.push(path);
+        let result = self.block(&mut tokenize(&text).into_iter(), out, None);
+        self.files.pop();
+        self.namespace = namespace;
+        self.at = at;
+        result
+    }
+
+    /// Compiles a `try` block, after its `try` at `pos`: a `Try` to the
+    /// handler, the body, an `EndTry` past the handler, then the handler.
+    fn try_block<'t, I>(&mut self, tokens: &mut I, out: &mut Program,
+                        pos: usize) -> Result<(), Located>
+        where I: Iterator<Item = (usize, &'t str)>
+    {
+        self.expect(tokens, "[", pos)?;
+        let start = out.code.len();
+        out.push(Instr::Try(0), pos);
+        self.block(tokens, out, Some(("]", pos)))?;
+
+        let catch_pos = self.expect(tokens, "catch", pos)?;
+        self.expect(tokens, "[", pos)?;
+        let end_try = out.code.len();
+        out.push(Instr::EndTry(0), catch_pos);
+        self.block(tokens, out, Some(("]", pos)))?;
+
+        out.code[start] = Instr::Try(end_try + 1);
+        out.code[end_try] = Instr::EndTry(out.code.len());
+        Ok(())
+    }
+
+    /// Compiles a `sample` block, after its `sample` at `pos`: a `Sample`
+    /// past the block, the body, then an `EndSample`.
+    fn sample_block<'t, I>(&mut self, tokens: &mut I, out: &mut Program,
+                           pos: usize) -> Result<(), Located>
+        where I: Iterator<Item = (usize, &'t str)>
+    {
+        self.expect(tokens, "[", pos)?;
+        let start = out.code.len();
+        out.push(Instr::Sample(0), pos);
+        self.block(tokens, out, Some(("]", pos)))?;
+        out.push(Instr::EndSample, pos);
+        out.code[start] = Instr::Sample(out.code.len());
+        Ok(())
+    }
+
+    /// Takes the next token, which must be `word`, and returns its
+    /// position. Running out of tokens fails at `pos`, where the block
+    /// started.
+    fn expect<'t, I>(&self, tokens: &mut I, word: &str, pos: usize) -> Result<usize, Located>
+        where I: Iterator<Item = (usize, &'t str)>
+    {
+        match self.next(tokens) {
+            Some((p, t)) if t == word => Ok(p),
+            Some((p, _)) => Err(syntax(p)),
+            None => Err(syntax(pos)),
+        }
+    }
+
+    /// Resolves a token. In a namespace, its words and variables hide the
+    /// ones of the same name outside.
+    fn token(&mut self, token: &str) -> rpn::Result<Instr> {
+        let local = self.qualify(token);
+        let store = token.strip_prefix('!').filter(|name| !name.is_empty());
+        let stored = store.map(|name| self.qualify(name));
+        let words = &mut *self.words;
+        if let Some(addr) = words.lookup(&local).or_else(|| words.lookup(token)) {
+            Ok(Instr::Call(addr))
+        } else if let Ok(op) = get_operation(token) {
+            Ok(Instr::Op(op))
+        } else if let Some(slot) = words.variable(&local).or_else(|| words.variable(token)) {
+            Ok(Instr::Load(slot))
+        } else if let (Some(name), Some(full)) = (store, stored) {
+            if !is_new_name(name) || !is_new_name(&full) || words.lookup(&full).is_some() {
+                return Err(rpn::Error::Syntax);
+            }
+            words.declare(&full).map(Instr::Store)
+        } else if let Some(unit) = token.strip_prefix('>').and_then(Unit::parse) {
+            Ok(Instr::Convert(unit))
+        } else {
+            get_value(token)
+                .map(Instr::Push)
+                .or_else(|e| Unit::parse(token).map(Instr::Unit).ok_or(e))
+        }
+    }
+}
+
+/// The source token an instruction stands for.
+pub fn instr_token(instr: &Instr, words: &Dictionary) -> String {
+    match *instr {
+        Instr::Push(ref v) => v.to_string(),
+        Instr::Op(op) => op_token(op).to_string(),
+        Instr::Call(addr) => words.name(addr).to_string(),
+        Instr::Store(slot) => format!("!{}", words.var_name(slot)),
+        Instr::Load(slot) => words.var_name(slot).to_string(),
+        Instr::Unit(ref unit) => unit.to_string(),
+        Instr::Convert(ref unit) => format!(">{}", unit),
+        Instr::Try(_) => "try".to_string(),
+        Instr::EndTry(_) => "catch".to_string(),
+        Instr::Sample(_) => "sample".to_string(),
+        Instr::EndSample => "]".to_string(),
+    }
+}
+
+/// Turns a program back into source tokens, e.g. to show what the
+/// optimizer did with it, or to save a word.
+pub fn to_source(program: &Program, words: &Dictionary) -> String {
+    let mut tokens = Vec::new();
+    // Where the handlers of the `try` blocks met so far end.
+    let mut ends = Vec::new();
+    for (i, instr) in program.code.iter().enumerate() {
+        while ends.last() == Some(&i) {
+            ends.pop();
+            tokens.push("]".to_string());
+        }
+        match *instr {
+            Instr::Try(_) => tokens.push("try [".to_string()),
+            Instr::Sample(_) => tokens.push("sample [".to_string()),
+            Instr::EndTry(after) => {
+                ends.push(after);
+                tokens.push("] catch [".to_string());
+            },
+            ref instr => tokens.push(instr_token(instr, words)),
+        }
+    }
+    tokens.extend(ends.iter().map(|_| "]".to_string()));
+    tokens.join(" ")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// A position inside the running code: the main program or a user word.
+pub struct Frame {
+    /// The address of the word being run, `None` for the main program.
+    pub word: Option<usize>,
+    /// The index of the instruction within the word or program.
+    pub pc: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// The instruction a `Vm` is about to execute, and where it comes from.
+pub struct Next {
+    pub instr: Instr,
+    pub frame: Frame,
+    /// The index of the token the instruction was compiled from.
+    pub pos: usize,
+    /// How many word calls deep the instruction is.
+    pub depth: usize,
+}
+
+/// A `try` or `sample` block being run.
+struct Handler {
+    /// How many frames were running when the block started: the last one
+    /// holds the block.
+    frames: usize,
+    /// The stack as it was before the block.
+    snapshot: Snapshot,
+    kind: Block,
+}
+
+enum Block {
+    /// A `try` block, with the index of its handler in the code.
+    Try(usize),
+    Sample(Sampler),
+}
+
+/// Where the range of a `sample` block starts, and its step.
+enum Range {
+    /// `from` and `step` are both integers, so is x.
+    Ints(i64, i64),
+    Floats(f64, f64),
+}
+
+/// The progress of a `sample` block through its range.
+struct Sampler {
+    /// The index of the first instruction of the block.
+    body: usize,
+    /// The index of the instruction after the block.
+    end: usize,
+    range: Range,
+    /// How many points the range has.
+    count: usize,
+    /// How many of them were run so far.
+    taken: usize,
+    /// The points kept so far, as rows `x y`.
+    points: Vec<Vec<f32>>,
+}
+
+impl Sampler {
+    /// Starts sampling the range `from to step`, from the block at `body`
+    /// to `end`. An empty or too long range is a domain error.
+    fn new(from: &Elt, to: &Elt, step: &Elt, body: usize, end: usize) -> rpn::Result<Sampler> {
+        let (range, last) = match (from, to, step) {
+            // All integers: counted exactly, even past 2^53.
+            (&Elt::Int(from), &Elt::Int(to), &Elt::Int(step)) => {
+                if step == 0 {
+                    return Err(rpn::Error::Domain);
+                }
+                let (span, step128) = (i128::from(to) - i128::from(from), i128::from(step));
+                let last = span.div_euclid(step128) - i128::from(step < 0 && span % step128 != 0);
+                (Range::Ints(from, step), last as f64)
+            },
+            _ => match (from.as_float(), to.as_float(), step.as_float()) {
+                (Some(x), Some(to), Some(dx)) => {
+                    let range = match (from, step) {
+                        (&Elt::Int(from), &Elt::Int(step)) => Range::Ints(from, step),
+                        _ => Range::Floats(x, dx),
+                    };
+                    (range, ((to - x) / dx + 1e-9).floor())
+                },
+                _ => return Err(rpn::Error::Type),
+            },
+        };
+        if !(last >= 0.0 && last < MAX_SAMPLES as f64) {
+            return Err(rpn::Error::Domain);
+        }
+        Ok(Sampler {
+            body, end, range,
+            count: last as usize + 1,
+            taken: 0,
+            points: Vec::new(),
+        })
+    }
+
+    /// The x of the next point to take. An integer x past the range of
+    /// `i64` overflows.
+    fn x(&self) -> rpn::Result<Elt> {
+        match self.range {
+            Range::Ints(from, step) => {
+                let x = i128::from(from) + i128::from(step) * self.taken as i128;
+                i64::try_from(x).map(Elt::Int).map_err(|_| rpn::Error::Overflow)
+            },
+            Range::Floats(from, step) => Ok(Elt::Float(from + step * self.taken as f64)),
+        }
+    }
+}
+
+/// The virtual machine executing a compiled `Program` on a `Stack`.
+///
+/// It enforces the step and time limits of the stack it runs on; the
+/// stack enforces the others.
+pub struct Vm<'a> {
+    program: &'a Program,
+    frames: Vec<Frame>,
+    /// The `try` and `sample` blocks being run, innermost last.
+    handlers: Vec<Handler>,
+    /// The position of the last main program instruction stepped.
+    pos: Option<usize>,
+    steps: u64,
+    started: Instant,
+}
+
+impl<'a> Vm<'a> {
+    /// Prepares `program` to be run from its first instruction.
+    pub fn new(program: &'a Program) -> Vm<'a> {
+        Vm {
+            program,
+            frames: vec![Frame { word: None, pc: 0 }],
+            handlers: Vec::new(),
+            pos: None,
+            steps: 0,
+            started: Instant::now(),
+        }
+    }
+
+    /// The instruction the next `step` executes, `None` once the program
+    /// ended.
+    pub fn peek(&mut self, stack: &Stack) -> Option<Next> {
+        while let Some(&frame) = self.frames.last() {
+            let code = match frame.word {
+                None => self.program,
+                Some(addr) => stack.words().body(addr),
+            };
+            if let Some(instr) = code.code.get(frame.pc) {
+                return Some(Next {
+                    instr: instr.clone(),
+                    frame,
+                    pos: code.positions[frame.pc],
+                    depth: self.frames.len() - 1,
+                });
+            }
+            // The current frame is done, carry on with its caller.
+            self.frames.pop();
+        }
+        None
+    }
+
+    /// Executes the next instruction. Returns `false` once the program ended.
+    ///
+    /// An error inside a `try` block is caught here: the step succeeds and
+    /// the next one is the first of the handler.
+    pub fn step(&mut self, stack: &mut Stack) -> rpn::Result<bool> {
+        let next = match self.peek(stack) {
+            None => return Ok(false),
+            Some(next) => next,
+        };
+        if next.depth == 0 {
+            self.pos = Some(next.pos);
+        }
+        self.charge(stack.limits())?;
+        if let Some(frame) = self.frames.last_mut() {
+            frame.pc += 1;
+        }
+
+        let result = match next.instr {
+            Instr::Push(v) => stack.push(v),
+            Instr::Op(op) => stack.eval(op),
+            Instr::Call(addr) => {
+                self.frames.push(Frame { word: Some(addr), pc: 0 });
+                Ok(())
+            },
+            Instr::Store(slot) => stack.pop().map(|v| stack.words_mut().set(slot, v)),
+            Instr::Load(slot) => match stack.words().value(slot).cloned() {
+                Some(v) => stack.push(v),
+                None => Err(rpn::Error::Unset),
+            },
+            Instr::Unit(unit) => stack.apply_unit(&unit),
+            Instr::Convert(unit) => stack.convert(&unit),
+            Instr::Try(catch) => {
+                self.enter(Block::Try(catch), stack);
+                Ok(())
+            },
+            Instr::EndTry(after) => {
+                self.handlers.pop();
+                self.jump(after);
+                Ok(())
+            },
+            Instr::Sample(end) => self.start_sample(end, stack),
+            Instr::EndSample => {
+                let y = stack.pop();
+                self.next_sample(y.ok().and_then(|y| y.as_float()), stack)
+            },
+        };
+        match result {
+            Err(error) if error.is_catchable() => self.catch(error, stack),
+            result => result,
+        }.map(|_| true)
+    }
+
+    /// Starts running a block, in the current frame and on the current
+    /// stack.
+    fn enter(&mut self, kind: Block, stack: &Stack) {
+        let frames = self.frames.len();
+        self.handlers.push(Handler { frames, snapshot: stack.snapshot(), kind });
+    }
+
+    /// Pops the range of a `sample` block ending at `end`, and runs the
+    /// block for its first x.
+    fn start_sample(&mut self, end: usize, stack: &mut Stack) -> rpn::Result<()> {
+        let range: Vec<Elt> = stack.iter().take(3).cloned().collect();
+        let (step, to, from) = match range.as_slice() {
+            [step, to, from] => (step, to, from),
+            _ => return Err(rpn::Error::Underflow),
+        };
+        let body = self.frames.last().map_or(0, |frame| frame.pc);
+        let sampler = Sampler::new(from, to, step, body, end)?;
+        let x = sampler.x()?;
+        for _ in 0..3 {
+            stack.pop()?;
+        }
+        self.enter(Block::Sample(sampler), stack);
+        stack.push(x)
+    }
+
+    /// Ends a run of the innermost `sample` block, keeping its point if
+    /// `y` is a finite number. Runs the block again for the next x, or
+    /// pushes the matrix of points once the range is done.
+    fn next_sample(&mut self, y: Option<f64>, stack: &mut Stack) -> rpn::Result<()> {
+        let mut handler = match self.handlers.pop() {
+            Some(handler) => handler,
+            None => return Ok(()),
+        };
+        self.frames.truncate(handler.frames);
+        let sampler = match handler.kind {
+            Block::Sample(ref mut sampler) => sampler,
+            Block::Try(_) => unreachable!("a try block ended as a sample block"),
+        };
+
+        // This x was pushed for the run, so it did not overflow.
+        let x = sampler.x().ok().and_then(|x| x.as_float()).unwrap_or(f64::NAN) as f32;
+        if let Some(y) = y.map(|y| y as f32).filter(|y| y.is_finite()) {
+            if x.is_finite() {
+                sampler.points.push(vec![x, y]);
+            }
+        }
+        sampler.taken += 1;
+        stack.restore(handler.snapshot.clone());
+
+        if sampler.taken < sampler.count {
+            let (body, x) = (sampler.body, sampler.x()?);
+            self.handlers.push(handler);
+            self.jump(body);
+            return stack.push(x);
+        }
+        self.jump(sampler.end);
+        let points = Matrix::new(mem::take(&mut sampler.points)).ok_or(rpn::Error::Domain)?;
+        stack.push(Elt::Matrix(points))
+    }
+
+    /// Continues at `pc` in the current frame.
+    fn jump(&mut self, pc: usize) {
+        if let Some(frame) = self.frames.last_mut() {
+            frame.pc = pc;
+        }
+    }
+
+    /// Hands `error` to the innermost block, if any. A `try` block returns
+    /// to the frame and the stack it started with, and pushes the error
+    /// for the handler. A `sample` block drops the point and goes on.
+    fn catch(&mut self, error: rpn::Error, stack: &mut Stack) -> rpn::Result<()> {
+        if let Some(&Handler { kind: Block::Sample(_), .. }) = self.handlers.last() {
+            return match self.next_sample(None, stack) {
+                Err(error) if error.is_catchable() => self.catch(error, stack),
+                result => result,
+            };
+        }
+        let handler = match self.handlers.pop() {
+            Some(handler) => handler,
+            None => return Err(error),
+        };
+        let catch = match handler.kind {
+            Block::Try(catch) => catch,
+            Block::Sample(_) => unreachable!(),
+        };
+        stack.restore(handler.snapshot);
+        self.frames.truncate(handler.frames);
+        self.jump(catch);
+        match error {
+            rpn::Error::Thrown(v) => stack.push(v),
+            error => stack.push(Elt::Str(error.kind().to_string())),
+        }
+    }
+
+    /// Counts one more step against the limits.
+    fn charge(&mut self, limits: &rpn::Limits) -> rpn::Result<()> {
+        self.steps += 1;
+        if limits.steps.is_some_and(|max| self.steps > max) {
+            return Err(rpn::Error::StepLimit);
+        }
+        if limits.time.is_some_and(|max| self.started.elapsed() > max) {
+            return Err(rpn::Error::TimeLimit);
+        }
+        Ok(())
+    }
+
+    /// Runs the program until it ends or an error occurs.
+    pub fn run(&mut self, stack: &mut Stack) -> rpn::Result<()> {
+        while self.step(stack)? {}
+        Ok(())
+    }
+
+    /// The position of the token the main program was last at: the one
+    /// that failed after an error. Inside a word, that is the word's call.
+    pub fn pos(&self) -> Option<usize> {
+        self.pos
+    }
+}
+
+/// Runs a compiled program on `stack`.
+pub fn run(program: &Program, stack: &mut Stack) -> rpn::Result<()> {
+    Vm::new(program).run(stack)
+}
+
+/// Like `run`, telling which token an error is at.
+pub fn run_located(program: &Program, stack: &mut Stack) -> Result<(), Located> {
+    let mut vm = Vm::new(program);
+    vm.run(stack).map_err(|error| Located { error, pos: vm.pos() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rpn::Error;
+
+    fn compile_str(s: &str) -> rpn::Result<Program> {
+        compile(s, &mut Dictionary::new())
+    }
+
+    #[test]
+    fn test_compile_resolves_tokens() {
+        let p = compile_str("1 true + ~").unwrap();
+        assert_eq!(p.code, vec![Instr::Push(Elt::Int(1)),
+                                Instr::Push(Elt::Bool(true)),
+                                Instr::Op(Op::Add),
+                                Instr::Op(Op::Neg)]);
+    }
+
+    #[test]
+    fn test_compile_bad_token() {
+        let res = compile_str("1 ~false");
+        if let Err(Error::Syntax) = res { } else { panic!(); }
+    }
+
+    #[test]
+    fn test_tokenize() {
+        assert_eq!(tokenize(" 1  2 +"), vec![(0, "1"), (1, "2"), (2, "+")]);
+        assert_eq!(tokenize("[[1 2] [3  4]] det 5"),
+                   vec![(0, "[[1 2] [3  4]]"), (4, "det"), (5, "5")]);
+        assert_eq!(tokenize("[[1 2]"), vec![(0, "[[1 2]")]);
+        assert_eq!(tokenize("[ 1 ]"), vec![(0, "["), (1, "1"), (2, "]")]);
+        assert_eq!(tokenize("\"a  b\" \" c\" \"d\" e"),
+                   vec![(0, "\"a  b\""), (2, "\" c\""), (4, "\"d\""), (5, "e")]);
+        assert_eq!(tokenize("\"a b"), vec![(0, "\"a b")]);
+        assert_eq!(tokenize(r#""a\" b" "\\" c"#), vec![(0, r#""a\" b""#), (2, r#""\\""#), (3, "c")]);
+        assert_eq!(tokenize("1 ( a b) 2 (3,4)"), vec![(0, "1"), (4, "2"), (5, "(3,4)")]);
+        assert_eq!(tokenize("1 ( 2"), vec![(0, "1")]);
+    }
+
+    #[test]
+    fn test_compile_error_position() {
+        let mut words = Dictionary::new();
+        for &(src, pos) in &[("1 2 ~false", 2), (": inc 1 + ; : w 1", 5), ("1 : 2 ;", 2),
+                             (": a 1 : b ;", 3), ("1 :", 1)] {
+            let res = compile_located(src, &mut words);
+            if let Err(Located { error: Error::Syntax, pos: p }) = res {
+                assert_eq!(p, Some(pos), "{}", src);
+            } else { panic!(); }
+        }
+    }
+
+    #[test]
+    fn test_run_error_position() {
+        let mut s = Stack::new();
+        let p = compile(": bad true + ; 1 2 + bad 4", s.words_mut()).unwrap();
+        let res = run_located(&p, &mut s);
+        if let Err(Located { error: Error::Type, pos: Some(8) }) = res { } else { panic!(); }
+    }
+
+    #[test]
+    fn test_compile_definition() {
+        let mut words = Dictionary::new();
+        let p = compile(": inc 1 + ; 2 inc", &mut words).unwrap();
+        let addr = words.lookup("inc").unwrap();
+        assert_eq!(p.code, vec![Instr::Push(Elt::Int(2)), Instr::Call(addr)]);
+        assert_eq!(words.body(addr).code, vec![Instr::Push(Elt::Int(1)),
+                                               Instr::Op(Op::Add)]);
+    }
+
+    #[test]
+    fn test_compile_bad_definition() {
+        for s in &[": inc 1 +", ": + 1 ;", ": 12 1 ;", ":", ": a : b ; ;"] {
+            let res = compile_str(s);
+            if let Err(Error::Syntax) = res { } else { panic!(); }
+        }
+    }
+
+    #[test]
+    fn test_compile_try() {
+        let p = compile_str("try [ 1 ] catch [ 2 3 ] 4").unwrap();
+        assert_eq!(p.code, vec![Instr::Try(3), Instr::Push(Elt::Int(1)), Instr::EndTry(5),
+                                Instr::Push(Elt::Int(2)), Instr::Push(Elt::Int(3)),
+                                Instr::Push(Elt::Int(4))]);
+        assert_eq!(p.positions, vec![0, 2, 4, 6, 7, 9]);
+
+        let p = compile_str("try [ try [ ] catch [ ] ] catch [ ]").unwrap();
+        assert_eq!(p.code, vec![Instr::Try(4), Instr::Try(3), Instr::EndTry(3), Instr::EndTry(4)]);
+    }
+
+    #[test]
+    fn test_compile_bad_try() {
+        let mut words = Dictionary::new();
+        for &(src, pos) in &[("1 try [ 2", 1), ("try 1", 1), ("try [ 1 ] 2", 4),
+                             ("try [ ] catch", 0), ("try [ ] catch [ 1", 0), ("1 ]", 1),
+                             ("catch", 0), ("try [ : a 1 ; ] catch [ ]", 2), (": try 1 ;", 1)] {
+            let res = compile_located(src, &mut words);
+            if let Err(Located { error: Error::Syntax, pos: p }) = res {
+                assert_eq!(p, Some(pos), "{}", src);
+            } else { panic!("{}", src); }
+        }
+    }
+
+    #[test]
+    fn test_compile_sample() {
+        let p = compile_str("0 2 1 sample [ enter * ] plot").unwrap();
+        assert_eq!(p.code, vec![Instr::Push(Elt::Int(0)), Instr::Push(Elt::Int(2)),
+                                Instr::Push(Elt::Int(1)), Instr::Sample(7),
+                                Instr::Op(Op::Enter), Instr::Op(Op::Mul), Instr::EndSample,
+                                Instr::Op(Op::Plot)]);
+
+        let mut words = Dictionary::new();
+        for &(src, pos) in &[("sample 1", 1), ("1 sample [ 2", 1), (": sample 1 ;", 1)] {
+            let res = compile_located(src, &mut words);
+            if let Err(Located { error: Error::Syntax, pos: p }) = res {
+                assert_eq!(p, Some(pos), "{}", src);
+            } else { panic!("{}", src); }
+        }
+    }
+
+    #[test]
+    fn test_compile_namespace() {
+        let mut s = Stack::new();
+        let src = ": sq 0 ; namespace m : sq enter * ; : quad sq sq ; 5 !x 2 quad x sq";
+        let p = compile(src, s.words_mut()).unwrap();
+        assert!(run(&p, &mut s).is_ok());
+        assert_eq!(s.pop().unwrap(), Elt::Int(25));
+        assert_eq!(s.pop().unwrap(), Elt::Int(16));
+        assert!(s.words().lookup("m:quad").is_some());
+        assert!(s.words().variable("m:x").is_some());
+
+        // Namespaces last until the end of the line.
+        let p = compile("3 sq 3 m:sq", s.words_mut()).unwrap();
+        assert!(run(&p, &mut s).is_ok());
+        assert_eq!(s.pop().unwrap(), Elt::Int(9));
+        assert_eq!(s.pop().unwrap(), Elt::Int(0));
+
+        for src in &["namespace", "namespace 12", "namespace a:b", "namespace m : true 1 ;",
+                     ": a: 1 ;", "try [ namespace m ] catch [ ]"] {
+            let res = compile(src, s.words_mut());
+            if let Err(Error::Syntax) = res { } else { panic!("{}", src); }
+        }
+    }
+
+    #[test]
+    fn test_include_stdlib() {
+        let mut s = Stack::new();
+        let p = compile("include \"math.rpn\" include \"stack.rpn\" 3 math:sq 1 2 stack:over",
+                        s.words_mut()).unwrap();
+        assert!(run(&p, &mut s).is_ok());
+        let mut values = Vec::new();
+        while let Ok(v) = s.pop() {
+            values.push(v);
+        }
+        assert_eq!(values, vec![Elt::Int(1), Elt::Int(2), Elt::Int(1), Elt::Int(9)]);
+
+        // The standard library is there without I/O.
+        let loader = Loader { path: Vec::new(), io: false };
+        assert!(compile_with("include \"stack.rpn\"", s.words_mut(), &loader).is_ok());
+    }
+
+    #[test]
+    fn test_include_files() {
+        let dir = ::std::env::temp_dir().join(format!("hw04-include-{}", ::std::process::id()));
+        let lib = dir.join("lib");
+        fs::create_dir_all(&lib).unwrap();
+        fs::write(dir.join("main.rpn"), "include \"lib/a.rpn\" : main a:twice ;").unwrap();
+        fs::write(lib.join("a.rpn"), "namespace a include \"b.rpn\" : twice b:one 2 * ;").unwrap();
+        fs::write(lib.join("b.rpn"), "namespace b : one 1 ;").unwrap();
+        fs::write(lib.join("loop.rpn"), "1 include \"lib/loop.rpn\"").unwrap();
+        fs::write(lib.join("bad.rpn"), "1 true +\n2 ~false").unwrap();
+
+        let mut s = Stack::new();
+        let loader = Loader { path: vec![dir.clone()], io: true };
+        let p = compile_with("include \"main.rpn\" main", s.words_mut(), &loader).unwrap();
+        assert!(run(&p, &mut s).is_ok());
+        assert_eq!(s.pop().unwrap(), Elt::Int(2));
+
+        // Errors in a file are at its include.
+        let res = compile_with("1 include \"lib/bad.rpn\"", s.words_mut(), &loader);
+        if let Err(Located { error: Error::Syntax, pos: Some(1) }) = res { } else { panic!(); }
+        let res = compile_with("include \"lib/loop.rpn\"", s.words_mut(), &loader);
+        if let Err(Located { error: Error::Cycle(p), pos: Some(0) }) = res {
+            assert!(p.ends_with("loop.rpn"));
+        } else { panic!(); }
+        let res = compile_with("include \"none.rpn\"", s.words_mut(), &loader);
+        if let Err(Located { error: Error::IO(e, Some(p)), .. }) = res {
+            assert_eq!(e.kind(), io::ErrorKind::NotFound);
+            assert_eq!(p, PathBuf::from("none.rpn"));
+        } else { panic!(); }
+
+        let loader = Loader { io: false, ..loader };
+        let res = compile_with("include \"main.rpn\"", s.words_mut(), &loader);
+        if let Err(Located { error: Error::IO(e, _), .. }) = res {
+            assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+        } else { panic!(); }
+        for src in &["include", "include main.rpn", "try [ include \"math.rpn\" ] catch [ ]"] {
+            let res = compile_with(src, s.words_mut(), &loader);
+            if let Err(Located { error: Error::Syntax, .. }) = res { } else { panic!("{}", src); }
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_run_try() {
+        let mut s = Stack::new();
+        let p = compile(": fail 1 2 true + ; 5 try [ 6 fail 7 ] catch [ 8 ] 9", s.words_mut()).unwrap();
+        assert!(run(&p, &mut s).is_ok());
+        let mut values = Vec::new();
+        while let Ok(v) = s.pop() {
+            values.push(v);
+        }
+        assert_eq!(values, vec![Elt::Int(9), Elt::Int(8), Elt::Str("Type".to_string()),
+                                Elt::Int(5)]);
+
+        // No error: the handler is skipped.
+        let p = compile("try [ 1 ] catch [ 2 ]", s.words_mut()).unwrap();
+        assert!(run(&p, &mut s).is_ok());
+        assert_eq!(s.pop().unwrap(), Elt::Int(1));
+        assert!(s.pop().is_err());
+
+        // An error in a handler goes to the enclosing block.
+        let p = compile("try [ try [ 1 throw ] catch [ 2 + throw ] ] catch [ ]", s.words_mut()).unwrap();
+        assert!(run(&p, &mut s).is_ok());
+        assert_eq!(s.pop().unwrap(), Elt::Int(3));
+
+        let p = compile("try [ 1 throw ] catch [ throw ]", s.words_mut()).unwrap();
+        let res = run(&p, &mut s);
+        if let Err(Error::Thrown(Elt::Int(1))) = res { } else { panic!(); }
+
+        // Reading an unset variable is caught like any other error.
+        let p = compile("try [ 1 throw 2 !u ] catch [ ] try [ u ] catch [ ]", s.words_mut()).unwrap();
+        assert!(run(&p, &mut s).is_ok());
+        assert_eq!(s.pop().unwrap(), Elt::Str("Unset".to_string()));
+    }
+
+    #[test]
+    fn test_run_sample() {
+        let points = |rows: Vec<Vec<f32>>| Elt::Matrix(Matrix::new(rows).unwrap());
+        let mut s = Stack::new();
+        let p = compile("7 0 3 1 sample [ enter * ]", s.words_mut()).unwrap();
+        assert!(run(&p, &mut s).is_ok());
+        assert_eq!(s.pop().unwrap(), points(vec![vec![0.0, 0.0], vec![1.0, 1.0],
+                                                 vec![2.0, 4.0], vec![3.0, 9.0]]));
+        assert_eq!(s.pop().unwrap(), Elt::Int(7));
+
+        // Points whose y fails are left out, and the stack is the same
+        // for each x.
+        let p = compile("1 0 2 1 sample [ 5 <-> ln ]", s.words_mut()).unwrap();
+        assert!(run(&p, &mut s).is_ok());
+        assert_eq!(s.pop().unwrap(), points(vec![vec![1.0, 0.0], vec![2.0, 2f32.ln()]]));
+        assert_eq!(s.pop().unwrap(), Elt::Int(1));
+
+        // Blocks nest, and errors reach the innermost one: here the throw
+        // leaves the inner block with no points, which the try catches.
+        let p = compile("1 2 1 sample [ try [ 0 0 1 sample [ throw ] ] catch [ 3 ] ]",
+                        s.words_mut()).unwrap();
+        assert!(run(&p, &mut s).is_ok());
+        assert_eq!(s.pop().unwrap(), points(vec![vec![1.0, 3.0], vec![2.0, 3.0]]));
+        assert!(s.pop().is_err());
+
+        for &(src, depth) in &[("1 0 1 sample [ ]", 3), ("0 1 0 sample [ ]", 3),
+                               ("0 1000000 1 sample [ ]", 3), ("0 1 1 sample [ 0 ln ]", 0),
+                               ("try [ 0 1 1 sample [ true ] ] catch [ ]", 0)] {
+            let p = compile(src, s.words_mut()).unwrap();
+            let res = run(&p, &mut s);
+            if src.starts_with("try") {
+                assert_eq!(s.pop().unwrap(), Elt::Str("Domain".to_string()));
+            } else if let Err(Error::Domain) = res { } else { panic!("{}", src); }
+            assert_eq!(s.depth(), depth, "{}", src);
+            while s.pop().is_ok() {}
+        }
+
+        let p = compile("1 2 sample [ ]", s.words_mut()).unwrap();
+        let res = run(&p, &mut s);
+        if let Err(Error::Underflow) = res { } else { panic!(); }
+        while s.pop().is_ok() {}
+    }
+
+    #[test]
+    fn test_sample_extreme_bounds() {
+        let mut s = Stack::new();
+        let (min, max) = (i64::MIN, i64::MAX);
+        for &(src, ref xs) in &[
+            ("0 9223372036854775807 4611686018427387904", vec![0, 1 << 62]),
+            ("-9223372036854775808 9223372036854775807 9223372036854775807", vec![min, -1, max - 1]),
+            ("9223372036854775807 -9223372036854775808 -9223372036854775807", vec![max, 0, min + 1]),
+            ("9223372036854775806 9223372036854775807 1", vec![max - 1, max]),
+        ] {
+            let p = compile(&format!("{} sample [ enter !last ]", src), s.words_mut()).unwrap();
+            assert!(run(&p, &mut s).is_ok(), "{}", src);
+            // Each x is pushed exactly: the block stores it, so the
+            // variable holds the last one.
+            let last = s.words().variable("last").and_then(|slot| s.words().value(slot).cloned());
+            assert_eq!(last, xs.last().map(|&x| Elt::Int(x)), "{}", src);
+            if let Elt::Matrix(m) = s.pop().unwrap() {
+                assert_eq!(m.height(), xs.len(), "{}", src);
+            } else { panic!("{}", src); }
+        }
+
+        // The range ends past `i64`: the x that would leave it overflows.
+        let p = compile("9223372036854775807 1.5e19 4611686018427387904 sample [ ]",
+                        s.words_mut()).unwrap();
+        let res = run(&p, &mut s);
+        if let Err(Error::Overflow) = res { } else { panic!(); }
+        assert_eq!(s.depth(), 0);
+    }
+
+    #[test]
+    fn test_try_keeps_limits() {
+        let mut s = Stack::new();
+        s.set_limits(rpn::Limits { steps: Some(3), ..rpn::Limits::default() });
+        let p = compile("try [ 1 2 3 ] catch [ ]", s.words_mut()).unwrap();
+        let res = run(&p, &mut s);
+        if let Err(Error::StepLimit) = res { } else { panic!(); }
+    }
+
+    #[test]
+    fn test_to_source() {
+        let mut words = Dictionary::new();
+        let p = compile(": inc 1 + ; 2 true <-> inc", &mut words).unwrap();
+        assert_eq!(to_source(&p, &words), "2 true <-> inc");
+    }
+
+    #[test]
+    fn test_to_source_try() {
+        let mut words = Dictionary::new();
+        for src in &["try [ 1 ] catch [ 2 ] 3", "try [ try [ ] catch [ ] ] catch [ 1 throw ]",
+                     "try [ ] catch [ try [ 1 ] catch [ ] ]",
+                     "0 1 0.5 sample [ try [ 1 ] catch [ ] ] plot", "sample [ sample [ ] ]"] {
+            let p = compile(src, &mut words).unwrap();
+            assert_eq!(to_source(&p, &words), *src);
+        }
+    }
+
+    #[test]
+    fn test_compile_variables() {
+        let mut words = Dictionary::new();
+        let p = compile("5 !x x !y", &mut words).unwrap();
+        let (x, y) = (words.variable("x").unwrap(), words.variable("y").unwrap());
+        assert_eq!(p.code, vec![Instr::Push(Elt::Int(5)), Instr::Store(x),
+                                Instr::Load(x), Instr::Store(y)]);
+        assert_eq!(to_source(&p, &words), "5 !x x !y");
+
+        for s in &["!true", "!+", "!", "z", ": x 1 ;", ": w 1 ; !w"] {
+            let res = compile(s, &mut words);
+            if let Err(Error::Syntax) = res { } else { panic!(); }
+        }
+    }
+
+    #[test]
+    fn test_run_variables() {
+        let mut s = Stack::new();
+        let p = compile("5 !x : addx x + ; 1 addx x", s.words_mut()).unwrap();
+        assert!(run(&p, &mut s).is_ok());
+        assert_eq!(s.pop().unwrap(), Elt::Int(5));
+        assert_eq!(s.pop().unwrap(), Elt::Int(6));
+
+        let p = compile(": sety !y ; y", s.words_mut()).unwrap();
+        let res = run(&p, &mut s);
+        if let Err(Error::Unset) = res { } else { panic!(); }
+
+        let p = compile("!x", s.words_mut()).unwrap();
+        let res = run(&p, &mut s);
+        if let Err(Error::Underflow) = res { } else { panic!(); }
+    }
+
+    #[test]
+    fn test_run() {
+        let mut s = Stack::new();
+        let p = compile("12 13 + 25 =", s.words_mut()).unwrap();
+        assert!(run(&p, &mut s).is_ok());
+        assert_eq!(s.pop().unwrap(), Elt::Bool(true));
+    }
+
+    #[test]
+    fn test_run_word() {
+        let mut s = Stack::new();
+        let p = compile(": inc 1 + ; : inc2 inc inc ; 1 inc2 inc", s.words_mut()).unwrap();
+        assert!(run(&p, &mut s).is_ok());
+        assert_eq!(s.pop().unwrap(), Elt::Int(4));
+        assert!(s.pop().is_err());
+    }
+
+    #[test]
+    fn test_run_redefined_word() {
+        let mut s = Stack::new();
+        let p = compile(": one 1 ; : two one one + ; : one 10 ;", s.words_mut()).unwrap();
+        assert!(run(&p, &mut s).is_ok());
+        let p = compile("two one", s.words_mut()).unwrap();
+        assert!(run(&p, &mut s).is_ok());
+        assert_eq!(s.pop().unwrap(), Elt::Int(10));
+        assert_eq!(s.pop().unwrap(), Elt::Int(2));
+    }
+
+    #[test]
+    fn test_run_stops_at_error() {
+        let mut s = Stack::new();
+        let p = compile_str("1 true + 5").unwrap();
+        let res = run(&p, &mut s);
+        if let Err(Error::Type) = res { } else { panic!(); }
+        assert!(s.pop().is_err());
+    }
+
+    #[test]
+    fn test_step_limit() {
+        let mut s = Stack::new();
+        s.set_limits(rpn::Limits { steps: Some(3), ..rpn::Limits::default() });
+        let p = compile(": inc 1 + ; 1 inc", s.words_mut()).unwrap();
+        let res = run(&p, &mut s);
+        if let Err(Error::StepLimit) = res { } else { panic!(); }
+        // The instructions up to the limit ran.
+        assert_eq!(s.depth(), 2);
+
+        // Every run gets its own budget.
+        let p = compile("2 +", s.words_mut()).unwrap();
+        assert!(run(&p, &mut s).is_ok());
+    }
+
+    #[test]
+    fn test_time_limit() {
+        let mut s = Stack::new();
+        s.set_limits(rpn::Limits { time: Some(::std::time::Duration::from_millis(50)),
+                                   ..rpn::Limits::default() });
+        // 2^25 additions, far more than fit in the budget.
+        let mut src = ": w0 0 1 + ;".to_string();
+        for i in 1..26 {
+            src.push_str(&format!(" : w{} w{} w{} + ;", i, i - 1, i - 1));
+        }
+        src.push_str(" w25");
+        let p = compile(&src, s.words_mut()).unwrap();
+        let res = run(&p, &mut s);
+        if let Err(Error::TimeLimit) = res { } else { panic!(); }
+    }
+
+    #[test]
+    fn test_vm_step() {
+        let mut s = Stack::new();
+        let p = compile(": inc 1 + ; 1 inc", s.words_mut()).unwrap();
+        let mut vm = Vm::new(&p);
+        // push 1, call inc, push 1, add
+        for _ in 0..4 {
+            assert!(vm.step(&mut s).unwrap());
+        }
+        assert!(!vm.step(&mut s).unwrap());
+        assert_eq!(s.pop().unwrap(), Elt::Int(2));
+    }
+}
@@ -0,0 +1,720 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use bytecode;
+
+use debugger::Breakpoint;
+use decimal::Rounding;
+use display::{Base, Format};
+use optimizer;
+use history::History;
+use parser::{compile_line, get_operation, op_info, op_token, Repl, OPERATIONS};
+use rpn::{self, Angle, Limits, Mode};
+use session::{self, Journal};
+
+/// Whether a line is a REPL command (`:name args`) rather than RPN input.
+/// A lone `:` starts a word definition, so it is not a command.
+pub fn is_command(line: &str) -> bool {
+    let line = line.trim_start();
+    line.starts_with(':') && line[1..].starts_with(|c: char| c.is_alphabetic())
+}
+
+/// Splits a command line into the command name and its arguments.
+fn split(line: &str) -> (&str, &str) {
+    let line = line.trim();
+    match line.find(char::is_whitespace) {
+        Some(i) => (&line[1..i], line[i..].trim()),
+        None => (&line[1..], ""),
+    }
+}
+
+/// Whether a line goes into the session journal. Blank lines are left
+/// out, and so are commands that only manage files or the debugger:
+/// `:load` is journaled as the lines it loads.
+pub fn is_journaled(line: &str) -> bool {
+    if line.trim().is_empty() {
+        return false;
+    }
+    if !is_command(line) {
+        return true;
+    }
+    !matches!(split(line).0,
+              "save" | "load" | "journal" | "replay" |
+              "trace" | "step" | "break" | "unbreak" | "help" | "words")
+}
+
+/// Runs a REPL command.
+pub fn run_command(repl: &mut Repl, line: &str) -> rpn::Result<()> {
+    let (name, args) = split(line);
+
+    match name {
+        "help" => help(repl, args),
+        "words" => words(repl),
+        "optimize" => optimize(repl, args),
+        "trace" => switch(args).map(|on| repl.debugger.trace = on),
+        "step" => switch(args).map(|on| repl.debugger.step = on),
+        "break" => breakpoint(repl, args),
+        "unbreak" => unbreak(repl, args),
+        "undo" => count(args).and_then(|n| undo(repl, n)),
+        "redo" => count(args).and_then(|n| redo(repl, n)),
+        "history" => history(repl, args),
+        "display" => display(repl, args),
+        "mode" => mode(repl, args),
+        "base" => base(repl, args),
+        "word" => word(repl, args),
+        "angle" => angle(repl, args),
+        "ieee" => switch(args).map(|on| repl.stack.set_ieee(on)),
+        "scale" => args.parse().map_err(|_| rpn::Error::Syntax).and_then(|n| repl.stack.set_scale(n)),
+        "rounding" => Rounding::parse(args).map(|r| repl.stack.set_rounding(r)),
+        "separators" => switch(args).map(|on| repl.display.separators = on),
+        "prompt" => prompt(repl, args),
+        "limit" => limit(repl, args),
+        "path" => include_path(repl, args),
+        "reset" => reset(repl),
+        "seed" => args.parse().map(|n| repl.stack.seed(n)).map_err(|_| rpn::Error::Syntax),
+        "save" => session::save(&mut repl.stack, path(args)?),
+        "load" => session::load(repl, path(args)?),
+        "journal" => journal(repl, args),
+        "replay" => repl.replay(path(args)?),
+        _ => Err(rpn::Error::Syntax),
+    }
+}
+
+/// Reads the `on`/`off` argument of a setting; no argument means `on`.
+fn switch(args: &str) -> rpn::Result<bool> {
+    match args {
+        "" | "on" => Ok(true),
+        "off" => Ok(false),
+        _ => Err(rpn::Error::Syntax),
+    }
+}
+
+/// Reads an optional repeat count; no argument means once.
+fn count(args: &str) -> rpn::Result<usize> {
+    if args.is_empty() {
+        Ok(1)
+    } else {
+        args.parse().map_err(|_| rpn::Error::Syntax)
+    }
+}
+
+/// Reads the file name argument of a command.
+fn path(args: &str) -> rpn::Result<&str> {
+    if args.is_empty() {
+        Err(rpn::Error::Syntax)
+    } else {
+        Ok(args)
+    }
+}
+
+/// Strips the double quotes around an argument, if any, so it can keep
+/// leading or trailing spaces: `:prompt "rpn> "`.
+fn unquote(arg: &str) -> &str {
+    if arg.len() >= 2 && arg.starts_with('"') && arg.ends_with('"') {
+        &arg[1..arg.len() - 1]
+    } else {
+        arg
+    }
+}
+
+/// `:help` lists the operations with their stack effects, `:help <token>`
+/// tells all about an operation, or shows the definition of a word.
+fn help(repl: &mut Repl, args: &str) -> rpn::Result<()> {
+    let words = repl.stack.words();
+    let text = if args.is_empty() {
+        let lines: Vec<String> = OPERATIONS.iter()
+            .map(|info| format!("{:<12} {:<16} {}", info.token, info.effect, info.doc))
+            .collect();
+        lines.join("\n")
+    } else if let Some(info) = op_info(args) {
+        info.describe()
+    } else if let Some(addr) = words.lookup(args) {
+        format!(": {} {} ;", args, bytecode::to_source(words.body(addr), words))
+    } else {
+        return Err(rpn::Error::Syntax);
+    };
+    repl.show(&text)
+}
+
+/// `:words` lists the tokens of the operations, then the words and
+/// variables defined in the session.
+fn words(repl: &mut Repl) -> rpn::Result<()> {
+    let tokens: Vec<&str> = OPERATIONS.iter().map(|info| info.token).collect();
+    let mut lines = vec![tokens.join(" ")];
+    let words = repl.stack.words();
+    let mut names: Vec<&str> = words.names().collect();
+    names.sort_unstable();
+    if !names.is_empty() {
+        lines.push(format!("words: {}", names.join(" ")));
+    }
+    let mut vars: Vec<&str> = words.variables().map(|(name, _)| name).collect();
+    vars.sort_unstable();
+    if !vars.is_empty() {
+        lines.push(format!("variables: {}", vars.join(" ")));
+    }
+    repl.show(&lines.join("\n"))
+}
+
+/// `:optimize <program>` shows a program before and after optimization.
+/// The words and variables it defines are not kept.
+fn optimize(repl: &mut Repl, args: &str) -> rpn::Result<()> {
+    let stack = &mut repl.stack;
+    let words = stack.words().clone();
+    let text = compile_line(stack, args).map(|program| {
+        let optimized = optimizer::optimize(&program, stack);
+        format!("before: {}\nafter:  {}",
+                bytecode::to_source(&program, stack.words()),
+                bytecode::to_source(&optimized, stack.words()))
+    });
+    *stack.words_mut() = words;
+    repl.show(&text?)
+}
+
+/// `:break <word|position>` adds a breakpoint, `:break` lists them.
+fn breakpoint(repl: &mut Repl, args: &str) -> rpn::Result<()> {
+    if args.is_empty() {
+        let lines: Vec<String> = repl.debugger.breakpoints.iter().map(|b| format!("{:?}", b)).collect();
+        repl.show(&lines.join("\n"))
+    } else {
+        repl.debugger.breakpoints.push(Breakpoint::parse(args));
+        Ok(())
+    }
+}
+
+/// `:unbreak <word|position>` removes a breakpoint, `:unbreak` all of them.
+fn unbreak(repl: &mut Repl, args: &str) -> rpn::Result<()> {
+    if args.is_empty() {
+        repl.debugger.breakpoints.clear();
+    } else {
+        let b = Breakpoint::parse(args);
+        repl.debugger.breakpoints.retain(|other| *other != b);
+    }
+    Ok(())
+}
+
+/// `:undo [n]` restores the stack as it was `n` lines ago.
+fn undo(repl: &mut Repl, n: usize) -> rpn::Result<()> {
+    for _ in 0..n {
+        if !repl.history.undo(&mut repl.stack) {
+            return repl.show("nothing to undo");
+        }
+    }
+    Ok(())
+}
+
+/// `:redo [n]` reapplies `n` undone lines.
+fn redo(repl: &mut Repl, n: usize) -> rpn::Result<()> {
+    for _ in 0..n {
+        if !repl.history.redo(&mut repl.stack) {
+            return repl.show("nothing to redo");
+        }
+    }
+    Ok(())
+}
+
+/// `:history <depth>` sets how many lines can be undone, `:history`
+/// shows it.
+fn history(repl: &mut Repl, args: &str) -> rpn::Result<()> {
+    if args.is_empty() {
+        let depth = repl.history.depth().to_string();
+        return repl.show(&depth);
+    }
+    let depth = args.parse().map_err(|_| rpn::Error::Syntax)?;
+    repl.history.set_depth(depth);
+    Ok(())
+}
+
+/// `:display <debug|pretty|top> [levels]` sets how the stack is shown
+/// after each line, `:display` shows it now.
+fn display(repl: &mut Repl, args: &str) -> rpn::Result<()> {
+    let mut args = args.split_whitespace();
+    match args.next() {
+        None => {
+            let text = repl.display.render(&repl.stack);
+            repl.show(&text)?;
+        },
+        Some(format) => {
+            let format = Format::parse(format)?;
+            let levels = match args.next() {
+                None => None,
+                Some(n) => Some(n.parse().map_err(|_| rpn::Error::Syntax)?),
+            };
+            repl.display.format = format;
+            repl.display.levels = levels;
+        },
+    }
+    Ok(())
+}
+
+/// `:mode <hp|unbounded>` switches between an HP-style four register
+/// stack and the unbounded one. The switch can be undone like a line.
+fn mode(repl: &mut Repl, args: &str) -> rpn::Result<()> {
+    let mode = match args {
+        "hp" => Mode::Hp,
+        "unbounded" => Mode::Unbounded,
+        _ => return Err(rpn::Error::Syntax),
+    };
+    let before = repl.stack.snapshot();
+    repl.stack.set_mode(mode);
+    repl.history.record(before, &repl.stack);
+    Ok(())
+}
+
+/// `:base <dec|hex|oct|bin> [signed|unsigned]` sets the base integers are
+/// shown in. Decimal is signed unless told otherwise, the other bases
+/// unsigned.
+fn base(repl: &mut Repl, args: &str) -> rpn::Result<()> {
+    let mut args = args.split_whitespace();
+    let base = Base::parse(args.next().unwrap_or(""))?;
+    let signed = match args.next() {
+        None => base == Base::Dec,
+        Some("signed") => true,
+        Some("unsigned") => false,
+        Some(_) => return Err(rpn::Error::Syntax),
+    };
+    if args.next().is_some() {
+        return Err(rpn::Error::Syntax);
+    }
+    repl.display.base = base;
+    repl.display.signed = signed;
+    Ok(())
+}
+
+/// `:word <8|16|32|64>` sets the word size of bitwise operations and of
+/// the integers shown in other bases, `:word` shows it.
+fn word(repl: &mut Repl, args: &str) -> rpn::Result<()> {
+    if args.is_empty() {
+        let bits = repl.stack.word_size().to_string();
+        return repl.show(&bits);
+    }
+    let bits = args.parse().map_err(|_| rpn::Error::Syntax)?;
+    repl.stack.set_word_size(bits)
+}
+
+/// `:angle <rad|deg>` sets the unit of angles.
+fn angle(repl: &mut Repl, args: &str) -> rpn::Result<()> {
+    let angle = match args {
+        "rad" => Angle::Rad,
+        "deg" => Angle::Deg,
+        _ => return Err(rpn::Error::Syntax),
+    };
+    repl.stack.set_angle(angle);
+    Ok(())
+}
+
+/// `:path <dirs>` sets the directories `include` searches, `:path off`
+/// clears them and `:path` shows them.
+fn include_path(repl: &mut Repl, args: &str) -> rpn::Result<()> {
+    match args {
+        "" => {
+            let dirs: Vec<String> = repl.stack.include_path().iter()
+                .map(|dir| dir.display().to_string())
+                .collect();
+            return repl.show(&dirs.join("\n"));
+        },
+        "off" => repl.stack.set_include_path(Vec::new()),
+        dirs => repl.stack.set_include_path(dirs.split_whitespace().map(PathBuf::from).collect()),
+    }
+    Ok(())
+}
+
+/// `:prompt <text>` sets the prompt, in double quotes to keep spaces.
+fn prompt(repl: &mut Repl, args: &str) -> rpn::Result<()> {
+    if args.is_empty() {
+        return Err(rpn::Error::Syntax);
+    }
+    repl.prompt = unquote(args).to_string();
+    Ok(())
+}
+
+/// `:limit <depth|steps|size|time|words|cells> <n|off>` bounds what a line may use
+/// (time in milliseconds), `:limit ops <tokens>|all` which operations are
+/// available, `:limit io on|off` whether they may use the console and
+/// files, `:limit sandbox` and `:limit off` set or clear them all.
+/// `:limit` shows them.
+fn limit(repl: &mut Repl, args: &str) -> rpn::Result<()> {
+    let mut limits = repl.stack.limits().clone();
+    let mut args = args.split_whitespace();
+    let (name, value) = match args.next() {
+        None => return repl.show(&show_limits(&limits)),
+        Some(name) => (name, args.next()),
+    };
+
+    // Reads the bound of a numeric limit.
+    let bound = |value: Option<&str>| -> rpn::Result<Option<u64>> {
+        match value {
+            Some("off") => Ok(None),
+            Some(n) => n.parse().map(Some).map_err(|_| rpn::Error::Syntax),
+            None => Err(rpn::Error::Syntax),
+        }
+    };
+
+    match name {
+        "sandbox" => limits = Limits::sandbox(),
+        "off" => limits = Limits::default(),
+        "depth" => limits.depth = bound(value)?.map(|n| n as usize),
+        "steps" => limits.steps = bound(value)?,
+        "size" => limits.size = bound(value)?.map(|n| n as usize),
+        "time" => limits.time = bound(value)?.map(Duration::from_millis),
+        "words" => limits.words = bound(value)?.map(|n| n as usize),
+        "cells" => limits.cells = bound(value)?.map(|n| n as usize),
+        "io" => limits.io = switch(value.unwrap_or(""))?,
+        "ops" => limits.ops = match value {
+            None => return Err(rpn::Error::Syntax),
+            Some("all") => None,
+            Some(first) => {
+                let ops = Some(first).into_iter().chain(args.by_ref())
+                    .map(get_operation)
+                    .collect::<rpn::Result<_>>()?;
+                Some(ops)
+            },
+        },
+        _ => return Err(rpn::Error::Syntax),
+    }
+    if args.next().is_some() {
+        return Err(rpn::Error::Syntax);
+    }
+    repl.stack.set_limits(limits);
+    Ok(())
+}
+
+/// What `:limit` shows about `limits`.
+fn show_limits(limits: &Limits) -> String {
+    let show = |bound: Option<String>| bound.unwrap_or_else(|| "off".to_string());
+    let ops = limits.ops.as_ref().map(|ops| {
+        ops.iter().map(|&op| op_token(op)).collect::<Vec<_>>().join(" ")
+    });
+    [format!("depth {}", show(limits.depth.map(|n| n.to_string()))),
+     format!("steps {}", show(limits.steps.map(|n| n.to_string()))),
+     format!("size  {}", show(limits.size.map(|n| n.to_string()))),
+     format!("time  {}", show(limits.time.map(|t| t.as_millis().to_string()))),
+     format!("words {}", show(limits.words.map(|n| n.to_string()))),
+     format!("cells {}", show(limits.cells.map(|n| n.to_string()))),
+     format!("ops   {}", ops.unwrap_or_else(|| "all".to_string())),
+     format!("io    {}", if limits.io { "on" } else { "off" })].join("\n")
+}
+
+/// `:reset` starts over with an empty stack, no words and no variables.
+/// The settings of the REPL, limits included, are kept.
+fn reset(repl: &mut Repl) -> rpn::Result<()> {
+    repl.stack.reset();
+    repl.history = History::new(repl.history.depth());
+    Ok(())
+}
+
+/// `:journal <path>` starts appending every line to a journal file,
+/// `:journal off` stops.
+fn journal(repl: &mut Repl, args: &str) -> rpn::Result<()> {
+    repl.journal = match path(args)? {
+        "off" => None,
+        path => Some(Journal::open(path, &mut repl.stack)?),
+    };
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use console::Buffer;
+    use rpn::{Elt, Error};
+
+    #[test]
+    fn test_is_command() {
+        assert!(is_command(":optimize 1 2 +"));
+        assert!(is_command("  :optimize"));
+        assert!(!is_command(": inc 1 + ;"));
+        assert!(!is_command("1 2 +"));
+    }
+
+    #[test]
+    fn test_unknown_command() {
+        let mut repl = Repl::new();
+        let res = run_command(&mut repl, ":frobnicate");
+        if let Err(Error::Syntax) = res { } else { panic!(); }
+    }
+
+    #[test]
+    fn test_optimize_leaves_stack() {
+        let mut repl = Repl::new();
+        assert!(run_command(&mut repl, ":optimize 2 3 +").is_ok());
+        assert_eq!(repl.stack.depth(), 0);
+
+        // Nor the dictionary, even when compiling fails.
+        assert!(run_command(&mut repl, ":optimize : foo 1 ; 5 !x foo").is_ok());
+        assert!(run_command(&mut repl, ":optimize : bar 1 ; frob").is_err());
+        assert!(repl.stack.words().lookup("foo").is_none());
+        assert!(repl.stack.words().lookup("bar").is_none());
+        assert!(repl.stack.words().variable("x").is_none());
+    }
+
+    #[test]
+    fn test_undo_redo() {
+        let mut repl = Repl::new();
+        assert!(repl.evaluate("1 2").is_ok());
+        assert!(repl.evaluate("+").is_ok());
+        assert!(repl.evaluate("true +").is_err());
+        assert_eq!(repl.stack.depth(), 0);
+
+        assert!(run_command(&mut repl, ":undo").is_ok());
+        assert_eq!(repl.stack.depth(), 1);
+        assert!(run_command(&mut repl, ":undo 5").is_ok());
+        assert_eq!(repl.stack.depth(), 0);
+        assert!(run_command(&mut repl, ":redo 2").is_ok());
+        assert_eq!(repl.stack.pop().unwrap(), Elt::Int(3));
+        assert!(run_command(&mut repl, ":undo x").is_err());
+    }
+
+    #[test]
+    fn test_history_depth() {
+        let mut repl = Repl::new();
+        assert!(run_command(&mut repl, ":history 1").is_ok());
+        assert_eq!(repl.history.depth(), 1);
+        assert!(run_command(&mut repl, ":history lots").is_err());
+    }
+
+    #[test]
+    fn test_display_settings() {
+        let mut repl = Repl::new();
+        assert!(run_command(&mut repl, ":display top").is_ok());
+        assert_eq!(repl.display.format, Format::Top);
+        assert!(run_command(&mut repl, ":display pretty 4").is_ok());
+        assert_eq!(repl.display.levels, Some(4));
+        assert!(run_command(&mut repl, ":display pretty four").is_err());
+        assert!(run_command(&mut repl, ":display").is_ok());
+    }
+
+    #[test]
+    fn test_mode() {
+        let mut repl = Repl::new();
+        assert!(repl.evaluate("1 2 3 4 5").is_ok());
+        assert!(run_command(&mut repl, ":mode hp").is_ok());
+        assert_eq!(repl.stack.mode(), Mode::Hp);
+        assert_eq!(repl.stack.depth(), 4);
+        assert!(run_command(&mut repl, ":mode rpl").is_err());
+
+        assert!(run_command(&mut repl, ":undo").is_ok());
+        assert_eq!(repl.stack.mode(), Mode::Unbounded);
+        assert_eq!(repl.stack.depth(), 5);
+    }
+
+    #[test]
+    fn test_help() {
+        let console = Buffer::new(&[]);
+        let mut repl = Repl::new();
+        repl.stack.set_console(Box::new(console.clone()));
+        assert!(repl.evaluate(": inc 1 + ; 5 !x").is_ok());
+
+        assert!(run_command(&mut repl, ":help").is_ok());
+        let help = console.take_output();
+        assert_eq!(help.lines().count(), OPERATIONS.len());
+        assert!(help.starts_with("+            y x -- y+x"));
+        assert!(run_command(&mut repl, ":help <->").is_ok());
+        assert!(console.take_output().starts_with("<->  ( y x -- x y )\n"));
+        assert!(run_command(&mut repl, ":help inc").is_ok());
+        assert_eq!(console.take_output(), ": inc 1 + ;\n");
+        assert!(run_command(&mut repl, ":words").is_ok());
+        let words = console.take_output();
+        assert!(words.starts_with("+ * "));
+        assert!(words.ends_with("\nwords: inc\nvariables: x\n"));
+
+        let res = run_command(&mut repl, ":help frob");
+        if let Err(Error::Syntax) = res { } else { panic!(); }
+        assert_eq!(console.output(), "");
+    }
+
+    #[test]
+    fn test_is_journaled() {
+        assert!(is_journaled("1 2 +"));
+        assert!(is_journaled(":undo"));
+        assert!(is_journaled(":seed 4"));
+        assert!(!is_journaled(":save x.rpn"));
+        assert!(!is_journaled(":trace on"));
+        assert!(!is_journaled(":help pow"));
+        assert!(!is_journaled("  \n"));
+    }
+
+    #[test]
+    fn test_reset_and_seed() {
+        let mut repl = Repl::new();
+        assert!(repl.evaluate(": inc 1 + ; 5 !x 1 2").is_ok());
+        assert!(run_command(&mut repl, ":reset").is_ok());
+        assert_eq!(repl.stack.depth(), 0);
+        assert!(repl.evaluate("x").is_err());
+        assert!(repl.evaluate("inc").is_err());
+
+        assert!(run_command(&mut repl, ":seed 12").is_ok());
+        assert!(run_command(&mut repl, ":seed twelve").is_err());
+        assert!(run_command(&mut repl, ":save").is_err());
+    }
+
+    #[test]
+    fn test_reset_keeps_settings() {
+        let mut repl = Repl::new();
+        for line in &[":mode hp", ":word 8", ":angle deg", ":ieee on", ":scale 2",
+                      ":rounding truncate", ":path lib", ":limit steps 100", ":base hex"] {
+            assert!(run_command(&mut repl, line).is_ok(), "{}", line);
+        }
+        let limits = repl.stack.limits().clone();
+        assert!(repl.evaluate("1 2").is_ok());
+        assert!(run_command(&mut repl, ":reset").is_ok());
+
+        assert_eq!(repl.stack.mode(), Mode::Hp);
+        assert_eq!(repl.stack.iter().cloned().collect::<Vec<_>>(), vec![Elt::Int(0); 4]);
+        assert_eq!(repl.stack.word_size(), 8);
+        assert_eq!(repl.stack.angle(), Angle::Deg);
+        assert!(repl.stack.ieee());
+        assert_eq!((repl.stack.scale(), repl.stack.rounding()), (2, Rounding::Truncate));
+        assert_eq!(repl.stack.include_path(), [PathBuf::from("lib")]);
+        assert_eq!(repl.stack.limits(), &limits);
+        assert_eq!(repl.display.base, Base::Hex);
+    }
+
+    #[test]
+    fn test_base_and_word() {
+        let mut repl = Repl::new();
+        assert!(run_command(&mut repl, ":base hex").is_ok());
+        assert_eq!((repl.display.base, repl.display.signed), (Base::Hex, false));
+        assert!(run_command(&mut repl, ":base dec").is_ok());
+        assert_eq!((repl.display.base, repl.display.signed), (Base::Dec, true));
+        assert!(run_command(&mut repl, ":base bin signed").is_ok());
+        assert_eq!((repl.display.base, repl.display.signed), (Base::Bin, true));
+        for line in &[":base", ":base hex maybe", ":base sexagesimal", ":word 12", ":word x"] {
+            assert!(run_command(&mut repl, line).is_err());
+        }
+
+        assert!(run_command(&mut repl, ":word 16").is_ok());
+        assert!(repl.evaluate("1 15 shl").is_ok());
+        assert_eq!(repl.stack.pop().unwrap(), Elt::Int(-32768));
+        assert!(run_command(&mut repl, ":word").is_ok());
+    }
+
+    #[test]
+    fn test_angle_and_ieee() {
+        let mut repl = Repl::new();
+        assert!(run_command(&mut repl, ":angle deg").is_ok());
+        assert_eq!(repl.stack.angle(), Angle::Deg);
+        assert!(run_command(&mut repl, ":angle grad").is_err());
+
+        let res = repl.evaluate("1 ~ sqrt");
+        if let Err(Error::Domain) = res { } else { panic!(); }
+        assert!(run_command(&mut repl, ":ieee").is_ok());
+        assert!(repl.evaluate("1 ~ sqrt").is_ok());
+        assert!(run_command(&mut repl, ":ieee off").is_ok());
+        assert!(!repl.stack.ieee());
+    }
+
+    #[test]
+    fn test_decimal_settings() {
+        let mut repl = Repl::new();
+        assert!(run_command(&mut repl, ":scale 2").is_ok());
+        assert!(run_command(&mut repl, ":rounding half-up").is_ok());
+        assert!(repl.evaluate("0.125 1 *  0.135 1.0 *").is_ok());
+        assert_eq!(repl.stack.pop().unwrap().to_string(), "0.14");
+        assert_eq!(repl.stack.pop().unwrap().to_string(), "0.13");
+        for line in &[":scale 0", ":scale 19", ":scale two", ":rounding up", ":separators maybe"] {
+            assert!(run_command(&mut repl, line).is_err());
+        }
+        assert!(run_command(&mut repl, ":separators").is_ok());
+        assert!(repl.display.separators);
+    }
+
+    #[test]
+    fn test_prompt() {
+        let mut repl = Repl::new();
+        assert!(run_command(&mut repl, ":prompt rpn>").is_ok());
+        assert_eq!(repl.prompt, "rpn>");
+        assert!(run_command(&mut repl, ":prompt \" rpn> \"").is_ok());
+        assert_eq!(repl.prompt, " rpn> ");
+        assert!(run_command(&mut repl, ":prompt").is_err());
+    }
+
+    #[test]
+    fn test_limit() {
+        let mut repl = Repl::new();
+        assert!(run_command(&mut repl, ":limit depth 3").is_ok());
+        assert!(run_command(&mut repl, ":limit ops + enter").is_ok());
+        assert!(run_command(&mut repl, ":limit time 500").is_ok());
+        assert_eq!(repl.stack.limits(), &Limits {
+            depth: Some(3),
+            ops: Some(vec![rpn::Op::Add, rpn::Op::Enter]),
+            time: Some(Duration::from_millis(500)),
+            ..Limits::default()
+        });
+        assert!(run_command(&mut repl, ":limit").is_ok());
+
+        let res = repl.evaluate("1 enter enter enter");
+        if let Err(Error::DepthLimit) = res { } else { panic!(); }
+        let res = repl.evaluate("~");
+        if let Err(Error::Denied(rpn::Op::Neg)) = res { } else { panic!(); }
+
+        // Limits are settings, they survive a reset.
+        assert!(run_command(&mut repl, ":limit words 2").is_ok());
+        assert!(run_command(&mut repl, ":reset").is_ok());
+        assert_eq!(repl.stack.limits().depth, Some(3));
+        assert!(repl.evaluate(": a 1 ; : b 1 ;").is_ok());
+        let res = repl.evaluate(": c 1 ;");
+        if let Err(Error::WordLimit) = res { } else { panic!(); }
+        assert!(run_command(&mut repl, ":limit words off").is_ok());
+        assert!(repl.evaluate(": c 1 ;").is_ok());
+        assert!(run_command(&mut repl, ":limit depth off").is_ok());
+        assert_eq!(repl.stack.limits().depth, None);
+        assert!(run_command(&mut repl, ":limit ops all").is_ok());
+        assert!(run_command(&mut repl, ":limit cells 4").is_ok());
+        let res = repl.evaluate("[[1 2 3]] enter transpose <-> *");
+        if let Err(Error::CellLimit) = res { } else { panic!(); }
+        assert!(run_command(&mut repl, ":limit cells off").is_ok());
+        assert!(run_command(&mut repl, ":limit io off").is_ok());
+        let res = repl.evaluate("1 .");
+        if let Err(Error::Denied(rpn::Op::Dot)) = res { } else { panic!(); }
+        assert!(run_command(&mut repl, ":limit io on").is_ok());
+        assert!(repl.stack.limits().io);
+        assert!(run_command(&mut repl, ":limit sandbox").is_ok());
+        assert_eq!(repl.stack.limits(), &Limits::sandbox());
+
+        for line in &[":limit depth", ":limit depth -1", ":limit ops", ":limit ops + frob",
+                      ":limit colour 3", ":limit steps 3 4"] {
+            assert!(run_command(&mut repl, line).is_err());
+        }
+    }
+
+    #[test]
+    fn test_include_path() {
+        let dir = ::std::env::temp_dir().join(format!("hw04-path-{}", ::std::process::id()));
+        ::std::fs::create_dir_all(&dir).unwrap();
+        ::std::fs::write(dir.join("seven.rpn"), ": seven 7 ;").unwrap();
+
+        let mut repl = Repl::new();
+        assert!(repl.evaluate("include \"seven.rpn\"").is_err());
+        assert!(run_command(&mut repl, &format!(":path /nowhere {}", dir.display())).is_ok());
+        assert_eq!(repl.stack.include_path(), &[PathBuf::from("/nowhere"), dir.clone()]);
+        assert!(run_command(&mut repl, ":path").is_ok());
+        assert!(repl.evaluate("include \"seven.rpn\" seven").is_ok());
+        assert_eq!(repl.stack.pop().unwrap(), Elt::Int(7));
+
+        // The path is a setting, it survives a reset.
+        assert!(run_command(&mut repl, ":reset").is_ok());
+        assert_eq!(repl.stack.include_path().len(), 2);
+        assert!(run_command(&mut repl, ":path off").is_ok());
+        assert!(repl.stack.include_path().is_empty());
+        ::std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_debugger_settings() {
+        let mut repl = Repl::new();
+        assert!(run_command(&mut repl, ":trace").is_ok());
+        assert!(run_command(&mut repl, ":step off").is_ok());
+        assert!(repl.debugger.trace && !repl.debugger.step);
+        assert!(run_command(&mut repl, ":trace maybe").is_err());
+
+        assert!(run_command(&mut repl, ":break inc").is_ok());
+        assert!(run_command(&mut repl, ":break 3").is_ok());
+        assert_eq!(repl.debugger.breakpoints,
+                   vec![Breakpoint::Word("inc".to_string()), Breakpoint::Position(3)]);
+        assert!(run_command(&mut repl, ":unbreak inc").is_ok());
+        assert_eq!(repl.debugger.breakpoints, vec![Breakpoint::Position(3)]);
+        assert!(run_command(&mut repl, ":unbreak").is_ok());
+        assert!(run_command(&mut repl, ":trace off").is_ok());
+        assert!(!repl.debugger.is_active());
+    }
+}
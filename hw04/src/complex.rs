@@ -0,0 +1,139 @@
+use std::fmt;
+use std::ops::{Add, Mul, Neg};
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+/// A complex number `re + im i`.
+pub struct Complex {
+    pub re: f64,
+    pub im: f64,
+}
+
+impl Complex {
+    pub fn new(re: f64, im: f64) -> Complex {
+        Complex { re, im }
+    }
+
+    /// The number of magnitude `r` at an angle of `theta` radians.
+    pub fn from_polar(r: f64, theta: f64) -> Complex {
+        Complex::new(r * theta.cos(), r * theta.sin())
+    }
+
+    pub fn is_zero(self) -> bool {
+        self.re == 0.0 && self.im == 0.0
+    }
+
+    pub fn conj(self) -> Complex {
+        Complex::new(self.re, -self.im)
+    }
+
+    /// The magnitude.
+    pub fn abs(self) -> f64 {
+        self.re.hypot(self.im)
+    }
+
+    /// The angle in radians, in (-π, π].
+    pub fn arg(self) -> f64 {
+        self.im.atan2(self.re)
+    }
+
+    /// The square root with a non-negative real part. Computed from the
+    /// parts rather than the angle, so `-4` gives exactly `2i`.
+    pub fn sqrt(self) -> Complex {
+        let r = self.abs();
+        let re = ((r + self.re) / 2.0).sqrt();
+        let im = ((r - self.re) / 2.0).sqrt();
+        Complex::new(re, if self.im.is_sign_negative() { -im } else { im })
+    }
+
+    pub fn exp(self) -> Complex {
+        Complex::from_polar(self.re.exp(), self.im)
+    }
+
+    /// The principal natural logarithm, the one with an imaginary part in
+    /// (-π, π].
+    pub fn ln(self) -> Complex {
+        Complex::new(self.abs().ln(), self.arg())
+    }
+
+    /// The principal value of `self` to the power `e`. Not defined for a
+    /// base of zero.
+    pub fn pow(self, e: Complex) -> Complex {
+        (e * self.ln()).exp()
+    }
+}
+
+impl Add for Complex {
+    type Output = Complex;
+
+    fn add(self, other: Complex) -> Complex {
+        Complex::new(self.re + other.re, self.im + other.im)
+    }
+}
+
+impl Mul for Complex {
+    type Output = Complex;
+
+    fn mul(self, other: Complex) -> Complex {
+        Complex::new(self.re * other.re - self.im * other.im,
+                     self.re * other.im + self.im * other.re)
+    }
+}
+
+impl Neg for Complex {
+    type Output = Complex;
+
+    fn neg(self) -> Complex {
+        Complex::new(-self.re, -self.im)
+    }
+}
+
+impl fmt::Display for Complex {
+    /// Formats the number as it is written in the input: `3+4i`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let sign = if self.im.is_sign_negative() { '-' } else { '+' };
+        write!(f, "{}{}{}i", self.re, sign, self.im.abs())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts;
+
+    fn close(a: Complex, b: Complex) -> bool {
+        (a.re - b.re).abs() < 1e-9 && (a.im - b.im).abs() < 1e-9
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        let z = Complex::new(3.0, 4.0);
+        assert_eq!(z.abs(), 5.0);
+        assert_eq!(z + z.conj(), Complex::new(6.0, 0.0));
+        assert_eq!(z * z.conj(), Complex::new(25.0, 0.0));
+        assert_eq!(-z, Complex::new(-3.0, -4.0));
+    }
+
+    #[test]
+    fn test_sqrt() {
+        assert_eq!(Complex::new(-4.0, 0.0).sqrt(), Complex::new(0.0, 2.0));
+        assert_eq!(Complex::new(-4.0, -0.0).sqrt(), Complex::new(0.0, -2.0));
+        assert_eq!(Complex::new(3.0, 4.0).sqrt(), Complex::new(2.0, 1.0));
+    }
+
+    #[test]
+    fn test_exp_ln() {
+        let z = Complex::new(0.0, consts::PI).exp();
+        assert!(close(z, Complex::new(-1.0, 0.0)));
+        let z = Complex::new(-1.0, 0.0).ln();
+        assert!(close(z, Complex::new(0.0, consts::PI)));
+        let z = Complex::new(0.0, 1.0).pow(Complex::new(2.0, 0.0));
+        assert!(close(z, Complex::new(-1.0, 0.0)));
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(Complex::new(3.0, 4.0).to_string(), "3+4i");
+        assert_eq!(Complex::new(0.5, -1.0).to_string(), "0.5-1i");
+        assert_eq!(Complex::new(-0.0, -0.0).to_string(), "-0-0i");
+    }
+}
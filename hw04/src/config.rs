@@ -0,0 +1,159 @@
+use std::env;
+use std::path::{Path, PathBuf};
+
+use commands::run_command;
+use history::History;
+use parser::Repl;
+use rpn;
+use session::read_lines;
+
+/// The settings file, `key = value` lines.
+pub const SETTINGS: &str = "settings";
+/// The startup script, input lines run before the first prompt.
+pub const RC: &str = "rc.rpn";
+
+/// The settings a settings file may change. Each one is set with the
+/// REPL command of the same name, e.g. `display = top` runs
+/// `:display top`.
+pub const KEYS: &[&str] = &[
+    "prompt", "display", "mode", "seed", "history", "limit", "base", "word", "angle", "ieee",
+    "scale", "rounding", "separators", "path",
+];
+
+/// The directory holding the startup files: `$XDG_CONFIG_HOME/hw04`, or
+/// `~/.config/hw04` without it.
+pub fn config_dir() -> Option<PathBuf> {
+    env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+        .map(|dir| dir.join("hw04"))
+}
+
+#[derive(Debug)]
+/// A line of a startup file that failed, which startup carries on after.
+pub struct Problem {
+    pub path: PathBuf,
+    /// The number of the line, from 1.
+    pub line: usize,
+    pub error: rpn::Error,
+}
+
+/// Reads a settings line into the command it stands for. Blank lines and
+/// `#` comments stand for none.
+fn setting(line: &str) -> rpn::Result<Option<String>> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+
+    let mut parts = line.splitn(2, '=');
+    let key = parts.next().unwrap_or("").trim();
+    let value = parts.next().ok_or(rpn::Error::Syntax)?.trim();
+    if !KEYS.contains(&key) {
+        return Err(rpn::Error::Syntax);
+    }
+    Ok(Some(format!(":{} {}", key, value)))
+}
+
+/// Sets up `repl` from the files in `dir`: first the settings, then the
+/// startup script, which may define words and variables or push values.
+/// Missing files are skipped; failing lines are returned, the others
+/// still apply. What the script did cannot be undone.
+pub fn startup(repl: &mut Repl, dir: &Path) -> Vec<Problem> {
+    let mut problems = Vec::new();
+
+    let path = dir.join(SETTINGS);
+    if path.is_file() {
+        run_file(&path, &mut problems, |line| match setting(line)? {
+            Some(command) => run_command(repl, &command),
+            None => Ok(()),
+        });
+    }
+
+    let path = dir.join(RC);
+    if path.is_file() {
+        run_file(&path, &mut problems, |line| repl.handle(line));
+    }
+
+    repl.history = History::new(repl.history.depth());
+    problems
+}
+
+/// Runs `f` on every line of the file at `path`, collecting the failures.
+fn run_file<F>(path: &Path, problems: &mut Vec<Problem>, mut f: F)
+    where F: FnMut(&str) -> rpn::Result<()>
+{
+    let lines = match read_lines(path) {
+        Ok(lines) => lines,
+        Err(error) => {
+            problems.push(Problem { path: path.to_path_buf(), line: 0, error });
+            return;
+        },
+    };
+    for (i, line) in lines.iter().enumerate() {
+        if let Err(error) = f(line) {
+            problems.push(Problem { path: path.to_path_buf(), line: i + 1, error });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use display::Format;
+    use rpn::{Elt, Error, Mode};
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("hw04-{}-{}", name, ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_setting() {
+        assert_eq!(setting(" display = top 3").unwrap(), Some(":display top 3".to_string()));
+        assert_eq!(setting("prompt = \"rpn> \"").unwrap(), Some(":prompt \"rpn> \"".to_string()));
+        assert_eq!(setting("# a comment").unwrap(), None);
+        assert_eq!(setting("").unwrap(), None);
+        for line in &["display top", "journal = x.rpn", "= 4"] {
+            if let Err(Error::Syntax) = setting(line) { } else { panic!(); }
+        }
+    }
+
+    #[test]
+    fn test_startup() {
+        let dir = temp_dir("config");
+        fs::write(dir.join(SETTINGS),
+                  "# settings\nprompt = \"rpn> \"\ndisplay = top\nmode = hp\ncolor = red\n").unwrap();
+        fs::write(dir.join(RC), ": sq enter + ;\n3 !x\n\nx sq\n1 true +\n").unwrap();
+
+        let mut repl = Repl::new();
+        let problems = startup(&mut repl, &dir);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(repl.prompt, "rpn> ");
+        assert_eq!(repl.display.format, Format::Top);
+        assert_eq!(repl.stack.mode(), Mode::Hp);
+        assert_eq!(repl.stack.pop().unwrap(), Elt::Int(6));
+
+        assert_eq!(problems.len(), 2);
+        assert_eq!((problems[0].path.file_name().unwrap(), problems[0].line),
+                   (SETTINGS.as_ref(), 5));
+        assert_eq!((problems[1].path.file_name().unwrap(), problems[1].line), (RC.as_ref(), 5));
+        if let Error::Type = problems[1].error { } else { panic!(); }
+
+        // The startup lines are not part of the history.
+        assert!(!repl.history.undo(&mut repl.stack));
+    }
+
+    #[test]
+    fn test_startup_without_files() {
+        let dir = temp_dir("config-empty");
+        let mut repl = Repl::new();
+        assert!(startup(&mut repl, &dir).is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(repl.prompt, "> ");
+    }
+}
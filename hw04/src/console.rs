@@ -0,0 +1,107 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{self, BufRead, Write};
+use std::mem;
+use std::rc::Rc;
+
+/// Where the console operations of a stack (`.`, `read-line`, ...) write
+/// and read.
+pub trait Console {
+    /// Writes text as it is, with no newline added.
+    fn write(&mut self, text: &str) -> io::Result<()>;
+    /// Reads a line without its line ending, `None` at the end of input.
+    fn read_line(&mut self) -> io::Result<Option<String>>;
+}
+
+/// The standard output and input of the process.
+pub struct StdConsole;
+
+impl Console for StdConsole {
+    /// Flushes right away, so a prompt shows before the input it asks for.
+    fn write(&mut self, text: &str) -> io::Result<()> {
+        let mut out = io::stdout();
+        out.write_all(text.as_bytes())?;
+        out.flush()
+    }
+
+    fn read_line(&mut self) -> io::Result<Option<String>> {
+        let mut line = String::new();
+        if io::stdin().lock().read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let len = line.trim_end_matches(['\n', '\r']).len();
+        line.truncate(len);
+        Ok(Some(line))
+    }
+}
+
+/// The standard error of the process, with no input: for modes where the
+/// standard output and input carry data, like `--format` and `--map`.
+pub struct Stderr;
+
+impl Console for Stderr {
+    fn write(&mut self, text: &str) -> io::Result<()> {
+        io::stderr().write_all(text.as_bytes())
+    }
+
+    fn read_line(&mut self) -> io::Result<Option<String>> {
+        Ok(None)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+/// A console reading from given lines and writing to a string, for tests
+/// and embedders that show the output themselves. Clones share their
+/// input and output.
+pub struct Buffer {
+    input: Rc<RefCell<VecDeque<String>>>,
+    output: Rc<RefCell<String>>,
+}
+
+impl Buffer {
+    /// A console whose input is `lines`.
+    pub fn new(lines: &[&str]) -> Buffer {
+        let input = lines.iter().map(|l| l.to_string()).collect();
+        Buffer { input: Rc::new(RefCell::new(input)), output: Rc::default() }
+    }
+
+    /// Everything written so far.
+    pub fn output(&self) -> String {
+        self.output.borrow().clone()
+    }
+
+    /// Everything written since the last call, which is then forgotten.
+    pub fn take_output(&self) -> String {
+        mem::take(&mut *self.output.borrow_mut())
+    }
+}
+
+impl Console for Buffer {
+    fn write(&mut self, text: &str) -> io::Result<()> {
+        self.output.borrow_mut().push_str(text);
+        Ok(())
+    }
+
+    fn read_line(&mut self) -> io::Result<Option<String>> {
+        Ok(self.input.borrow_mut().pop_front())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buffer() {
+        let buffer = Buffer::new(&["a", "b"]);
+        let mut console = buffer.clone();
+        assert_eq!(console.read_line().unwrap(), Some("a".to_string()));
+        assert_eq!(console.read_line().unwrap(), Some("b".to_string()));
+        assert_eq!(console.read_line().unwrap(), None);
+        console.write("x").unwrap();
+        console.write("y\n").unwrap();
+        assert_eq!(buffer.output(), "xy\n");
+        assert_eq!(buffer.take_output(), "xy\n");
+        assert_eq!(buffer.output(), "");
+    }
+}
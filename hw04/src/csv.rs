@@ -0,0 +1,273 @@
+use std::cmp::Ordering;
+use std::io::{BufRead, Write};
+
+use bytecode::{self, is_new_name, Program};
+use console::Stderr;
+use optimizer;
+use output::csv_field;
+use parser::get_value;
+use rpn::{self, Elt, Op, Snapshot, Stack};
+
+/// Splits a CSV line into its fields. Fields may be quoted, with `""`
+/// standing for a quote; quoted line breaks are not supported.
+pub fn split_row(line: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            },
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(field.split_off(0)),
+            c => field.push(c),
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+/// A program run once per row of a table.
+///
+/// Before each run the stack is emptied, every variable is unset, the
+/// row's values are pushed in column order and every column named like a
+/// variable (`price`, not `unit price`) is bound to a variable of that
+/// name. The value left on top is the result, so nothing a run stores
+/// carries over to the next row.
+pub struct RowProgram {
+    stack: Stack,
+    program: Program,
+    /// The variable slot of each column, if it has one.
+    slots: Vec<Option<usize>>,
+    empty: Snapshot,
+}
+
+impl RowProgram {
+    /// Compiles `program` for rows with the columns of `header`.
+    pub fn new(header: &[String], program: &str) -> rpn::Result<RowProgram> {
+        let mut stack = Stack::new();
+        stack.set_console(Box::new(Stderr));
+        let slots = header.iter()
+            .map(|name| {
+                let name = name.trim();
+                if is_new_name(name) {
+                    stack.words_mut().declare(name).map(Some)
+                } else {
+                    Ok(None)
+                }
+            })
+            .collect::<rpn::Result<_>>()?;
+
+        let program = bytecode::compile(program, stack.words_mut())?;
+        let program = optimizer::optimize(&program, &stack);
+        let empty = stack.snapshot();
+        Ok(RowProgram { stack, program, slots, empty })
+    }
+
+    /// Runs the program on a row and returns its result.
+    pub fn apply(&mut self, row: &[String]) -> rpn::Result<Elt> {
+        if row.len() != self.slots.len() {
+            return Err(rpn::Error::Syntax);
+        }
+        self.stack.restore(self.empty.clone());
+        self.stack.words_mut().unset_all();
+        for (cell, slot) in row.iter().zip(&self.slots) {
+            let value = get_value(cell.trim())?;
+            if let Some(slot) = *slot {
+                self.stack.words_mut().set(slot, value.clone());
+            }
+            self.stack.push(value)?;
+        }
+        bytecode::run(&self.program, &mut self.stack)?;
+        self.stack.pop()
+    }
+}
+
+/// Reads the header of a table and compiles `program` for its rows.
+fn start<R: BufRead>(lines: &mut R, program: &str) -> rpn::Result<(String, RowProgram)> {
+    let mut header = String::new();
+    if lines.read_line(&mut header).map_err(rpn::Error::io)? == 0 {
+        return Err(rpn::Error::Syntax);
+    }
+    let header = header.trim_end_matches(['\n', '\r']).to_string();
+    let row_program = RowProgram::new(&split_row(&header), program)?;
+    Ok((header, row_program))
+}
+
+/// Copies a CSV table from `input` to `output` with a `result` column
+/// added: the result of `program` on each row. A row the program fails on
+/// gets an empty result, and its error is reported on `errors`.
+pub fn map<R: BufRead, W: Write, E: Write>(program: &str,
+                                           mut input: R,
+                                           mut output: W,
+                                           mut errors: E) -> rpn::Result<()> {
+    let (header, mut row_program) = start(&mut input, program)?;
+    writeln!(output, "{},result", header).map_err(rpn::Error::io)?;
+
+    for (i, line) in input.lines().enumerate() {
+        let line = line.map_err(rpn::Error::io)?;
+        let result = match row_program.apply(&split_row(&line)) {
+            Ok(Elt::Str(s)) => csv_field(&s),
+            Ok(v) => csv_field(&v.to_string()),
+            Err(e) => {
+                writeln!(errors, "row {}: {:?}", i + 1, e).map_err(rpn::Error::io)?;
+                String::new()
+            },
+        };
+        writeln!(output, "{},{}", line, result).map_err(rpn::Error::io)?;
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// How `reduce` combines the results of the rows.
+pub enum Aggregate {
+    Sum,
+    Count,
+    Min,
+    Max,
+}
+
+impl Aggregate {
+    /// Reads an aggregate name: `sum`, `count`, `min` or `max`.
+    pub fn parse(name: &str) -> rpn::Result<Aggregate> {
+        match name {
+            "sum" => Ok(Aggregate::Sum),
+            "count" => Ok(Aggregate::Count),
+            "min" => Ok(Aggregate::Min),
+            "max" => Ok(Aggregate::Max),
+            _ => Err(rpn::Error::Syntax),
+        }
+    }
+
+    /// Combines the aggregate so far with the result of another row.
+    fn combine(self, acc: Option<Elt>, v: Elt) -> rpn::Result<Elt> {
+        let acc = match acc {
+            None if self == Aggregate::Count => return Ok(Elt::Int(1)),
+            None => return Ok(v),
+            Some(acc) => acc,
+        };
+        match (self, acc, v) {
+            (Aggregate::Count, Elt::Int(n), _) => n.checked_add(1).map(Elt::Int).ok_or(rpn::Error::Overflow),
+            // Adds like `+`, whatever the types of the numbers.
+            (Aggregate::Sum, a, b) => {
+                let mut stack = Stack::new();
+                stack.push(a)?;
+                stack.push(b)?;
+                stack.eval(Op::Add)?;
+                stack.pop()
+            },
+            (Aggregate::Min, Elt::Bool(a), Elt::Bool(b)) => Ok(Elt::Bool(a && b)),
+            (Aggregate::Max, Elt::Bool(a), Elt::Bool(b)) => Ok(Elt::Bool(a || b)),
+            (aggregate, a, b) => match a.compare(&b) {
+                Some(Ordering::Greater) if aggregate == Aggregate::Min => Ok(b),
+                Some(Ordering::Less) if aggregate == Aggregate::Max => Ok(b),
+                Some(_) => Ok(a),
+                None => Err(rpn::Error::Type),
+            },
+        }
+    }
+}
+
+/// Runs `program` on every row of a CSV table from `input` and combines
+/// the results. Rows the program fails on are left out, and their errors
+/// reported on `errors`. The sum of no rows is 0, and so is their count;
+/// their minimum and maximum are `None`.
+pub fn reduce<R: BufRead, E: Write>(aggregate: Aggregate,
+                                    program: &str,
+                                    mut input: R,
+                                    mut errors: E) -> rpn::Result<Option<Elt>> {
+    let (_, mut row_program) = start(&mut input, program)?;
+    let mut acc = None;
+
+    for (i, line) in input.lines().enumerate() {
+        let line = line.map_err(rpn::Error::io)?;
+        match row_program.apply(&split_row(&line)) {
+            Ok(v) => acc = Some(aggregate.combine(acc, v)?),
+            Err(e) => writeln!(errors, "row {}: {:?}", i + 1, e).map_err(rpn::Error::io)?,
+        }
+    }
+
+    match aggregate {
+        Aggregate::Sum | Aggregate::Count => Ok(acc.or(Some(Elt::Int(0)))),
+        Aggregate::Min | Aggregate::Max => Ok(acc),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TABLE: &str = "a,b,unit price\n1,2,10\n3,4,20\nx,5,30\n";
+
+    #[test]
+    fn test_split_row() {
+        assert_eq!(split_row("1,2"), vec!["1", "2"]);
+        assert_eq!(split_row("\"a,b\",\"say \"\"hi\"\"\","), vec!["a,b", "say \"hi\"", ""]);
+    }
+
+    #[test]
+    fn test_map() {
+        let (mut out, mut errors) = (Vec::new(), Vec::new());
+        assert!(map("a b + 2 *", TABLE.as_bytes(), &mut out, &mut errors).is_ok());
+        assert_eq!(String::from_utf8(out).unwrap(),
+                   "a,b,unit price,result\n1,2,10,6\n3,4,20,14\nx,5,30,\n");
+        assert_eq!(String::from_utf8(errors).unwrap(), "row 3: Syntax\n");
+    }
+
+    #[test]
+    fn test_map_pushes_columns() {
+        // The unnamed column is only on the stack.
+        let mut out = Vec::new();
+        assert!(map("<-> ~ +", "a,b,unit price\n1,2,10\n".as_bytes(), &mut out, Vec::new()).is_ok());
+        assert_eq!(String::from_utf8(out).unwrap(), "a,b,unit price,result\n1,2,10,8\n");
+    }
+
+    #[test]
+    fn test_map_strings() {
+        // Strings are written as their contents, quoted only as CSV needs.
+        let mut out = Vec::new();
+        let program = "\"say \\\"hi\\\", then\" \"ok\" +";
+        assert!(map(program, "a\n1\n".as_bytes(), &mut out, Vec::new()).is_ok());
+        assert_eq!(String::from_utf8(out).unwrap(), "a,result\n1,\"say \"\"hi\"\", thenok\"\n");
+    }
+
+    #[test]
+    fn test_map_resets_variables() {
+        // `y` is unset on every row, so each row stores its own `a` in it.
+        // The first block only declares `y`: it throws before the store.
+        let mut out = Vec::new();
+        let program = "try [ 0 throw 0 !y ] catch [ !err ] try [ y ] catch [ !err a !y y ]";
+        assert!(map(program, "a\n1\n2\n".as_bytes(), &mut out, Vec::new()).is_ok());
+        assert_eq!(String::from_utf8(out).unwrap(), "a,result\n1,1\n2,2\n");
+    }
+
+    #[test]
+    fn test_map_bad_program() {
+        let res = map("a frob", TABLE.as_bytes(), Vec::new(), Vec::new());
+        if let Err(rpn::Error::Syntax) = res { } else { panic!(); }
+    }
+
+    #[test]
+    fn test_reduce() {
+        let reduce_table = |aggregate, program| {
+            reduce(aggregate, program, TABLE.as_bytes(), Vec::new()).unwrap()
+        };
+        assert_eq!(reduce_table(Aggregate::Sum, "a b *"), Some(Elt::Int(14)));
+        assert_eq!(reduce_table(Aggregate::Count, "a"), Some(Elt::Int(2)));
+        assert_eq!(reduce_table(Aggregate::Max, "b"), Some(Elt::Int(4)));
+        assert_eq!(reduce_table(Aggregate::Min, "a 1 ="), Some(Elt::Bool(false)));
+
+        assert_eq!(reduce_table(Aggregate::Sum, "a 0.5 *").unwrap().to_string(), "2.0");
+        assert_eq!(reduce_table(Aggregate::Min, "a 2e0 *"), Some(Elt::Float(2.0)));
+
+        let res = reduce(Aggregate::Sum, "a 1 =", TABLE.as_bytes(), Vec::new());
+        if let Err(rpn::Error::Type) = res { } else { panic!(); }
+        let res = reduce(Aggregate::Min, "a", "a\n".as_bytes(), Vec::new());
+        assert_eq!(res.unwrap(), None);
+    }
+}
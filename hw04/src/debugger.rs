@@ -0,0 +1,314 @@
+use std::io::{BufRead, Write};
+
+use bytecode::{instr_token, Instr, Next, Program, Vm};
+use parser::get_value;
+use rpn::{self, Stack};
+
+/// Observes a program while the `Vm` runs it.
+pub trait Hooks {
+    /// Called before each instruction runs. May inspect and edit the stack.
+    fn before(&mut self, next: &Next, stack: &mut Stack) -> rpn::Result<()>;
+    /// Called after each instruction ran, with its result.
+    fn after(&mut self, next: &Next, stack: &Stack, result: &rpn::Result<bool>) -> rpn::Result<()>;
+}
+
+/// Runs `program` on `stack`, calling `hooks` around every instruction.
+pub fn run_with<H: Hooks>(program: &Program, stack: &mut Stack, hooks: &mut H) -> rpn::Result<()> {
+    let mut vm = Vm::new(program);
+    while let Some(next) = vm.peek(stack) {
+        hooks.before(&next, stack)?;
+        let result = vm.step(stack);
+        hooks.after(&next, stack, &result)?;
+        result?;
+    }
+    Ok(())
+}
+
+/// Formats the stack bottom first, e.g. `[1, 2, true]`.
+pub fn format_stack(stack: &Stack) -> String {
+    let mut elts: Vec<String> = stack.iter().map(|e| e.to_string()).collect();
+    elts.reverse();
+    format!("[{}]", elts.join(", "))
+}
+
+/// Describes where an instruction is: its token position and, inside a
+/// word, the word's name.
+fn location(next: &Next, stack: &Stack) -> String {
+    match next.frame.word {
+        None => format!("{}", next.pos),
+        Some(addr) => format!("{}:{}", stack.words().name(addr), next.pos),
+    }
+}
+
+/// What an instruction resolved to, e.g. `Op(Add)` or `Call(inc)`.
+fn resolved(instr: &Instr, stack: &Stack) -> String {
+    let words = stack.words();
+    match *instr {
+        Instr::Call(addr) => format!("Call({})", words.name(addr)),
+        Instr::Store(slot) => format!("Store({})", words.var_name(slot)),
+        Instr::Load(slot) => format!("Load({})", words.var_name(slot)),
+        Instr::Unit(ref unit) => format!("Unit({})", unit),
+        Instr::Convert(ref unit) => format!("Convert({})", unit),
+        ref other => format!("{:?}", other),
+    }
+}
+
+/// Hooks writing one line per instruction: its position, token, what it
+/// resolved to and the stack before and after it.
+pub struct Tracer<W: Write> {
+    out: W,
+    before: String,
+}
+
+impl<W: Write> Tracer<W> {
+    pub fn new(out: W) -> Tracer<W> {
+        Tracer { out, before: String::new() }
+    }
+}
+
+impl<W: Write> Hooks for Tracer<W> {
+    fn before(&mut self, _next: &Next, stack: &mut Stack) -> rpn::Result<()> {
+        self.before = format_stack(stack);
+        Ok(())
+    }
+
+    fn after(&mut self, next: &Next, stack: &Stack, result: &rpn::Result<bool>) -> rpn::Result<()> {
+        let after = match *result {
+            Ok(_) => format_stack(stack),
+            Err(ref e) => format!("{:?}", e),
+        };
+        writeln!(self.out, "{:indent$}[{}] {:<8} {:<16} {} -> {}",
+                 "",
+                 location(next, stack),
+                 instr_token(&next.instr, stack.words()),
+                 resolved(&next.instr, stack),
+                 self.before,
+                 after,
+                 indent = 2 * next.depth).map_err(rpn::Error::io)
+    }
+}
+
+/// Runs `program` on `stack`, writing a trace of every instruction to `out`.
+pub fn trace<W: Write>(program: &Program, stack: &mut Stack, out: W) -> rpn::Result<()> {
+    run_with(program, stack, &mut Tracer::new(out))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Where the debugger pauses a program.
+pub enum Breakpoint {
+    /// Before every use of a word or builtin operation, e.g. `inc` or `+`.
+    Word(String),
+    /// Before the token at this position of the line.
+    Position(usize),
+}
+
+impl Breakpoint {
+    /// Reads a breakpoint: a number is a token position, anything else a word.
+    pub fn parse(arg: &str) -> Breakpoint {
+        arg.parse()
+            .map(Breakpoint::Position)
+            .unwrap_or_else(|_| Breakpoint::Word(arg.to_string()))
+    }
+
+    fn hits(&self, next: &Next, stack: &Stack) -> bool {
+        match *self {
+            Breakpoint::Word(ref w) => match next.instr {
+                Instr::Push(_) => false,
+                ref instr => instr_token(instr, stack.words()) == *w,
+            },
+            Breakpoint::Position(p) => next.frame.word.is_none() && next.pos == p,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+/// Debugging settings of a session: tracing, stepping and breakpoints.
+pub struct Debugger {
+    /// Print every instruction as it runs.
+    pub trace: bool,
+    /// Pause before every instruction.
+    pub step: bool,
+    pub breakpoints: Vec<Breakpoint>,
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger::default()
+    }
+
+    /// Whether running through the debugger differs from a plain run.
+    pub fn is_active(&self) -> bool {
+        self.trace || self.step || !self.breakpoints.is_empty()
+    }
+
+    /// Runs `program` on `stack`. When it pauses, debugger commands are
+    /// read from `input`; trace and debugger output go to `output`.
+    pub fn run<R: BufRead, W: Write>(&self,
+                                     program: &Program,
+                                     stack: &mut Stack,
+                                     input: R,
+                                     output: W) -> rpn::Result<()> {
+        let mut session = Session {
+            debugger: self,
+            stepping: self.step,
+            input,
+            tracer: Tracer::new(output),
+        };
+        run_with(program, stack, &mut session)
+    }
+}
+
+/// The state of one debugged run.
+struct Session<'a, R, W: Write> {
+    debugger: &'a Debugger,
+    stepping: bool,
+    input: R,
+    tracer: Tracer<W>,
+}
+
+impl<'a, R: BufRead, W: Write> Session<'a, R, W> {
+    /// Reads and runs commands until the user resumes the program.
+    fn pause(&mut self, next: &Next, stack: &mut Stack) -> rpn::Result<()> {
+        writeln!(self.tracer.out, "paused at [{}] {}",
+                 location(next, stack),
+                 instr_token(&next.instr, stack.words())).map_err(rpn::Error::io)?;
+
+        loop {
+            write!(self.tracer.out, "(debug) ").map_err(rpn::Error::io)?;
+            self.tracer.out.flush().map_err(rpn::Error::io)?;
+
+            let mut line = String::new();
+            if self.input.read_line(&mut line).map_err(rpn::Error::io)? == 0 {
+                // Out of input, let the program run to the end.
+                self.stepping = false;
+                return Ok(());
+            }
+
+            let mut args = line.split_whitespace();
+            let reply = match args.next().unwrap_or("step") {
+                "s" | "step" => {
+                    self.stepping = true;
+                    return Ok(());
+                },
+                "c" | "continue" => {
+                    self.stepping = false;
+                    return Ok(());
+                },
+                "stack" => format_stack(stack),
+                "pop" => match stack.pop() {
+                    Ok(v) => v.to_string(),
+                    Err(e) => format!("Error: {:?}", e),
+                },
+                "push" => match args.map(get_value).collect::<rpn::Result<Vec<_>>>() {
+                    Ok(values) => {
+                        for v in values {
+                            stack.push(v)?;
+                        }
+                        format_stack(stack)
+                    },
+                    Err(e) => format!("Error: {:?}", e),
+                },
+                _ => "commands: step, continue, stack, pop, push <values>".to_string(),
+            };
+            writeln!(self.tracer.out, "{}", reply).map_err(rpn::Error::io)?;
+        }
+    }
+}
+
+impl<'a, R: BufRead, W: Write> Hooks for Session<'a, R, W> {
+    fn before(&mut self, next: &Next, stack: &mut Stack) -> rpn::Result<()> {
+        let hit = self.debugger.breakpoints.iter().any(|b| b.hits(next, stack));
+        if self.stepping || hit {
+            self.pause(next, stack)?;
+        }
+        self.tracer.before(next, stack)
+    }
+
+    fn after(&mut self, next: &Next, stack: &Stack, result: &rpn::Result<bool>) -> rpn::Result<()> {
+        if self.debugger.trace {
+            self.tracer.after(next, stack, result)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytecode::compile;
+    use rpn::{Elt, Error};
+    use std::io::Cursor;
+
+    fn run_debugger(debugger: &Debugger, src: &str, input: &str) -> (Stack, rpn::Result<()>, String) {
+        let mut stack = Stack::new();
+        let mut output = Vec::new();
+        let res = compile(src, stack.words_mut()).and_then(|p| {
+            debugger.run(&p, &mut stack, Cursor::new(input), &mut output)
+        });
+        (stack, res, String::from_utf8(output).unwrap())
+    }
+
+    #[test]
+    fn test_trace() {
+        let mut stack = Stack::new();
+        let p = compile(": inc 1 + ; 12 inc", stack.words_mut()).unwrap();
+        let mut out = Vec::new();
+        assert!(trace(&p, &mut stack, &mut out).is_ok());
+
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].starts_with("[5] 12"));
+        assert!(lines[0].ends_with("[] -> [12]"));
+        assert!(lines[1].contains("Call(inc)"));
+        assert!(lines[3].starts_with("  [inc:3] +"));
+        assert!(lines[3].contains("Op(Add)"));
+        assert!(lines[3].ends_with("[12, 1] -> [13]"));
+    }
+
+    #[test]
+    fn test_trace_error() {
+        let mut stack = Stack::new();
+        let p = compile("1 true +", stack.words_mut()).unwrap();
+        let mut out = Vec::new();
+        let res = trace(&p, &mut stack, &mut out);
+        if let Err(Error::Type) = res { } else { panic!(); }
+        assert!(String::from_utf8(out).unwrap().ends_with("[1, true] -> Type\n"));
+    }
+
+    #[test]
+    fn test_breakpoint_parse() {
+        assert_eq!(Breakpoint::parse("3"), Breakpoint::Position(3));
+        assert_eq!(Breakpoint::parse("inc"), Breakpoint::Word("inc".to_string()));
+    }
+
+    #[test]
+    fn test_break_on_word_and_edit() {
+        let mut debugger = Debugger::new();
+        debugger.breakpoints.push(Breakpoint::parse("+"));
+        let (mut stack, res, out) = run_debugger(&debugger, "1 2 +", "pop\npush 40\nc\n");
+        assert!(res.is_ok());
+        assert!(out.starts_with("paused at [2] +"));
+        assert_eq!(stack.pop().unwrap(), Elt::Int(41));
+    }
+
+    #[test]
+    fn test_break_on_position() {
+        let mut debugger = Debugger::new();
+        debugger.breakpoints.push(Breakpoint::Position(1));
+        let (_, res, out) = run_debugger(&debugger, "1 2 +", "stack\nc\n");
+        assert!(res.is_ok());
+        assert!(out.starts_with("paused at [1] 2\n(debug) [1]\n"));
+    }
+
+    #[test]
+    fn test_step() {
+        let mut debugger = Debugger::new();
+        debugger.step = true;
+        let (mut stack, res, out) = run_debugger(&debugger, ": inc 1 + ; 5 inc", "s\ns\n\nc\n");
+        assert!(res.is_ok());
+        assert_eq!(out.matches("paused").count(), 4);
+        assert!(out.contains("paused at [inc:3] +"));
+        assert_eq!(stack.pop().unwrap(), Elt::Int(6));
+    }
+}
@@ -0,0 +1,264 @@
+use std::cmp::Ordering;
+use std::fmt;
+
+use rpn;
+
+/// The most digits a decimal keeps after the point.
+pub const MAX_SCALE: u32 = 18;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// How a decimal loses digits when it is rounded to a smaller scale.
+pub enum Rounding {
+    /// To the nearest, halves to the even neighbour: 0.125 is 0.12.
+    HalfEven,
+    /// To the nearest, halves away from zero: 0.125 is 0.13.
+    HalfUp,
+    /// Towards zero: 0.129 is 0.12.
+    Truncate,
+}
+
+impl Rounding {
+    /// Reads a rounding mode: `half-even`, `half-up` or `truncate`.
+    pub fn parse(name: &str) -> rpn::Result<Rounding> {
+        match name {
+            "half-even" => Ok(Rounding::HalfEven),
+            "half-up" => Ok(Rounding::HalfUp),
+            "truncate" => Ok(Rounding::Truncate),
+            _ => Err(rpn::Error::Syntax),
+        }
+    }
+
+    /// The name `parse` reads.
+    pub fn name(self) -> &'static str {
+        match self {
+            Rounding::HalfEven => "half-even",
+            Rounding::HalfUp => "half-up",
+            Rounding::Truncate => "truncate",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+/// An exact decimal number: `units` over 10 to the `scale`, so 1999 at a
+/// scale of 2 is 19.99.
+///
+/// Decimals compare by value, `0.30` equals `0.3`. Operations return
+/// `None` when the result does not fit.
+pub struct Decimal {
+    units: i128,
+    scale: u32,
+}
+
+fn pow10(n: u32) -> i128 {
+    10i128.pow(n)
+}
+
+impl Decimal {
+    pub fn new(units: i128, scale: u32) -> Decimal {
+        Decimal { units, scale }
+    }
+
+    pub fn from_int(i: i64) -> Decimal {
+        Decimal::new(i128::from(i), 0)
+    }
+
+    /// The number of digits after the point.
+    pub fn scale(self) -> u32 {
+        self.scale
+    }
+
+    /// The nearest float.
+    pub fn to_f64(self) -> f64 {
+        self.to_string().parse().unwrap_or(f64::NAN)
+    }
+
+    /// The same number at a larger scale.
+    fn widen(self, scale: u32) -> Option<Decimal> {
+        let units = self.units.checked_mul(pow10(scale - self.scale))?;
+        Some(Decimal::new(units, scale))
+    }
+
+    /// The exact sum, at the larger scale of the two.
+    pub fn checked_add(self, other: Decimal) -> Option<Decimal> {
+        let scale = self.scale.max(other.scale);
+        let units = self.widen(scale)?.units.checked_add(other.widen(scale)?.units)?;
+        Some(Decimal::new(units, scale))
+    }
+
+    /// The exact product, at the sum of the scales.
+    pub fn checked_mul(self, other: Decimal) -> Option<Decimal> {
+        let units = self.units.checked_mul(other.units)?;
+        Some(Decimal::new(units, self.scale + other.scale))
+    }
+
+    /// `self` to the power `e`, rounded to `scale`. Intermediate results
+    /// keep `MAX_SCALE` digits.
+    pub fn checked_pow(self, mut e: u32, scale: u32, rounding: Rounding) -> Option<Decimal> {
+        let mut base = self;
+        let mut result = Decimal::from_int(1);
+        while e > 0 {
+            if e & 1 == 1 {
+                result = result.checked_mul(base)?.round(MAX_SCALE, rounding);
+            }
+            e >>= 1;
+            if e > 0 {
+                base = base.checked_mul(base)?.round(MAX_SCALE, rounding);
+            }
+        }
+        Some(result.round(scale, rounding))
+    }
+
+    pub fn checked_neg(self) -> Option<Decimal> {
+        Some(Decimal::new(self.units.checked_neg()?, self.scale))
+    }
+
+    pub fn checked_abs(self) -> Option<Decimal> {
+        Some(Decimal::new(self.units.checked_abs()?, self.scale))
+    }
+
+    /// Rounds to at most `scale` digits after the point.
+    pub fn round(self, scale: u32, rounding: Rounding) -> Decimal {
+        if self.scale <= scale {
+            return self;
+        }
+        let div = pow10(self.scale - scale);
+        let (q, r) = (self.units / div, (self.units % div).abs());
+        let away = match rounding {
+            Rounding::Truncate => false,
+            Rounding::HalfUp => r * 2 >= div,
+            Rounding::HalfEven => r * 2 > div || (r * 2 == div && q % 2 != 0),
+        };
+        let q = if away { q + self.units.signum() } else { q };
+        Decimal::new(q, scale)
+    }
+
+    /// The largest whole number not above `self`, at the same scale.
+    pub fn floor(self) -> Option<Decimal> {
+        let mut whole = self.round(0, Rounding::Truncate).units;
+        if whole * pow10(self.scale) > self.units {
+            whole -= 1;
+        }
+        Decimal::new(whole, 0).widen(self.scale)
+    }
+
+    /// The smallest whole number not below `self`, at the same scale.
+    pub fn ceil(self) -> Option<Decimal> {
+        self.checked_neg()?.floor()?.checked_neg()
+    }
+
+    /// The nearest whole number, halves away from zero, at the same scale.
+    pub fn round_whole(self) -> Option<Decimal> {
+        self.round(0, Rounding::HalfUp).widen(self.scale)
+    }
+}
+
+impl Ord for Decimal {
+    fn cmp(&self, other: &Decimal) -> Ordering {
+        let scale = self.scale.max(other.scale);
+        match (self.widen(scale), other.widen(scale)) {
+            (Some(a), Some(b)) => a.units.cmp(&b.units),
+            // Too big to widen: the scales are far apart, so the floats
+            // tell them apart.
+            _ => self.to_f64().partial_cmp(&other.to_f64()).unwrap_or(Ordering::Equal),
+        }
+    }
+}
+
+impl PartialOrd for Decimal {
+    fn partial_cmp(&self, other: &Decimal) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Decimal {
+    fn eq(&self, other: &Decimal) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Decimal {}
+
+impl fmt::Display for Decimal {
+    /// Formats the number with all the digits of its scale: `19.90`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let digits = format!("{:0>width$}", self.units.unsigned_abs(), width = self.scale as usize + 1);
+        let (whole, fraction) = digits.split_at(digits.len() - self.scale as usize);
+        let sign = if self.units < 0 { "-" } else { "" };
+        if fraction.is_empty() {
+            write!(f, "{}{}", sign, whole)
+        } else {
+            write!(f, "{}{}.{}", sign, whole, fraction)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn d(units: i128, scale: u32) -> Decimal {
+        Decimal::new(units, scale)
+    }
+
+    #[test]
+    fn test_add_mul() {
+        assert_eq!(d(1, 1).checked_add(d(2, 1)).unwrap(), d(3, 1));
+        assert_eq!(d(1999, 2).checked_add(d(1, 3)).unwrap().to_string(), "19.991");
+        assert_eq!(d(1999, 2).checked_mul(d(3, 0)).unwrap().to_string(), "59.97");
+        assert!(d(i128::MAX, 0).checked_add(d(1, 0)).is_none());
+        assert!(d(i128::MAX, 0).checked_mul(d(2, 0)).is_none());
+    }
+
+    #[test]
+    fn test_compare() {
+        assert_eq!(d(30, 2), d(3, 1));
+        assert!(d(-5, 1) < d(0, 0));
+        assert!(d(1999, 2) > d(19, 0));
+    }
+
+    #[test]
+    fn test_round() {
+        let cases = [
+            (125, Rounding::HalfEven, 12), (135, Rounding::HalfEven, 14),
+            (125, Rounding::HalfUp, 13), (-125, Rounding::HalfUp, -13),
+            (129, Rounding::Truncate, 12), (-129, Rounding::Truncate, -12),
+            (126, Rounding::HalfEven, 13),
+        ];
+        for &(units, rounding, rounded) in &cases {
+            assert_eq!(d(units, 3).round(2, rounding).units, rounded);
+        }
+        assert_eq!(d(5, 1).round(2, Rounding::Truncate).scale(), 1);
+    }
+
+    #[test]
+    fn test_whole() {
+        assert_eq!(d(-125, 2).floor().unwrap().to_string(), "-2.00");
+        assert_eq!(d(125, 2).floor().unwrap().to_string(), "1.00");
+        assert_eq!(d(-125, 2).ceil().unwrap().to_string(), "-1.00");
+        assert_eq!(d(250, 2).round_whole().unwrap().to_string(), "3.00");
+    }
+
+    #[test]
+    fn test_pow() {
+        let r = d(105, 2).checked_pow(2, 6, Rounding::HalfEven).unwrap();
+        assert_eq!(r.to_string(), "1.1025");
+        let r = d(105, 2).checked_pow(2, 2, Rounding::HalfEven).unwrap();
+        assert_eq!(r.to_string(), "1.10");
+        assert!(d(10, 0).checked_pow(100, 2, Rounding::HalfEven).is_none());
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(d(1999, 2).to_string(), "19.99");
+        assert_eq!(d(-5, 2).to_string(), "-0.05");
+        assert_eq!(d(7, 0).to_string(), "7");
+        assert_eq!(d(3, 1).to_f64(), 0.3);
+    }
+
+    #[test]
+    fn test_parse_rounding() {
+        assert_eq!(Rounding::parse("half-up").unwrap(), Rounding::HalfUp);
+        assert_eq!(Rounding::parse(Rounding::Truncate.name()).unwrap(), Rounding::Truncate);
+        assert!(Rounding::parse("bankers").is_err());
+    }
+}
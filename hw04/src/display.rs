@@ -0,0 +1,284 @@
+use rpn::{self, from_word, to_word, Elt, Mode, Stack};
+
+/// The names of the HP mode registers, from the top down.
+const REGISTERS: [&str; 4] = ["X", "Y", "Z", "T"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// How the REPL shows the stack after each line.
+pub enum Format {
+    /// The values bottom first, debug formatted: `[Int(1), Bool(true)]`.
+    Debug,
+    /// One value per line labelled with its level, as on an HP calculator,
+    /// or with its register name in HP mode.
+    Pretty,
+    /// Only the value on top of the stack.
+    Top,
+}
+
+impl Format {
+    /// Reads a format name: `debug`, `pretty` or `top`.
+    pub fn parse(name: &str) -> rpn::Result<Format> {
+        match name {
+            "debug" => Ok(Format::Debug),
+            "pretty" => Ok(Format::Pretty),
+            "top" => Ok(Format::Top),
+            _ => Err(rpn::Error::Syntax),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// The base integers are shown in.
+pub enum Base {
+    Dec,
+    Hex,
+    Oct,
+    Bin,
+}
+
+impl Base {
+    /// Reads a base name: `dec`, `hex`, `oct` or `bin`.
+    pub fn parse(name: &str) -> rpn::Result<Base> {
+        match name {
+            "dec" => Ok(Base::Dec),
+            "hex" => Ok(Base::Hex),
+            "oct" => Ok(Base::Oct),
+            "bin" => Ok(Base::Bin),
+            _ => Err(rpn::Error::Syntax),
+        }
+    }
+
+    /// Writes a number in this base, with the prefix `get_value` reads.
+    fn format(self, n: u64) -> String {
+        match self {
+            Base::Dec => n.to_string(),
+            Base::Hex => format!("{:#x}", n),
+            Base::Oct => format!("{:#o}", n),
+            Base::Bin => format!("{:#b}", n),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Display settings: the format, how many levels to show at most and how
+/// integers are written.
+pub struct Display {
+    pub format: Format,
+    /// Show only the top levels of the stack, `None` for all of them.
+    pub levels: Option<usize>,
+    pub base: Base,
+    /// Whether decimal integers and decimals are shown with thousands
+    /// separators: `1,234,567.89`.
+    pub separators: bool,
+    /// Whether integers are read as signed words of the stack's word
+    /// size, or as unsigned ones: with 8 bit words, -1 is `-0x1` signed
+    /// and `0xff` unsigned.
+    pub signed: bool,
+}
+
+impl Default for Display {
+    fn default() -> Display {
+        Display {
+            format: Format::Pretty,
+            levels: None,
+            base: Base::Dec,
+            separators: false,
+            signed: true,
+        }
+    }
+}
+
+impl Display {
+    /// Writes a value in the base of the display, as a word of `bits` bits.
+    /// Floats are written plainly, `0.5`, unless they are very large or
+    /// very small: `1e16`, `1e-5`.
+    pub fn value(&self, v: &Elt, bits: u32) -> String {
+        let i = match *v {
+            Elt::Int(i) => i,
+            Elt::Decimal(_) => return self.group(v.to_string()),
+            Elt::Float(x) => return format!("{:?}", x),
+            _ => return v.to_string(),
+        };
+        let word = to_word(i, bits);
+        let text = if self.signed {
+            let i = from_word(word, bits);
+            let digits = self.base.format(i.unsigned_abs());
+            if i < 0 {
+                format!("-{}", digits)
+            } else {
+                digits
+            }
+        } else {
+            self.base.format(word)
+        };
+        if self.base == Base::Dec {
+            self.group(text)
+        } else {
+            text
+        }
+    }
+
+    /// Adds the thousands separators to a decimal number, if shown.
+    fn group(&self, number: String) -> String {
+        if !self.separators {
+            return number;
+        }
+        let (sign, digits) = match number.strip_prefix('-') {
+            Some(rest) => ("-", rest),
+            None => ("", &number[..]),
+        };
+        let (whole, fraction) = match digits.find('.') {
+            Some(i) => digits.split_at(i),
+            None => (digits, ""),
+        };
+        let mut grouped = String::new();
+        for (i, c) in whole.chars().enumerate() {
+            if i > 0 && (whole.len() - i) % 3 == 0 {
+                grouped.push(',');
+            }
+            grouped.push(c);
+        }
+        format!("{}{}{}", sign, grouped, fraction)
+    }
+
+    /// Renders the stack without changing it. Debug output is always in
+    /// decimal.
+    pub fn render(&self, stack: &Stack) -> String {
+        let shown = match self.format {
+            Format::Top => 1,
+            _ => self.levels.unwrap_or_else(|| stack.depth()),
+        };
+        // Level 1 is the top of the stack.
+        let mut levels: Vec<_> = stack.iter().take(shown).collect();
+        levels.reverse();
+
+        match self.format {
+            Format::Debug => format!("{:?}", levels),
+            _ if levels.is_empty() => "(empty)".to_string(),
+            Format::Top => self.value(levels[0], stack.word_size()),
+            Format::Pretty => {
+                let width = levels.len().to_string().len();
+                let lines: Vec<String> = levels.iter().enumerate()
+                    .map(|(i, v)| {
+                        let level = levels.len() - i;
+                        let v = self.value(v, stack.word_size());
+                        match stack.mode() {
+                            Mode::Hp => format!("{}: {}", REGISTERS[level - 1], v),
+                            Mode::Unbounded => format!("{:>width$}: {}", level, v, width = width),
+                        }
+                    })
+                    .collect();
+                lines.join("\n")
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use decimal::Decimal;
+
+    fn stack(n: i64) -> Stack {
+        let mut s = Stack::new();
+        for i in 1..n + 1 {
+            s.push(Elt::Int(i)).unwrap();
+        }
+        s.push(Elt::Bool(true)).unwrap();
+        s
+    }
+
+    fn display(format: Format, levels: Option<usize>) -> Display {
+        Display { format, levels, ..Display::default() }
+    }
+
+    #[test]
+    fn test_debug() {
+        let s = stack(2);
+        assert_eq!(display(Format::Debug, None).render(&s), "[Int(1), Int(2), Bool(true)]");
+        assert_eq!(display(Format::Debug, Some(2)).render(&s), "[Int(2), Bool(true)]");
+        assert_eq!(display(Format::Debug, None).render(&Stack::new()), "[]");
+    }
+
+    #[test]
+    fn test_pretty() {
+        let s = stack(2);
+        assert_eq!(Display::default().render(&s), "3: 1\n2: 2\n1: true");
+        assert_eq!(display(Format::Pretty, Some(1)).render(&s), "1: true");
+        assert_eq!(display(Format::Pretty, Some(12)).render(&stack(10)).lines().next(),
+                   Some("11: 1"));
+        assert_eq!(Display::default().render(&Stack::new()), "(empty)");
+    }
+
+    #[test]
+    fn test_pretty_hp() {
+        let mut s = Stack::with_mode(Mode::Hp);
+        s.push(Elt::Int(7)).unwrap();
+        assert_eq!(Display::default().render(&s), "T: 0\nZ: 0\nY: 0\nX: 7");
+        assert_eq!(display(Format::Pretty, Some(2)).render(&s), "Y: 0\nX: 7");
+    }
+
+    #[test]
+    fn test_top() {
+        assert_eq!(display(Format::Top, Some(3)).render(&stack(2)), "true");
+        assert_eq!(display(Format::Top, None).render(&Stack::new()), "(empty)");
+    }
+
+    #[test]
+    fn test_base() {
+        let mut s = Stack::new();
+        s.push(Elt::Int(-1)).unwrap();
+        s.push(Elt::Int(255)).unwrap();
+        let mut d = display(Format::Pretty, None);
+        d.base = Base::Hex;
+        assert_eq!(d.render(&s), "2: -0x1\n1: 0xff");
+        d.signed = false;
+        assert_eq!(d.render(&s), "2: 0xffffffffffffffff\n1: 0xff");
+
+        s.set_word_size(8).unwrap();
+        assert_eq!(d.render(&s), "2: 0xff\n1: 0xff");
+        d.signed = true;
+        d.base = Base::Bin;
+        assert_eq!(d.render(&s), "2: -0b1\n1: -0b1");
+        d.base = Base::Oct;
+        d.format = Format::Debug;
+        assert_eq!(d.render(&s), "[Int(-1), Int(255)]");
+    }
+
+    #[test]
+    fn test_separators() {
+        let mut s = Stack::new();
+        s.push(Elt::Int(-1234567)).unwrap();
+        s.push(Elt::Decimal(Decimal::new(123456789, 2))).unwrap();
+        s.push(Elt::Int(999)).unwrap();
+        let mut d = display(Format::Pretty, None);
+        d.separators = true;
+        assert_eq!(d.render(&s), "3: -1,234,567\n2: 1,234,567.89\n1: 999");
+        d.base = Base::Hex;
+        assert_eq!(d.render(&s).lines().last(), Some("1: 0x3e7"));
+    }
+
+    #[test]
+    fn test_floats() {
+        let mut s = Stack::new();
+        for &x in &[0.5, -1.0, 1e16, 1e-5, 123456.789] {
+            s.push(Elt::Float(x)).unwrap();
+        }
+        assert_eq!(Display::default().render(&s), "5: 0.5\n4: -1.0\n3: 1e16\n2: 1e-5\n1: 123456.789");
+    }
+
+    #[test]
+    fn test_render_keeps_stack() {
+        let s = stack(2);
+        Display::default().render(&s);
+        assert_eq!(s.depth(), 3);
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Format::parse("top").unwrap(), Format::Top);
+        assert!(Format::parse("fancy").is_err());
+        assert_eq!(Base::parse("oct").unwrap(), Base::Oct);
+        assert!(Base::parse("ternary").is_err());
+    }
+}
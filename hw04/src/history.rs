@@ -0,0 +1,204 @@
+use std::collections::VecDeque;
+
+use rpn::{Snapshot, Stack};
+
+/// How many lines can be undone unless configured otherwise.
+pub const DEFAULT_DEPTH: usize = 100;
+
+/// Undo/redo history of the stack values, one entry per input line.
+///
+/// Entries are `Snapshot`s, which share their values with the stack, so
+/// a long history of a large stack only costs the values that changed.
+pub struct History {
+    undo: VecDeque<Snapshot>,
+    redo: Vec<Snapshot>,
+    depth: usize,
+}
+
+impl Default for History {
+    fn default() -> History {
+        History::new(DEFAULT_DEPTH)
+    }
+}
+
+impl History {
+    /// Creates a history remembering up to `depth` lines.
+    pub fn new(depth: usize) -> History {
+        History {
+            undo: VecDeque::new(),
+            redo: Vec::new(),
+            depth,
+        }
+    }
+
+    /// How many lines can be undone at most.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Changes how many lines are remembered, forgetting the oldest ones
+    /// and the undone ones furthest from the current values.
+    pub fn set_depth(&mut self, depth: usize) {
+        self.depth = depth;
+        while self.undo.len() > depth {
+            self.undo.pop_front();
+        }
+        let excess = self.redo.len().saturating_sub(depth);
+        self.redo.drain(..excess);
+    }
+
+    /// Records the values a line started from, if the line changed them.
+    /// A new change makes what was undone before it unreachable.
+    pub fn record(&mut self, before: Snapshot, stack: &Stack) {
+        if stack.is_at(&before) || self.depth == 0 {
+            return;
+        }
+        self.redo.clear();
+        self.undo.push_back(before);
+        if self.undo.len() > self.depth {
+            self.undo.pop_front();
+        }
+    }
+
+    /// Goes back to the values before the last line. Returns `false` when
+    /// there is nothing left to undo.
+    pub fn undo(&mut self, stack: &mut Stack) -> bool {
+        match self.undo.pop_back() {
+            Some(snapshot) => {
+                self.redo.push(stack.snapshot());
+                if self.redo.len() > self.depth {
+                    self.redo.remove(0);
+                }
+                stack.restore(snapshot);
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Reapplies the last undone line. Returns `false` when there is
+    /// nothing to redo.
+    pub fn redo(&mut self, stack: &mut Stack) -> bool {
+        match self.redo.pop() {
+            Some(snapshot) => {
+                self.undo.push_back(stack.snapshot());
+                if self.undo.len() > self.depth {
+                    self.undo.pop_front();
+                }
+                stack.restore(snapshot);
+                true
+            },
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rpn::{Elt, Op};
+
+    fn line<F: FnOnce(&mut Stack)>(history: &mut History, stack: &mut Stack, f: F) {
+        let before = stack.snapshot();
+        f(stack);
+        history.record(before, stack);
+    }
+
+    fn top(stack: &Stack) -> Option<Elt> {
+        stack.iter().next().cloned()
+    }
+
+    #[test]
+    fn test_undo_redo() {
+        let mut h = History::default();
+        let mut s = Stack::new();
+        line(&mut h, &mut s, |s| s.push(Elt::Int(1)).unwrap());
+        line(&mut h, &mut s, |s| s.push(Elt::Int(2)).unwrap());
+        line(&mut h, &mut s, |s| s.eval(Op::Add).unwrap());
+        assert_eq!(top(&s), Some(Elt::Int(3)));
+
+        assert!(h.undo(&mut s));
+        assert_eq!(s.depth(), 2);
+        assert_eq!(top(&s), Some(Elt::Int(2)));
+        assert!(h.undo(&mut s));
+        assert!(h.undo(&mut s));
+        assert_eq!(s.depth(), 0);
+        assert!(!h.undo(&mut s));
+
+        assert!(h.redo(&mut s));
+        assert!(h.redo(&mut s));
+        assert!(h.redo(&mut s));
+        assert_eq!(top(&s), Some(Elt::Int(3)));
+        assert!(!h.redo(&mut s));
+    }
+
+    #[test]
+    fn test_new_line_clears_redo() {
+        let mut h = History::default();
+        let mut s = Stack::new();
+        line(&mut h, &mut s, |s| s.push(Elt::Int(1)).unwrap());
+        assert!(h.undo(&mut s));
+        line(&mut h, &mut s, |s| s.push(Elt::Int(5)).unwrap());
+        assert!(!h.redo(&mut s));
+        assert_eq!(top(&s), Some(Elt::Int(5)));
+    }
+
+    #[test]
+    fn test_unchanged_line_not_recorded() {
+        let mut h = History::default();
+        let mut s = Stack::new();
+        line(&mut h, &mut s, |s| s.push(Elt::Int(1)).unwrap());
+        line(&mut h, &mut s, |_| ());
+        assert!(h.undo(&mut s));
+        assert_eq!(s.depth(), 0);
+    }
+
+    #[test]
+    fn test_undo_failed_line() {
+        let mut h = History::default();
+        let mut s = Stack::new();
+        line(&mut h, &mut s, |s| s.push(Elt::Int(1)).unwrap());
+        line(&mut h, &mut s, |s| assert!(s.eval(Op::Swap).is_err()));
+        assert_eq!(s.depth(), 0);
+        assert!(h.undo(&mut s));
+        assert_eq!(top(&s), Some(Elt::Int(1)));
+    }
+
+    #[test]
+    fn test_depth() {
+        let mut h = History::new(2);
+        let mut s = Stack::new();
+        for i in 0..5 {
+            line(&mut h, &mut s, |s| s.push(Elt::Int(i)).unwrap());
+        }
+        assert!(h.undo(&mut s));
+        assert!(h.undo(&mut s));
+        assert!(!h.undo(&mut s));
+        assert_eq!(s.depth(), 3);
+
+        h.set_depth(0);
+        line(&mut h, &mut s, |s| s.push(Elt::Int(9)).unwrap());
+        assert!(!h.undo(&mut s));
+        assert!(!h.redo(&mut s));
+    }
+
+    #[test]
+    fn test_redo_depth() {
+        let mut h = History::new(3);
+        let mut s = Stack::new();
+        for i in 0..3 {
+            line(&mut h, &mut s, |s| s.push(Elt::Int(i)).unwrap());
+        }
+        while h.undo(&mut s) {}
+
+        // Only the two lines nearest the current values can be redone.
+        h.set_depth(2);
+        assert!(h.redo(&mut s));
+        assert!(h.redo(&mut s));
+        assert!(!h.redo(&mut s));
+        assert_eq!(s.depth(), 2);
+        assert!(h.undo(&mut s));
+        assert!(h.undo(&mut s));
+        assert!(!h.undo(&mut s));
+    }
+}
@@ -0,0 +1,30 @@
+extern crate hw01;
+extern crate rand;
+#[macro_use]
+extern crate serde_json;
+#[cfg(feature = "tui")]
+extern crate termion;
+pub mod bench;
+pub mod bytecode;
+pub mod commands;
+pub mod complex;
+pub mod console;
+pub mod config;
+pub mod csv;
+pub mod debugger;
+pub mod decimal;
+pub mod display;
+pub mod history;
+pub mod matrix;
+pub mod optimizer;
+pub mod output;
+pub mod parser;
+pub mod plot;
+pub mod primes;
+pub mod rpn;
+pub mod server;
+pub mod stdlib;
+pub mod session;
+#[cfg(feature = "tui")]
+pub mod tui;
+pub mod units;
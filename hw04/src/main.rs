@@ -1,11 +1,108 @@
-extern crate rand;
-pub mod parser;
-pub mod rpn;
+extern crate hw04;
 
-use parser::read_eval_print_loop;
+use std::env;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::PathBuf;
+
+use hw04::{bench, config, csv, output};
+use hw04::parser::{read_eval_print_loop, Repl};
+use hw04::rpn::Limits;
+use hw04::server::Server;
+#[cfg(feature = "tui")]
+use hw04::tui::Tui;
 
 fn main() {
-    if let Err(err) = read_eval_print_loop() {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    let result = match args.first().map(|a| a.as_str()) {
+        Some("--bench") => {
+            let iterations = args.get(1).and_then(|n| n.parse().ok()).unwrap_or(1000);
+            bench::run_benchmark(iterations)
+        },
+        Some("--replay") => match args.get(1) {
+            Some(path) => Repl::new().replay(path),
+            None => Err(hw04::rpn::Error::Syntax),
+        },
+        Some("--serve") => {
+            // `--public` may come before or after the address.
+            let public = args.iter().any(|a| a == "--public");
+            let address = args.iter().skip(1).find(|a| *a != "--public");
+            let address = address.map(|a| a.as_str()).unwrap_or("-");
+            Server::new(Limits::sandbox()).listen(address, public)
+        },
+        Some("--format") => match args.get(1).map(|f| output::Format::parse(f)) {
+            Some(Ok(format)) => {
+                let final_only = args.get(2).map(|a| a.as_str()) == Some("--final");
+                let stdin = io::stdin();
+                output::run_batch(format, final_only, stdin.lock(), io::stdout())
+            },
+            _ => Err(hw04::rpn::Error::Syntax),
+        },
+        Some("--map") => match args.get(1) {
+            Some(program) => with_input(args.get(2), |input| {
+                csv::map(program, input, io::stdout(), io::stderr())
+            }),
+            None => Err(hw04::rpn::Error::Syntax),
+        },
+        Some("--reduce") => match (args.get(1).map(|a| csv::Aggregate::parse(a)), args.get(2)) {
+            (Some(Ok(aggregate)), Some(program)) => with_input(args.get(3), |input| {
+                let result = csv::reduce(aggregate, program, input, io::stderr())?;
+                println!("{}", result.map(|v| v.to_string()).unwrap_or_default());
+                Ok(())
+            }),
+            _ => Err(hw04::rpn::Error::Syntax),
+        },
+        #[cfg(feature = "tui")]
+        Some("--tui") => full_screen(config::config_dir()),
+        Some("--config") => match args.get(1) {
+            Some(dir) => interactive(Some(PathBuf::from(dir))),
+            None => Err(hw04::rpn::Error::Syntax),
+        },
+        _ => interactive(config::config_dir()),
+    };
+
+    if let Err(err) = result {
         println!("Error: {:?}", err);
     }
 }
+
+/// Runs the REPL, set up from the startup files in `dir`.
+fn interactive(dir: Option<PathBuf>) -> hw04::rpn::Result<()> {
+    let mut repl = Repl::new();
+    if let Some(dir) = dir {
+        for p in config::startup(&mut repl, &dir) {
+            println!("Error: {}:{}: {:?}", p.path.display(), p.line, p.error);
+        }
+    }
+    read_eval_print_loop(&mut repl)
+}
+
+/// Runs the full-screen REPL, set up from the startup files in `dir`.
+#[cfg(feature = "tui")]
+fn full_screen(dir: Option<PathBuf>) -> hw04::rpn::Result<()> {
+    let mut tui = Tui::new(Repl::new());
+    if let Some(dir) = dir {
+        for p in config::startup(&mut tui.repl, &dir) {
+            tui.report(&format!("Error: {}:{}: {:?}", p.path.display(), p.line, p.error));
+        }
+    }
+    tui.run()
+}
+
+/// Calls `f` on the file at `path`, or on stdin without a path.
+fn with_input<F>(path: Option<&String>, f: F) -> hw04::rpn::Result<()>
+    where F: FnOnce(&mut dyn BufRead) -> hw04::rpn::Result<()>
+{
+    match path {
+        Some(path) => {
+            let file = File::open(path).map_err(hw04::rpn::Error::file(path))?;
+            f(&mut BufReader::new(file))
+        },
+        None => {
+            let stdin = io::stdin();
+            let mut lock = stdin.lock();
+            f(&mut lock)
+        },
+    }
+}
@@ -0,0 +1,195 @@
+use std::fmt;
+
+use hw01::problem2;
+
+/// The most rows and columns of a matrix `identity` builds.
+pub const MAX_SIZE: usize = 256;
+
+#[derive(Debug, Clone, PartialEq, PartialOrd)]
+/// A matrix of floats in row-major order, with at least one row and one
+/// column and all rows of the same length.
+///
+/// Operations return `None` when the sizes of the matrices do not allow
+/// them, instead of panicking like `problem2::mat_mult`.
+pub struct Matrix {
+    rows: problem2::Matrix,
+}
+
+impl Matrix {
+    /// A matrix of the given rows, `None` if there are none or they are
+    /// not all of the same, non-zero length.
+    pub fn new(rows: problem2::Matrix) -> Option<Matrix> {
+        let width = rows.first()?.len();
+        if width == 0 || rows.iter().any(|row| row.len() != width) {
+            return None;
+        }
+        Some(Matrix { rows })
+    }
+
+    /// The identity matrix of size `n`, `None` unless `n` is from 1 to
+    /// `MAX_SIZE`.
+    pub fn identity(n: usize) -> Option<Matrix> {
+        if n == 0 || n > MAX_SIZE {
+            return None;
+        }
+        let rows = (0..n)
+            .map(|r| (0..n).map(|c| if r == c { 1.0 } else { 0.0 }).collect())
+            .collect();
+        Some(Matrix { rows })
+    }
+
+    /// The rows of the matrix.
+    pub fn rows(&self) -> &problem2::Matrix {
+        &self.rows
+    }
+
+    pub fn height(&self) -> usize {
+        self.rows.len()
+    }
+
+    pub fn width(&self) -> usize {
+        self.rows[0].len()
+    }
+
+    /// Whether every entry is finite.
+    pub fn is_finite(&self) -> bool {
+        self.rows.iter().flatten().all(|x| x.is_finite())
+    }
+
+    /// Whether some entry is NaN.
+    pub fn has_nan(&self) -> bool {
+        self.rows.iter().flatten().any(|x| x.is_nan())
+    }
+
+    /// Applies `f` to every entry.
+    pub fn map<F: Fn(f32) -> f32>(&self, f: F) -> Matrix {
+        let rows = self.rows.iter()
+            .map(|row| row.iter().map(|&x| f(x)).collect())
+            .collect();
+        Matrix { rows }
+    }
+
+    /// The sum, of two matrices of the same size.
+    pub fn checked_add(&self, other: &Matrix) -> Option<Matrix> {
+        if self.height() != other.height() || self.width() != other.width() {
+            return None;
+        }
+        let rows = self.rows.iter().zip(&other.rows)
+            .map(|(a, b)| a.iter().zip(b).map(|(x, y)| x + y).collect())
+            .collect();
+        Some(Matrix { rows })
+    }
+
+    /// The product, if `self` has as many columns as `other` has rows.
+    pub fn checked_mul(&self, other: &Matrix) -> Option<Matrix> {
+        if self.width() != other.height() {
+            return None;
+        }
+        Some(Matrix { rows: problem2::mat_mult(&self.rows, &other.rows) })
+    }
+
+    pub fn transpose(&self) -> Matrix {
+        let rows = (0..self.width())
+            .map(|c| self.rows.iter().map(|row| row[c]).collect())
+            .collect();
+        Matrix { rows }
+    }
+
+    /// The determinant of a square matrix, by Gaussian elimination.
+    pub fn det(&self) -> Option<f64> {
+        let n = self.height();
+        if self.width() != n {
+            return None;
+        }
+        let mut m: Vec<Vec<f64>> = self.rows.iter()
+            .map(|row| row.iter().map(|&x| f64::from(x)).collect())
+            .collect();
+        let mut det = 1.0;
+        for c in 0..n {
+            // The largest pivot loses the least precision.
+            let pivot = (c..n)
+                .max_by(|&a, &b| m[a][c].abs().total_cmp(&m[b][c].abs()))
+                .unwrap_or(c);
+            if m[pivot][c] == 0.0 {
+                return Some(0.0);
+            }
+            if pivot != c {
+                m.swap(pivot, c);
+                det = -det;
+            }
+            det *= m[c][c];
+            let (top, below) = m.split_at_mut(c + 1);
+            let pivot_row = &top[c];
+            for row in below {
+                let k = row[c] / pivot_row[c];
+                for (x, p) in row.iter_mut().zip(pivot_row).skip(c) {
+                    *x -= k * p;
+                }
+            }
+        }
+        Some(det)
+    }
+}
+
+impl fmt::Display for Matrix {
+    /// Formats the matrix as it is written in the input: `[[1 2][3 4]]`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[")?;
+        for row in &self.rows {
+            let entries: Vec<String> = row.iter().map(|x| x.to_string()).collect();
+            write!(f, "[{}]", entries.join(" "))?;
+        }
+        write!(f, "]")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn m(rows: &[&[f32]]) -> Matrix {
+        Matrix::new(rows.iter().map(|r| r.to_vec()).collect()).unwrap()
+    }
+
+    #[test]
+    fn test_new() {
+        assert!(Matrix::new(vec![]).is_none());
+        assert!(Matrix::new(vec![vec![]]).is_none());
+        assert!(Matrix::new(vec![vec![1.0, 2.0], vec![3.0]]).is_none());
+        assert!(Matrix::identity(0).is_none());
+        assert!(Matrix::identity(MAX_SIZE + 1).is_none());
+        assert_eq!(Matrix::identity(2).unwrap(), m(&[&[1.0, 0.0], &[0.0, 1.0]]));
+    }
+
+    #[test]
+    fn test_mul() {
+        let a = m(&[&[1.0, 2.0], &[3.0, 4.0]]);
+        let b = m(&[&[5.0], &[6.0]]);
+        assert_eq!(a.checked_mul(&b).unwrap(), m(&[&[17.0], &[39.0]]));
+        assert!(b.checked_mul(&a).is_none());
+        assert_eq!(a.checked_mul(&Matrix::identity(2).unwrap()).unwrap(), a);
+    }
+
+    #[test]
+    fn test_add_transpose() {
+        let a = m(&[&[1.0, 2.0, 3.0]]);
+        assert_eq!(a.checked_add(&a).unwrap(), a.map(|x| x * 2.0));
+        assert!(a.checked_add(&a.transpose()).is_none());
+        assert_eq!(a.transpose(), m(&[&[1.0], &[2.0], &[3.0]]));
+        assert_eq!(a.transpose().transpose(), a);
+    }
+
+    #[test]
+    fn test_det() {
+        assert_eq!(m(&[&[1.0, 2.0], &[3.0, 4.0]]).det(), Some(-2.0));
+        assert_eq!(m(&[&[0.0, 1.0], &[1.0, 0.0]]).det(), Some(-1.0));
+        assert_eq!(m(&[&[1.0, 2.0], &[2.0, 4.0]]).det(), Some(0.0));
+        assert_eq!(Matrix::identity(5).unwrap().det(), Some(1.0));
+        assert_eq!(m(&[&[1.0, 2.0]]).det(), None);
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(m(&[&[1.0, 2.5], &[-3.0, 4.0]]).to_string(), "[[1 2.5][-3 4]]");
+    }
+}
@@ -0,0 +1,465 @@
+use std::collections::HashMap;
+
+use bytecode::{Dictionary, Instr, Program};
+use rpn::{Elt, Op, Stack};
+
+/// Words with at most this many instructions, once the words they call
+/// are inlined too, are inlined at call sites.
+pub const INLINE_LIMIT: usize = 8;
+
+/// How many calls deep inlining goes. Past that calls are left as they
+/// are, so a long chain of words cannot exhaust the call stack.
+pub const INLINE_DEPTH: usize = 16;
+
+/// The number of values an operation pops and pushes when it succeeds.
+fn stack_effect(op: Op) -> (usize, usize) {
+    match op {
+        Op::Add | Op::Mul | Op::Eq => (2, 1),
+        Op::And | Op::Or | Op::Xor | Op::Shl | Op::Shr | Op::Rol | Op::Ror => (2, 1),
+        Op::Pow => (2, 1),
+        Op::Neg | Op::Not | Op::Rand => (1, 1),
+        Op::Sqrt | Op::Exp | Op::Ln | Op::Log10 | Op::Log2 |
+        Op::Sin | Op::Cos | Op::Tan | Op::Asin | Op::Acos | Op::Atan |
+        Op::Sinh | Op::Cosh | Op::Tanh | Op::Asinh | Op::Acosh | Op::Atanh |
+        Op::Floor | Op::Ceil | Op::Round | Op::Conj | Op::Abs | Op::Arg => (1, 1),
+        Op::Transpose | Op::Det | Op::Identity => (1, 1),
+        // At least: `primes` and `factor` may push nothing.
+        Op::Primes | Op::Factor => (1, 0),
+        Op::IsPrime | Op::Totient => (1, 1),
+        Op::Gcd | Op::Lcm | Op::ModInv => (2, 1),
+        Op::ModPow => (3, 1),
+        Op::Polar => (1, 2),
+        Op::Rect => (2, 1),
+        Op::Pi | Op::E => (0, 1),
+        Op::Swap => (2, 2),
+        Op::Enter => (1, 2),
+        Op::LastX => (0, 1),
+        Op::Throw => (1, 0),
+        Op::Dot | Op::Emit | Op::Print | Op::Plot | Op::Table => (1, 0),
+        Op::ReadLine | Op::ReadNumber => (0, 1),
+        Op::ReadFile => (1, 1),
+        Op::WriteFile | Op::AppendFile => (2, 0),
+        Op::Quit => (0, 0),
+    }
+}
+
+/// Whether an operation only depends on its operands (and the settings
+/// `Stack::scratch` copies), so it can be evaluated ahead of time.
+fn is_pure(op: Op) -> bool {
+    !matches!(op, Op::Rand | Op::LastX | Op::Throw | Op::Quit) && !op.is_io()
+}
+
+/// Whether applying the operation twice in a row gives back the operands,
+/// `bool` telling whether the value on top is known to be a boolean.
+/// `<->` always is, so a pair of them only fails when the stack is too
+/// short. `not` is only on booleans: it fails on most types, and on an
+/// integer wider than the word size it runs with it loses the high bits.
+/// So is `~`, which is `not` on booleans but overflows negating the lowest
+/// integer.
+fn is_involution(op: Op, bool: bool) -> bool {
+    match op {
+        Op::Swap => true,
+        Op::Not | Op::Neg => bool,
+        _ => false,
+    }
+}
+
+/// Whether the operation always leaves a boolean on top. `bool` tells
+/// whether its operand on top is known to be one.
+fn gives_bool(op: Op, bool: bool) -> bool {
+    match op {
+        Op::Eq | Op::IsPrime => true,
+        Op::Not | Op::Neg => bool,
+        _ => false,
+    }
+}
+
+/// Optimizes a compiled program.
+///
+/// Small user words are inlined, constant subexpressions are folded and
+/// pairs of operations that cancel out are removed. The result runs to the
+/// same stack, and fails with the same error, as the original program, as
+/// long as it runs on an unbounded stack: in HP mode folding would change
+/// what ends up in the T register. Folding evaluates like `stack`, see
+/// `Stack::scratch`. Under a depth, step or time limit nothing is folded:
+/// the work would be done outside of the limits, before the program runs,
+/// and folded values would not count against the depth.
+///
+/// Programs with `try` or `sample` blocks are left as they are, since the
+/// blocks jump to indices of the code, and so are the words holding them.
+pub fn optimize(program: &Program, stack: &Stack) -> Program {
+    if has_jumps(program) {
+        return program.clone();
+    }
+    let inlined = Inliner { words: stack.words(), expanded: HashMap::new() }.inline(program, None, 0);
+    let limits = stack.limits();
+    let folded = if limits.depth.is_some() || limits.steps.is_some() || limits.time.is_some() {
+        inlined
+    } else {
+        fold(&inlined, stack)
+    };
+    cancel_pairs(&folded)
+}
+
+fn has_jumps(program: &Program) -> bool {
+    program.code.iter().any(|instr| matches!(instr, Instr::Try(_) | Instr::EndTry(_) | Instr::Sample(_)))
+}
+
+/// Replaces calls to small words by their body.
+struct Inliner<'a> {
+    words: &'a Dictionary,
+    /// The inlined body of each word met so far, `None` for words too big
+    /// to inline.
+    expanded: HashMap<usize, Option<Program>>,
+}
+
+impl<'a> Inliner<'a> {
+    /// Inlines the calls of `program`, `depth` calls deep. Inlined
+    /// instructions take the position of the call (`at`) when inlining
+    /// into a word.
+    fn inline(&mut self, program: &Program, at: Option<usize>, depth: usize) -> Program {
+        let mut out = Program::default();
+        for (instr, &pos) in program.code.iter().zip(&program.positions) {
+            let pos = at.unwrap_or(pos);
+            let body = match *instr {
+                Instr::Call(addr) if depth < INLINE_DEPTH => self.expand(addr, depth),
+                _ => None,
+            };
+            match body {
+                Some(body) => {
+                    for instr in body.code {
+                        out.push(instr, pos);
+                    }
+                },
+                None => out.push(instr.clone(), pos),
+            }
+        }
+        out
+    }
+
+    /// The body of the word at `addr` with its own calls inlined, if it
+    /// is small enough.
+    fn expand(&mut self, addr: usize, depth: usize) -> Option<Program> {
+        if let Some(body) = self.expanded.get(&addr) {
+            return body.clone();
+        }
+        // Words may only call words defined before them, so this ends.
+        let words = self.words;
+        let body = Some(self.inline(words.body(addr), None, depth + 1))
+            .filter(|body| body.code.len() <= INLINE_LIMIT && !has_jumps(body));
+        self.expanded.insert(addr, body.clone());
+        body
+    }
+}
+
+/// Replaces pure operations on constants by their result, evaluated like
+/// on `stack`. An operation that would fail, or that `stack` does not
+/// allow, is left in place so it still fails at run time.
+fn fold(program: &Program, stack: &Stack) -> Program {
+    let mut out = Program::default();
+    for (instr, &pos) in program.code.iter().zip(&program.positions) {
+        out.push(instr.clone(), pos);
+
+        let op = match *instr {
+            Instr::Op(op) if is_pure(op) => op,
+            _ => continue,
+        };
+        let pops = stack_effect(op).0;
+        if out.code.len() <= pops {
+            continue;
+        }
+
+        let start = out.code.len() - 1 - pops;
+        let mut scratch = stack.scratch();
+        let pushed = out.code[start..out.code.len() - 1].iter().all(|operand| match *operand {
+            Instr::Push(ref v) => scratch.push(v.clone()).is_ok(),
+            _ => true,
+        });
+        if !pushed || scratch.depth() != pops || scratch.eval(op).is_err() {
+            continue;
+        }
+
+        // The results replace the operands; folding the next operation
+        // picks them up as constants again.
+        let mut results = Vec::new();
+        while let Ok(v) = scratch.pop() {
+            results.push(v);
+        }
+        out.code.truncate(start);
+        out.positions.truncate(start);
+        for v in results.into_iter().rev() {
+            out.push(Instr::Push(v), pos);
+        }
+    }
+    out
+}
+
+/// Removes pairs such as `not not` and `<-> <->`, but only where the stack is
+/// known to hold enough values, of a type the pair gives back: otherwise
+/// the pair would fail.
+fn cancel_pairs(program: &Program) -> Program {
+    let mut out = Program::default();
+    // The least number of values on the stack before each instruction of
+    // `out`; what was there before the program started is unknown.
+    let mut depths: Vec<usize> = Vec::new();
+    let mut depth = 0;
+    // Whether the value on top before each instruction of `out` is known
+    // to be a boolean.
+    let mut bools: Vec<bool> = Vec::new();
+    let mut bool = false;
+
+    for (instr, &pos) in program.code.iter().zip(&program.positions) {
+        if let Instr::Op(op) = *instr {
+            let prev = depths.last().cloned().zip(bools.last().cloned());
+            if out.code.last() == Some(instr) {
+                if let Some((before, was_bool)) = prev {
+                    if before >= stack_effect(op).0 && is_involution(op, was_bool) {
+                        out.code.pop();
+                        out.positions.pop();
+                        depths.pop();
+                        bools.pop();
+                        depth = before;
+                        bool = was_bool;
+                        continue;
+                    }
+                }
+            }
+        }
+
+        depths.push(depth);
+        bools.push(bool);
+        out.push(instr.clone(), pos);
+        bool = match *instr {
+            Instr::Push(ref v) => matches!(*v, Elt::Bool(_)),
+            Instr::Op(op) => gives_bool(op, bool),
+            _ => false,
+        };
+        depth = match *instr {
+            Instr::Push(_) | Instr::Load(_) => depth + 1,
+            Instr::Store(_) => depth.max(1) - 1,
+            Instr::Unit(_) | Instr::Convert(_) => depth.max(1),
+            Instr::Op(op) => {
+                let (pops, pushes) = stack_effect(op);
+                depth.max(pops) - pops + pushes
+            },
+            // What a word leaves on the stack is not tracked, nor where
+            // blocks jump.
+            Instr::Call(_) | Instr::Try(_) | Instr::EndTry(_)
+                | Instr::Sample(_) | Instr::EndSample => 0,
+        };
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytecode::{compile, run, to_source};
+    use parser::OPERATIONS;
+    use rpn::{Error, Limits};
+    use std::time::Duration;
+
+    fn optimized(s: &str) -> String {
+        let mut stack = Stack::new();
+        let p = compile(s, stack.words_mut()).unwrap();
+        to_source(&optimize(&p, &stack), stack.words())
+    }
+
+    #[test]
+    fn test_stack_effects_match_help() {
+        // Values ending in `...` may be none, the effect counts the least.
+        let count = |values: &str| values.split_whitespace().filter(|v| !v.ends_with("...")).count();
+        for info in OPERATIONS {
+            let mut sides = info.effect.split("--");
+            let pops = count(sides.next().unwrap());
+            let pushes = count(sides.next().unwrap());
+            assert_eq!(stack_effect(info.op), (pops, pushes), "{}", info.token);
+        }
+    }
+
+    #[test]
+    fn test_fold_constants() {
+        assert_eq!(optimized("2 3 +"), "5");
+        assert_eq!(optimized("1 2 3 + + ~"), "-6");
+        assert_eq!(optimized("4 4 = ~"), "false");
+        assert_eq!(optimized("1 true <->"), "true 1");
+    }
+
+    #[test]
+    fn test_fold_keeps_impure_ops() {
+        assert_eq!(optimized("10 #"), "10 #");
+        assert_eq!(optimized("2 3 + 10 # +"), "5 10 # +");
+    }
+
+    #[test]
+    fn test_fold_keeps_errors() {
+        assert_eq!(optimized("1 true +"), "1 true +");
+        assert_eq!(optimized("3 +"), "3 +");
+
+        let mut s = Stack::new();
+        let p = compile("2 3 + true +", s.words_mut()).unwrap();
+        let p = optimize(&p, &s);
+        let res = run(&p, &mut s);
+        if let Err(Error::Type) = res { } else { panic!(); }
+    }
+
+    #[test]
+    fn test_cancel_pairs() {
+        assert_eq!(optimized("10 # 1 = not not"), "10 # 1 =");
+        assert_eq!(optimized("1 10 # <-> <->"), "1 10 #");
+        assert_eq!(optimized("1 10 # 2 = not <-> <-> not"), "1 10 # 2 =");
+        // `not` is only undone on booleans.
+        assert_eq!(optimized("10 # not not"), "10 # not not");
+        assert_eq!(optimized("10 # 1 = <-> not not"), "10 # 1 = <-> not not");
+        // Negating twice is only a no-op on booleans.
+        assert_eq!(optimized("10 # 1 = ~ ~"), "10 # 1 =");
+        assert_eq!(optimized("10 # 1 = ~ not not ~"), "10 # 1 =");
+        assert_eq!(optimized("10 # ~ ~"), "10 # ~ ~");
+    }
+
+    #[test]
+    fn test_cancel_pairs_keeps_underflow() {
+        assert_eq!(optimized("not not"), "not not");
+        assert_eq!(optimized("10 # <-> <->"), "10 # <-> <->");
+
+        let mut s = Stack::new();
+        let p = compile("not not", s.words_mut()).unwrap();
+        let res = run(&optimize(&p, &s), &mut s);
+        if let Err(Error::Underflow) = res { } else { panic!(); }
+    }
+
+    #[test]
+    fn test_cancel_pairs_keeps_type_errors() {
+        assert_eq!(optimized("1e1 not not"), "1e1 not not");
+
+        for src in &["1e1 not not", "\"a\" !x x not not"] {
+            let mut s = Stack::new();
+            let p = compile(src, s.words_mut()).unwrap();
+            let res = run(&optimize(&p, &s), &mut s);
+            if let Err(Error::Type) = res { } else { panic!("{}", src); }
+        }
+
+        // An integer past the word size loses its high bits.
+        let mut s = Stack::new();
+        s.set_word_size(8).unwrap();
+        let p = compile("300 !x x not not", s.words_mut()).unwrap();
+        run(&optimize(&p, &s), &mut s).unwrap();
+        assert_eq!(s.pop().unwrap(), Elt::Int(44));
+    }
+
+    #[test]
+    fn test_inline_words() {
+        assert_eq!(optimized(": inc 1 + ; 2 inc inc"), "4");
+        assert_eq!(optimized(": inc 1 + ; : inc2 inc inc ; 10 # inc2"), "10 # 1 + 1 +");
+    }
+
+    #[test]
+    fn test_positions() {
+        let mut s = Stack::new();
+        let p = compile(": inc 1 + ; 10 # inc 2 3 +", s.words_mut()).unwrap();
+        let p = optimize(&p, &s);
+        assert_eq!(to_source(&p, s.words()), "10 # 1 + 5");
+        assert_eq!(p.positions, vec![5, 6, 7, 7, 10]);
+    }
+
+    #[test]
+    fn test_inline_limit() {
+        let big = ": big 1 + 1 + 1 + 1 + 1 + ; 10 # big";
+        assert_eq!(optimized(big), "10 # big");
+        // Small words calling small words only inline while the result
+        // stays small.
+        let nested = ": a 10 # ; : b a a a ; : c b b b ; b c";
+        assert_eq!(optimized(nested), "10 # 10 # 10 # c");
+    }
+
+    #[test]
+    fn test_try_blocks_are_kept() {
+        assert_eq!(optimized("2 3 + try [ 1 true + ] catch [ ]"), "2 3 + try [ 1 true + ] catch [ ]");
+        assert_eq!(optimized(": safe try [ 1 ] catch [ ] ; : inc 1 + ; safe 2 inc"), "safe 3");
+    }
+
+    #[test]
+    fn test_inline_depth() {
+        let mut s = Stack::new();
+        let mut src = ": w0 10 # ;".to_string();
+        for i in 1..10_000 {
+            src.push_str(&format!(" : w{} w{} ;", i, i - 1));
+        }
+        src.push_str(" w9999");
+        let p = compile(&src, s.words_mut()).unwrap();
+        let p = optimize(&p, &s);
+        assert_eq!(p.code.len(), 1);
+        assert!(matches!(p.code[0], Instr::Call(_)));
+    }
+
+    #[test]
+    fn test_fold_keeps_denied_ops() {
+        let mut s = Stack::new();
+        s.set_limits(Limits { ops: Some(vec![Op::Neg]), ..Limits::default() });
+        let p = compile("2 3 + ~ 4 ~", s.words_mut()).unwrap();
+        assert_eq!(to_source(&optimize(&p, &s), s.words()), "2 3 + ~ -4");
+    }
+
+    #[test]
+    fn test_fold_keeps_limits() {
+        for limits in &[Limits::sandbox(), Limits { steps: Some(100), ..Limits::default() },
+                        Limits { time: Some(Duration::from_secs(1)), ..Limits::default() }] {
+            let mut s = Stack::new();
+            s.set_limits(limits.clone());
+            let p = compile(": sq enter * ; 600851475143 factor 3 sq", s.words_mut()).unwrap();
+            assert_eq!(to_source(&optimize(&p, &s), s.words()), "600851475143 factor 3 enter *");
+        }
+
+        let mut s = Stack::new();
+        s.set_limits(Limits { depth: Some(1), ..Limits::default() });
+        let p = compile("1 2 +", s.words_mut()).unwrap();
+        let p = optimize(&p, &s);
+        assert_eq!(to_source(&p, s.words()), "1 2 +");
+        let res = run(&p, &mut s);
+        if let Err(Error::DepthLimit) = res { } else { panic!(); }
+
+        // The scratch stack has the other limits too.
+        let mut s = Stack::new();
+        s.set_limits(Limits { size: Some(3), ..Limits::default() });
+        let p = compile("\"ab\" \"cd\" +", s.words_mut()).unwrap();
+        assert_eq!(to_source(&optimize(&p, &s), s.words()), "\"ab\" \"cd\" +");
+    }
+
+    #[test]
+    fn test_fold_word_size() {
+        let mut s = Stack::new();
+        s.set_word_size(8).unwrap();
+        let p = compile("0x40 1 shl 0 not", s.words_mut()).unwrap();
+        assert_eq!(to_source(&optimize(&p, &s), s.words()), "-128 -1");
+    }
+
+    #[test]
+    fn test_fold_floats() {
+        assert_eq!(optimized("4 sqrt 0.5 *"), "1e0");
+        assert_eq!(optimized("1 ~ sqrt"), "-1 sqrt");
+
+        let mut s = Stack::new();
+        s.set_ieee(true);
+        let p = compile("1 ~ sqrt", s.words_mut()).unwrap();
+        assert_eq!(to_source(&optimize(&p, &s), s.words()), "NaN");
+    }
+
+    #[test]
+    fn test_optimized_runs_the_same() {
+        let src = ": inc 1 + ; : neg2 ~ ~ ; 5 inc neg2 true ~ ~ <-> <-> 3 4 = <->";
+        let mut a = Stack::new();
+        let p = compile(src, a.words_mut()).unwrap();
+        run(&p, &mut a).unwrap();
+
+        let mut b = Stack::new();
+        let p = compile(src, b.words_mut()).unwrap();
+        let p = optimize(&p, &b);
+        run(&p, &mut b).unwrap();
+
+        for _ in 0..3 {
+            assert_eq!(a.pop().unwrap(), b.pop().unwrap());
+        }
+        assert!(b.pop().is_err());
+        assert_eq!(a.depth(), 0);
+    }
+}
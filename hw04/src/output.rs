@@ -0,0 +1,248 @@
+use std::io::{BufRead, Write};
+
+use serde_json::Value;
+
+use bytecode::{tokenize, Located};
+use console::Stderr;
+use parser::evaluate_located;
+use rpn::{self, Elt, Stack};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Machine-readable formats for the results of a run, see `Output`.
+pub enum Format {
+    /// One JSON object per record.
+    Json,
+    /// A header, then one comma-separated row per record.
+    Csv,
+    /// The stack values separated by spaces, or the error.
+    Plain,
+}
+
+impl Format {
+    /// Reads a format name: `json`, `csv` or `plain`.
+    pub fn parse(name: &str) -> rpn::Result<Format> {
+        match name {
+            "json" => Ok(Format::Json),
+            "csv" => Ok(Format::Csv),
+            "plain" => Ok(Format::Plain),
+            _ => Err(rpn::Error::Syntax),
+        }
+    }
+}
+
+/// A value as JSON: a number, a string of its exact digits for a decimal,
+/// `{"re": 3.0, "im": 4.0}` for a complex number, `{"value": 9.81,
+/// "unit": "m/s^2"}` for a quantity, an array of rows for a matrix, a
+/// boolean or a string.
+pub fn elt_to_json(elt: &Elt) -> Value {
+    match *elt {
+        Elt::Int(i) => Value::from(i),
+        Elt::Decimal(d) => Value::from(d.to_string()),
+        // NaN and infinities are `null`.
+        Elt::Float(x) => Value::from(x),
+        Elt::Complex(z) => json!({ "re": z.re, "im": z.im }),
+        Elt::Quantity(ref q) => json!({ "value": q.value(), "unit": q.unit().to_string() }),
+        Elt::Matrix(ref m) => json!(m.rows()),
+        Elt::Bool(b) => Value::from(b),
+        Elt::Str(ref s) => Value::from(s.as_str()),
+    }
+}
+
+/// The values of a stack as a JSON array, bottom first.
+pub fn stack_to_json(stack: &Stack) -> Value {
+    Value::Array(bottom_first(stack).into_iter().map(elt_to_json).collect())
+}
+
+/// An error of `line` as a JSON object: its kind, message, and the
+/// position and text of the token it is at (`null` when not known).
+pub fn error_to_json(error: &Located, line: &str) -> Value {
+    json!({
+        "kind": error.error.kind(),
+        "message": format!("{:?}", error.error),
+        "position": error.pos,
+        "token": token(error, line),
+    })
+}
+
+fn bottom_first(stack: &Stack) -> Vec<&Elt> {
+    let mut values: Vec<&Elt> = stack.iter().collect();
+    values.reverse();
+    values
+}
+
+/// The token of `line` an error is at.
+fn token<'a>(error: &Located, line: &'a str) -> Option<&'a str> {
+    let pos = error.pos?;
+    tokenize(line).into_iter().find(|&(p, _)| p == pos).map(|(_, t)| t)
+}
+
+/// Quotes a CSV field if it needs to be.
+pub fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Writes results as records with a stable schema, one per line of input
+/// and one for the final stack.
+///
+/// - JSON: `{"line": 1, "stack": [1, true]}`, with an `"error"` object
+///   (see `error_to_json`) when the line failed; the final stack record
+///   has no `"line"`.
+/// - CSV: the columns `line,stack,error,position,token`, the stack values
+///   bottom first separated by spaces.
+/// - Plain: the stack values bottom first separated by spaces, or
+///   `error: <kind> at <position> (<token>)` on a failed line.
+pub struct Output<W: Write> {
+    format: Format,
+    out: W,
+    header: bool,
+}
+
+impl<W: Write> Output<W> {
+    pub fn new(format: Format, out: W) -> Output<W> {
+        Output { format, out, header: false }
+    }
+
+    /// Writes the result of input line number `line` (from 1), or of the
+    /// whole input when `None`.
+    pub fn record(&mut self,
+                  line: Option<usize>,
+                  source: &str,
+                  stack: &Stack,
+                  error: Option<&Located>) -> rpn::Result<()> {
+        let values: Vec<String> = bottom_first(stack).iter().map(|v| v.to_string()).collect();
+        let values = values.join(" ");
+
+        let text = match self.format {
+            Format::Json => {
+                let mut record = json!({});
+                if let Some(line) = line {
+                    record["line"] = json!(line);
+                }
+                record["stack"] = stack_to_json(stack);
+                if let Some(error) = error {
+                    record["error"] = error_to_json(error, source);
+                }
+                record.to_string()
+            },
+            Format::Csv => {
+                let mut text = String::new();
+                if !self.header {
+                    text.push_str("line,stack,error,position,token\n");
+                    self.header = true;
+                }
+                let fields = [
+                    line.map(|l| l.to_string()).unwrap_or_default(),
+                    values,
+                    error.map(|e| e.error.kind().to_string()).unwrap_or_default(),
+                    error.and_then(|e| e.pos).map(|p| p.to_string()).unwrap_or_default(),
+                    error.and_then(|e| token(e, source)).unwrap_or_default().to_string(),
+                ];
+                let fields: Vec<String> = fields.iter().map(|f| csv_field(f)).collect();
+                text.push_str(&fields.join(","));
+                text
+            },
+            Format::Plain => match error {
+                None => values,
+                Some(error) => match (error.pos, token(error, source)) {
+                    (Some(pos), Some(t)) => format!("error: {} at {} ({})", error.error.kind(), pos, t),
+                    _ => format!("error: {}", error.error.kind()),
+                },
+            },
+        };
+        writeln!(self.out, "{}", text).map_err(rpn::Error::io)
+    }
+}
+
+/// Evaluates every line of `input` on a fresh stack and writes the results
+/// to `output`: a record per line, or with `final_only` only the records
+/// of failed lines and then the final stack. `quit` ends the input.
+/// Console operations write to the standard error.
+pub fn run_batch<R: BufRead, W: Write>(format: Format,
+                                       final_only: bool,
+                                       input: R,
+                                       output: W) -> rpn::Result<()> {
+    let mut stack = Stack::new();
+    stack.set_console(Box::new(Stderr));
+    let mut output = Output::new(format, output);
+
+    for (i, line) in input.lines().enumerate() {
+        let line = line.map_err(rpn::Error::io)?;
+        let result = evaluate_located(&mut stack, &line);
+        if let Err(Located { error: rpn::Error::Quit, .. }) = result {
+            break;
+        }
+        if !final_only || result.is_err() {
+            output.record(Some(i + 1), &line, &stack, result.err().as_ref())?;
+        }
+    }
+
+    if final_only {
+        output.record(None, "", &stack, None)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use decimal::Decimal;
+
+    fn batch(format: Format, final_only: bool, input: &str) -> String {
+        let mut out = Vec::new();
+        run_batch(format, final_only, input.as_bytes(), &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    const INPUT: &str = "1 2\n+ true\n~ 4 +\n";
+
+    #[test]
+    fn test_json() {
+        let lines: Vec<Value> = batch(Format::Json, false, INPUT).lines()
+            .map(|l| ::serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(lines[0], json!({ "line": 1, "stack": [1, 2] }));
+        assert_eq!(lines[1], json!({ "line": 2, "stack": [3, true] }));
+        assert_eq!(lines[2], json!({
+            "line": 3,
+            "stack": [3],
+            "error": { "kind": "Type", "message": "Type", "position": 2, "token": "+" },
+        }));
+    }
+
+    #[test]
+    fn test_decimal_json() {
+        // Too many digits for an f64 to hold.
+        let d = Elt::Decimal(Decimal::new(1234567890123456789, 2));
+        assert_eq!(elt_to_json(&d), json!("12345678901234567.89"));
+        assert_eq!(batch(Format::Json, false, "0.10"), "{\"line\":1,\"stack\":[\"0.10\"]}\n");
+    }
+
+    #[test]
+    fn test_csv() {
+        assert_eq!(batch(Format::Csv, false, INPUT),
+                   "line,stack,error,position,token\n1,1 2,,,\n2,3 true,,,\n3,3,Type,2,+\n");
+        assert_eq!(batch(Format::Csv, false, "1,2"),
+                   "line,stack,error,position,token\n1,,Syntax,0,\"1,2\"\n");
+    }
+
+    #[test]
+    fn test_plain() {
+        assert_eq!(batch(Format::Plain, false, INPUT), "1 2\n3 true\nerror: Type at 2 (+)\n");
+    }
+
+    #[test]
+    fn test_final_only() {
+        assert_eq!(batch(Format::Plain, true, INPUT), "error: Type at 2 (+)\n3\n");
+        assert_eq!(batch(Format::Json, true, "1\nquit\n2"), "{\"stack\":[1]}\n");
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Format::parse("csv").unwrap(), Format::Csv);
+        assert!(Format::parse("xml").is_err());
+    }
+}
@@ -1,58 +1,531 @@
 use std::io::{self, Write};
 
-use rpn::{self, Stack};
+use bytecode::{self, Loader, Located};
+use commands::{is_command, is_journaled, run_command};
+use complex::Complex;
+use decimal::{Decimal, MAX_SCALE};
+use debugger::Debugger;
+use display::Display;
+use history::History;
+use matrix::Matrix;
+use optimizer;
+use rpn::{self, Mode, Stack};
+use session::{self, Journal};
 
-/// Start& a read-eval-print loop, which runs until an error or `quit`.
-pub fn read_eval_print_loop() -> rpn::Result<()> {
-    // Create a stack to work on.
-    let mut stack = Stack::new();
+/// The prompt shown before each line unless configured otherwise.
+pub const DEFAULT_PROMPT: &str = "> ";
 
+/// The state of an interactive session.
+pub struct Repl {
+    pub stack: Stack,
+    pub debugger: Debugger,
+    pub history: History,
+    pub display: Display,
+    /// Where input lines are recorded, if anywhere.
+    pub journal: Option<Journal>,
+    /// Printed before reading each line.
+    pub prompt: String,
+}
+
+impl Default for Repl {
+    fn default() -> Repl {
+        Repl {
+            stack: Stack::new(),
+            debugger: Debugger::new(),
+            history: History::default(),
+            display: Display::default(),
+            journal: None,
+            prompt: DEFAULT_PROMPT.to_string(),
+        }
+    }
+}
+
+impl Repl {
+    pub fn new() -> Repl {
+        Repl::default()
+    }
+
+    /// Runs a line of input, a command or RPN, and records it in the
+    /// journal.
+    pub fn handle(&mut self, line: &str) -> rpn::Result<()> {
+        if let Some(ref mut journal) = self.journal {
+            if is_journaled(line) {
+                journal.record(line)?;
+            }
+        }
+
+        if is_command(line) {
+            run_command(self, line)
+        } else {
+            self.evaluate(line)
+        }
+    }
+
+    /// Runs a line as typed at the prompt: errors are shown and, after
+    /// RPN input, so is the stack. Only `quit` is returned as an error.
+    pub fn run_line(&mut self, line: &str) -> rpn::Result<()> {
+        match self.handle(line) {
+            Err(rpn::Error::Quit) => return Err(rpn::Error::Quit),
+            Err(e) => self.show(&format!("Error: {:?}", e))?,
+            Ok(_) => {},
+        }
+        if !is_command(line) {
+            let text = self.display.render(&self.stack);
+            self.show(&text)?;
+        }
+        Ok(())
+    }
+
+    /// Runs every line of a journal (or session) file as if typed at the
+    /// prompt, echoing it first.
+    pub fn replay(&mut self, path: &str) -> rpn::Result<()> {
+        for line in session::read_lines(path)? {
+            self.show(&format!("> {}", line))?;
+            self.run_line(&line)?;
+        }
+        Ok(())
+    }
+
+    /// Shows text and a newline on the console of the stack, like the
+    /// output of commands: standard output unless set otherwise.
+    pub fn show(&mut self, text: &str) -> rpn::Result<()> {
+        self.stack.console().write(&format!("{}\n", text)).map_err(rpn::Error::io)
+    }
+
+    /// Evaluates a line of input and records it in the history. While
+    /// debugging, the line runs unoptimized through the debugger, which
+    /// talks over stdin/stdout.
+    pub fn evaluate(&mut self, buf: &str) -> rpn::Result<()> {
+        let before = self.stack.snapshot();
+        let result = if self.debugger.is_active() {
+            compile_line(&mut self.stack, buf).and_then(|program| {
+                let stdin = io::stdin();
+                self.debugger.run(&program, &mut self.stack, stdin.lock(), io::stdout())
+            })
+        } else {
+            evaluate_line(&mut self.stack, buf)
+        };
+        self.history.record(before, &self.stack);
+        result
+    }
+}
+
+/// Start& a read-eval-print loop on `repl`, which runs until an error or
+/// `quit`.
+pub fn read_eval_print_loop(repl: &mut Repl) -> rpn::Result<()> {
     loop {
         // Print a user input prompt.
-        print!("> ");
-        io::stdout().flush().map_err(rpn::Error::IO)?;
+        print!("{}", repl.prompt);
+        io::stdout().flush().map_err(rpn::Error::io)?;
 
         // Read from stdin into a String, 
         let mut user_input = String::new();
         let stdin = io::stdin();
         match stdin.read_line(&mut user_input) {
-            Ok(_) => return evaluate_line(&mut stack, &user_input),
-            Err(e) => return rpn::Result::Err(rpn::Error::IO(e)),
+            // End of input, same as `quit`.
+            Ok(0) => return Err(rpn::Error::Quit),
+            Ok(_) => repl.run_line(&user_input)?,
+            Err(e) => return rpn::Result::Err(rpn::Error::io(e)),
         }
     }
 }
 
-fn get_value(token: &str) -> Result<rpn::Elt, rpn::Error> {
-    // for me to understand what is happening here!!
-    // 1. token.parse returns a Result
-    // 2. that result calls its method map
-    // 2.1      if result was ok(t), call the function given to map -> f(t)
-    // 2.2      else return a Result as Err
-    // 3. on the result from map, call its or method
-    // 3.1      if the result from map was ok, then forward the ok
-    // 3.2      else return the result provided to or, which is the parse.map to bool
-    // 4. on the result from the or(parse.map), call again or
-    token.parse::<i32>()
-        .map(|v| rpn::Elt::Int(v))
-        .or(token.parse::<bool>().map(|v| rpn::Elt::Bool(v)))
+/// Reads a literal value. Each kind of literal is tried in turn, and the
+/// first one that reads the token wins: an integer, a decimal, a float, a
+/// complex number, a matrix, a string, then `true` or `false`.
+pub fn get_value(token: &str) -> Result<rpn::Elt, rpn::Error> {
+    get_int(token)
+        .map(rpn::Elt::Int)
+        .or_else(|| get_decimal(token).map(rpn::Elt::Decimal))
+        .or_else(|| get_float(token).map(rpn::Elt::Float))
+        .or_else(|| get_complex(token).map(rpn::Elt::Complex))
+        .or_else(|| get_matrix(token).map(rpn::Elt::Matrix))
+        .or_else(|| get_string(token).map(rpn::Elt::Str))
+        .ok_or(rpn::Error::Syntax)
+        .or(token.parse::<bool>().map(rpn::Elt::Bool))
         .or(Err(rpn::Error::Syntax))
 }
 
-fn get_operation(token: &str) -> rpn::Result<rpn::Op> {
+/// Reads a decimal literal: digits with a point and at most `MAX_SCALE`
+/// digits after it (`19.99`, `-.5`). The scale is the number of digits
+/// after the point.
+fn get_decimal(token: &str) -> Option<Decimal> {
+    let (negative, digits) = match token.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, token),
+    };
+    let (whole, fraction) = digits.split_once('.')?;
+    let is_digits = |s: &str| s.chars().all(|c| c.is_ascii_digit());
+    if fraction.is_empty() || fraction.len() > MAX_SCALE as usize
+        || !is_digits(whole) || !is_digits(fraction) {
+        return None;
+    }
+    let units: i128 = format!("{}{}", whole, fraction).parse().ok()?;
+    let units = if negative { -units } else { units };
+    Some(Decimal::new(units, fraction.len() as u32))
+}
+
+/// Reads a float literal: a number with an exponent (`1e-3`, `2.5e0`) or
+/// with too many digits to be a decimal, or `inf`, `-inf` and `NaN` as
+/// floats are shown.
+fn get_float(token: &str) -> Option<f64> {
     match token {
-        "+" => rpn::Result::Ok(rpn::Op::Add),
-        "~" => rpn::Result::Ok(rpn::Op::Neg),
-        "<->" => rpn::Result::Ok(rpn::Op::Swap),
-        "=" => rpn::Result::Ok(rpn::Op::Eq),
-        "#" => rpn::Result::Ok(rpn::Op::Rand),
-        "quit" => rpn::Result::Ok(rpn::Op::Quit),
-        _ => rpn::Result::Err(rpn::Error::Syntax),
+        "inf" => return Some(f64::INFINITY),
+        "-inf" => return Some(f64::NEG_INFINITY),
+        "NaN" => return Some(f64::NAN),
+        _ => {},
+    }
+    // Rust reads `nan` or `infinity` too, which are names here.
+    let digits = token.strip_prefix('-').unwrap_or(token);
+    if !digits.starts_with(|c: char| c.is_ascii_digit() || c == '.') {
+        return None;
+    }
+    token.parse().ok()
+}
+
+/// Reads a decimal number as a float, the parts of a complex literal.
+fn get_real(token: &str) -> Option<f64> {
+    token.parse::<i64>().ok().map(|i| i as f64).or_else(|| get_float(token))
+}
+
+/// Reads a complex literal: `3+4i`, `-2.5i` or `(3,4)`.
+fn get_complex(token: &str) -> Option<Complex> {
+    if let Some(parts) = token.strip_prefix('(').and_then(|t| t.strip_suffix(')')) {
+        let (re, im) = parts.split_once(',')?;
+        return Some(Complex::new(get_real(re)?, get_real(im)?));
+    }
+
+    let number = token.strip_suffix('i')?;
+    // The sign of the imaginary part, not one of the real part or of an
+    // exponent.
+    let split = number.char_indices()
+        .rev()
+        .find(|&(i, c)| {
+            i > 0 && (c == '+' || c == '-') && !number[..i].ends_with(['e', 'E'])
+        })
+        .map(|(i, _)| i);
+    match split {
+        Some(i) => {
+            let im = &number[i..];
+            let im = get_real(im.strip_prefix('+').unwrap_or(im))?;
+            Some(Complex::new(get_real(&number[..i])?, im))
+        },
+        None => Some(Complex::new(0.0, get_real(number)?)),
+    }
+}
+
+/// Reads a string literal: any text in double quotes, `"hello world"`.
+/// `\"`, `\\`, `\n`, `\r` and `\t` stand for a quote, a backslash, a
+/// newline, a carriage return and a tab, the way strings are displayed.
+fn get_string(token: &str) -> Option<String> {
+    let text = token.strip_prefix('"')?.strip_suffix('"')?;
+    let mut string = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        string.push(match c {
+            '"' => return None,
+            '\\' => match chars.next()? {
+                'n' => '\n',
+                'r' => '\r',
+                't' => '\t',
+                c @ ('"' | '\\') => c,
+                _ => return None,
+            },
+            c => c,
+        });
+    }
+    Some(string)
+}
+
+/// Reads a matrix literal: rows of real numbers in brackets, inside
+/// brackets (`[[1 2][3 4]]`). Rows may be separated by spaces.
+fn get_matrix(token: &str) -> Option<Matrix> {
+    let mut rest = token.strip_prefix('[')?.strip_suffix(']')?.trim();
+    let mut rows = Vec::new();
+    while !rest.is_empty() {
+        let (row, after) = rest.strip_prefix('[')?.split_once(']')?;
+        let row: Option<Vec<f32>> = row.split_whitespace()
+            .map(|t| get_value(t).ok()?.as_float().map(|x| x as f32))
+            .collect();
+        rows.push(row?);
+        rest = after.trim_start();
+    }
+    Matrix::new(rows)
+}
+
+/// Reads an integer literal: decimal, or hexadecimal, octal or binary with
+/// a `0x`, `0o` or `0b` prefix, optionally negative (`-0xff`). Prefixed
+/// literals are bit patterns: `0xffffffffffffffff` is -1.
+fn get_int(token: &str) -> Option<i64> {
+    let (negative, digits) = match token.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, token),
+    };
+    let radix = match digits.get(..2) {
+        Some("0x") => 16,
+        Some("0o") => 8,
+        Some("0b") => 2,
+        _ => return token.parse().ok(),
+    };
+    let digits = &digits[2..];
+    if digits.starts_with(['+', '-']) {
+        return None;
+    }
+    if negative {
+        i64::from_str_radix(&format!("-{}", digits), radix).ok()
+    } else {
+        u64::from_str_radix(digits, radix).ok().map(|w| w as i64)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+/// A builtin operation: the token it is written with, and what `:help`
+/// tells about it.
+pub struct OpInfo {
+    pub token: &'static str,
+    pub op: rpn::Op,
+    /// The values it takes and leaves, deepest first, `--` in between. A
+    /// name ending in `...` stands for any number of values.
+    pub effect: &'static str,
+    /// The types of values it takes.
+    pub types: &'static str,
+    /// What it does, in a sentence.
+    pub doc: &'static str,
+    /// A line using it, and the stack that line leaves.
+    pub example: (&'static str, &'static str),
+}
+
+impl OpInfo {
+    /// What `:help <token>` tells about the operation.
+    pub fn describe(&self) -> String {
+        let mut lines = vec![format!("{}  ( {} )", self.token, self.effect), self.doc.to_string()];
+        if !self.types.is_empty() {
+            lines.push(format!("takes: {}", self.types));
+        }
+        match self.example {
+            (line, "") => lines.push(format!("example: {}", line)),
+            (line, leaves) => lines.push(format!("example: {}  => {}", line, leaves)),
+        }
+        lines.join("\n")
+    }
+}
+
+/// The builtin operations, with their tokens and documentation.
+pub const OPERATIONS: &[OpInfo] = &[
+    OpInfo { token: "+", op: rpn::Op::Add, effect: "y x -- y+x",
+             types: "numbers, quantities, matrices, strings",
+             doc: "Adds two values, or joins two strings.", example: ("2 3 +", "5") },
+    OpInfo { token: "*", op: rpn::Op::Mul, effect: "y x -- y*x",
+             types: "numbers, quantities, matrices",
+             doc: "Multiplies two values; matrices multiply y by x.", example: ("2 3 *", "6") },
+    OpInfo { token: "and", op: rpn::Op::And, effect: "y x -- y&x", types: "integers, booleans",
+             doc: "Bitwise and of two integers, logical and of two booleans.",
+             example: ("12 10 and", "8") },
+    OpInfo { token: "or", op: rpn::Op::Or, effect: "y x -- y|x", types: "integers, booleans",
+             doc: "Bitwise or of two integers, logical or of two booleans.",
+             example: ("12 10 or", "14") },
+    OpInfo { token: "xor", op: rpn::Op::Xor, effect: "y x -- y^x", types: "integers, booleans",
+             doc: "Bitwise exclusive or of two integers or booleans.",
+             example: ("12 10 xor", "6") },
+    OpInfo { token: "not", op: rpn::Op::Not, effect: "x -- !x", types: "integers, booleans",
+             doc: "Flips every bit of the word of an integer, or negates a boolean.",
+             example: ("true not", "false") },
+    OpInfo { token: "shl", op: rpn::Op::Shl, effect: "x n -- x<<n", types: "integers",
+             doc: "Shifts left by n bits within the word, right if n is negative.",
+             example: ("1 4 shl", "16") },
+    OpInfo { token: "shr", op: rpn::Op::Shr, effect: "x n -- x>>n", types: "integers",
+             doc: "Shifts right by n bits within the word, filling with zeros.",
+             example: ("16 4 shr", "1") },
+    OpInfo { token: "rol", op: rpn::Op::Rol, effect: "x n -- x", types: "integers",
+             doc: "Rotates the word left by n bits.", example: ("1 1 rol", "2") },
+    OpInfo { token: "ror", op: rpn::Op::Ror, effect: "x n -- x", types: "integers",
+             doc: "Rotates the word right by n bits.", example: ("2 1 ror", "1") },
+    OpInfo { token: "pow", op: rpn::Op::Pow, effect: "y x -- y^x", types: "numbers, quantities",
+             doc: "Raises y to the power x; integer powers of integers are integers.",
+             example: ("2 10 pow", "1024") },
+    OpInfo { token: "sqrt", op: rpn::Op::Sqrt, effect: "x -- √x", types: "numbers, quantities",
+             doc: "Square root.", example: ("2.25 sqrt", "1.5e0") },
+    OpInfo { token: "exp", op: rpn::Op::Exp, effect: "x -- e^x", types: "numbers",
+             doc: "Exponential.", example: ("0 exp", "1e0") },
+    OpInfo { token: "ln", op: rpn::Op::Ln, effect: "x -- ln(x)", types: "numbers",
+             doc: "Natural logarithm.", example: ("1 ln", "0e0") },
+    OpInfo { token: "log10", op: rpn::Op::Log10, effect: "x -- log10(x)", types: "real numbers",
+             doc: "Base 10 logarithm.", example: ("1000 log10", "3e0") },
+    OpInfo { token: "log2", op: rpn::Op::Log2, effect: "x -- log2(x)", types: "real numbers",
+             doc: "Base 2 logarithm.", example: ("8 log2", "3e0") },
+    OpInfo { token: "sin", op: rpn::Op::Sin, effect: "a -- sin(a)", types: "real numbers",
+             doc: "Sine of an angle, in the unit set with `:angle`.", example: ("0 sin", "0e0") },
+    OpInfo { token: "cos", op: rpn::Op::Cos, effect: "a -- cos(a)", types: "real numbers",
+             doc: "Cosine of an angle.", example: ("0 cos", "1e0") },
+    OpInfo { token: "tan", op: rpn::Op::Tan, effect: "a -- tan(a)", types: "real numbers",
+             doc: "Tangent of an angle.", example: ("0 tan", "0e0") },
+    OpInfo { token: "asin", op: rpn::Op::Asin, effect: "x -- a", types: "real numbers",
+             doc: "Arcsine, an angle in the unit set with `:angle`.", example: ("0 asin", "0e0") },
+    OpInfo { token: "acos", op: rpn::Op::Acos, effect: "x -- a", types: "real numbers",
+             doc: "Arccosine.", example: ("1 acos", "0e0") },
+    OpInfo { token: "atan", op: rpn::Op::Atan, effect: "x -- a", types: "real numbers",
+             doc: "Arctangent.", example: ("0 atan", "0e0") },
+    OpInfo { token: "sinh", op: rpn::Op::Sinh, effect: "x -- sinh(x)", types: "real numbers",
+             doc: "Hyperbolic sine.", example: ("0 sinh", "0e0") },
+    OpInfo { token: "cosh", op: rpn::Op::Cosh, effect: "x -- cosh(x)", types: "real numbers",
+             doc: "Hyperbolic cosine.", example: ("0 cosh", "1e0") },
+    OpInfo { token: "tanh", op: rpn::Op::Tanh, effect: "x -- tanh(x)", types: "real numbers",
+             doc: "Hyperbolic tangent.", example: ("0 tanh", "0e0") },
+    OpInfo { token: "asinh", op: rpn::Op::Asinh, effect: "x -- asinh(x)", types: "real numbers",
+             doc: "Inverse hyperbolic sine.", example: ("0 asinh", "0e0") },
+    OpInfo { token: "acosh", op: rpn::Op::Acosh, effect: "x -- acosh(x)", types: "real numbers",
+             doc: "Inverse hyperbolic cosine.", example: ("1 acosh", "0e0") },
+    OpInfo { token: "atanh", op: rpn::Op::Atanh, effect: "x -- atanh(x)", types: "real numbers",
+             doc: "Inverse hyperbolic tangent.", example: ("0 atanh", "0e0") },
+    OpInfo { token: "floor", op: rpn::Op::Floor, effect: "x -- ⌊x⌋", types: "real numbers, quantities",
+             doc: "Rounds down to a whole number.", example: ("2.5 floor", "2.0") },
+    OpInfo { token: "ceil", op: rpn::Op::Ceil, effect: "x -- ⌈x⌉", types: "real numbers, quantities",
+             doc: "Rounds up to a whole number.", example: ("2.5 ceil", "3.0") },
+    OpInfo { token: "round", op: rpn::Op::Round, effect: "x -- x", types: "real numbers, quantities",
+             doc: "Rounds to the nearest whole number, halves away from zero.",
+             example: ("2.5 round", "3.0") },
+    OpInfo { token: "conj", op: rpn::Op::Conj, effect: "z -- z̄", types: "numbers, quantities",
+             doc: "Complex conjugate; real numbers are left as they are.",
+             example: ("(1,2) conj", "1-2i") },
+    OpInfo { token: "abs", op: rpn::Op::Abs, effect: "x -- |x|", types: "numbers, quantities",
+             doc: "Absolute value, or magnitude of a complex number.", example: ("-3 abs", "3") },
+    OpInfo { token: "arg", op: rpn::Op::Arg, effect: "z -- a", types: "numbers",
+             doc: "Angle of a complex number, in the unit set with `:angle`.",
+             example: ("1 arg", "0e0") },
+    OpInfo { token: "polar", op: rpn::Op::Polar, effect: "z -- r a", types: "numbers",
+             doc: "Splits a complex number into its magnitude and angle.",
+             example: ("(3,4) polar", "5e0 9.272952180016122e-1") },
+    OpInfo { token: "rect", op: rpn::Op::Rect, effect: "r a -- z", types: "real numbers",
+             doc: "Makes a complex number from a magnitude and an angle.",
+             example: ("2 0 rect", "2+0i") },
+    OpInfo { token: "transpose", op: rpn::Op::Transpose, effect: "m -- mᵀ", types: "matrices",
+             doc: "Transposes a matrix.", example: ("[[1 2]] transpose", "[[1][2]]") },
+    OpInfo { token: "det", op: rpn::Op::Det, effect: "m -- d", types: "square matrices",
+             doc: "Determinant of a matrix.", example: ("[[1 2] [3 4]] det", "-2e0") },
+    OpInfo { token: "identity", op: rpn::Op::Identity, effect: "n -- m", types: "integers",
+             doc: "Identity matrix of size n.", example: ("2 identity", "[[1 0][0 1]]") },
+    OpInfo { token: "primes", op: rpn::Op::Primes, effect: "n -- p...", types: "integers",
+             doc: "Pushes every prime below n.", example: ("10 primes", "2 3 5 7") },
+    OpInfo { token: "prime?", op: rpn::Op::IsPrime, effect: "n -- b", types: "integers",
+             doc: "Whether n is prime.", example: ("97 prime?", "true") },
+    OpInfo { token: "factor", op: rpn::Op::Factor, effect: "n -- p...", types: "integers",
+             doc: "Pushes the prime factors of n, smallest first.",
+             example: ("12 factor", "2 2 3") },
+    OpInfo { token: "gcd", op: rpn::Op::Gcd, effect: "a b -- d", types: "integers",
+             doc: "Greatest common divisor.", example: ("12 18 gcd", "6") },
+    OpInfo { token: "lcm", op: rpn::Op::Lcm, effect: "a b -- m", types: "integers",
+             doc: "Least common multiple.", example: ("4 6 lcm", "12") },
+    OpInfo { token: "modpow", op: rpn::Op::ModPow, effect: "b e m -- r", types: "integers",
+             doc: "b to the power e, modulo m; a negative e uses the inverse of b.",
+             example: ("4 13 497 modpow", "445") },
+    OpInfo { token: "modinv", op: rpn::Op::ModInv, effect: "a m -- x", types: "integers",
+             doc: "The x with a*x = 1 modulo m.", example: ("3 11 modinv", "4") },
+    OpInfo { token: "totient", op: rpn::Op::Totient, effect: "n -- φ", types: "integers",
+             doc: "Euler's totient: how many numbers up to n are coprime with it.",
+             example: ("36 totient", "12") },
+    OpInfo { token: "pi", op: rpn::Op::Pi, effect: "-- π", types: "",
+             doc: "Pushes π.", example: ("pi", "3.141592653589793e0") },
+    OpInfo { token: "e", op: rpn::Op::E, effect: "-- e", types: "",
+             doc: "Pushes e.", example: ("e", "2.718281828459045e0") },
+    OpInfo { token: "~", op: rpn::Op::Neg, effect: "x -- -x",
+             types: "numbers, quantities, matrices, booleans",
+             doc: "Negates a value.", example: ("3 ~", "-3") },
+    OpInfo { token: "<->", op: rpn::Op::Swap, effect: "y x -- x y", types: "any",
+             doc: "Swaps the top two values.", example: ("1 2 <->", "2 1") },
+    OpInfo { token: "=", op: rpn::Op::Eq, effect: "y x -- b", types: "any",
+             doc: "Whether two values are equal; numbers compare by value.",
+             example: ("1 1.0 =", "true") },
+    OpInfo { token: "#", op: rpn::Op::Rand, effect: "x -- r", types: "real numbers",
+             doc: "A random number in [0, x), see `:seed`.", example: ("10 #", "7") },
+    OpInfo { token: "enter", op: rpn::Op::Enter, effect: "x -- x x", types: "any",
+             doc: "Duplicates the top value; in HP mode the next value replaces the copy.",
+             example: ("5 enter", "5 5") },
+    OpInfo { token: "lastx", op: rpn::Op::LastX, effect: "-- x", types: "",
+             doc: "Pushes the X the last operation consumed, in HP mode only.",
+             example: ("2 3 + lastx", "5 3") },
+    OpInfo { token: "throw", op: rpn::Op::Throw, effect: "x --", types: "any",
+             doc: "Fails with an error holding x, which `try` blocks catch.",
+             example: ("try [ 1 throw ] catch [ ]", "1") },
+    OpInfo { token: ".", op: rpn::Op::Dot, effect: "x --", types: "any",
+             doc: "Prints a value and a newline.", example: ("42 .", "") },
+    OpInfo { token: "emit", op: rpn::Op::Emit, effect: "n --", types: "integers",
+             doc: "Prints the character with code point n.", example: ("65 emit", "") },
+    OpInfo { token: "print", op: rpn::Op::Print, effect: "x --", types: "any",
+             doc: "Prints a string without its quotes, or a value, with no newline.",
+             example: ("\"total: \" print", "") },
+    OpInfo { token: "read-line", op: rpn::Op::ReadLine, effect: "-- s", types: "",
+             doc: "Reads a line of input as a string.", example: ("read-line", "\"text\"") },
+    OpInfo { token: "read-number", op: rpn::Op::ReadNumber, effect: "-- x", types: "",
+             doc: "Reads a line of input holding a number.", example: ("read-number", "42") },
+    OpInfo { token: "read-file", op: rpn::Op::ReadFile, effect: "path -- s", types: "strings",
+             doc: "Reads a whole file as a string.", example: ("\"notes.txt\" read-file", "\"...\"") },
+    OpInfo { token: "write-file", op: rpn::Op::WriteFile, effect: "x path --", types: "any, strings",
+             doc: "Writes a value to a file, like `print`, replacing its contents.",
+             example: ("\"hi\" \"notes.txt\" write-file", "") },
+    OpInfo { token: "append-file", op: rpn::Op::AppendFile, effect: "x path --", types: "any, strings",
+             doc: "Writes a value at the end of a file, like `print`.",
+             example: ("\"hi\" \"notes.txt\" append-file", "") },
+    OpInfo { token: "plot", op: rpn::Op::Plot, effect: "pts --", types: "matrices of two columns",
+             doc: "Draws a chart of points `x y`, as made by a `sample` block, fitted to the points.",
+             example: ("-2 2 0.25 sample [ enter * ] plot", "") },
+    OpInfo { token: "table", op: rpn::Op::Table, effect: "pts --", types: "matrices of two columns",
+             doc: "Prints points `x y`, as made by a `sample` block, as a table.",
+             example: ("0 3 1 sample [ enter * ] table", "") },
+    OpInfo { token: "quit", op: rpn::Op::Quit, effect: "--", types: "",
+             doc: "Quits the calculator.", example: ("quit", "") },
+];
+
+/// The builtin operation written `token`.
+pub fn op_info(token: &str) -> Option<&'static OpInfo> {
+    OPERATIONS.iter().find(|info| info.token == token)
+}
+
+pub fn get_operation(token: &str) -> rpn::Result<rpn::Op> {
+    op_info(token)
+        .map(|info| info.op)
+        .ok_or(rpn::Error::Syntax)
+}
+
+/// The token an operation is written with, the inverse of `get_operation`.
+pub fn op_token(op: rpn::Op) -> &'static str {
+    OPERATIONS.iter()
+        .find(|info| info.op == op)
+        .map(|info| info.token)
+        .expect("every operation has a token")
+}
+
+/// Compiles a line to bytecode for `stack`, unless it is longer than the
+/// stack's limits allow.
+pub fn compile_line(stack: &mut Stack, buf: &str) -> rpn::Result<bytecode::Program> {
+    stack.limits().check_size(buf)?;
+    let loader = Loader::of(stack);
+    bytecode::compile_with(buf, stack.words_mut(), &loader).map_err(|e| e.error)
+}
+
+/// Compiles a line to bytecode, optimizes it and runs it on `stack`.
+/// In HP mode the line runs as written, see `optimizer::optimize`.
+pub fn evaluate_line(stack: &mut Stack, buf: &str) -> rpn::Result<()> {
+    evaluate_located(stack, buf).map_err(|e| e.error)
+}
+
+/// Like `evaluate_line`, telling which token an error is at.
+pub fn evaluate_located(stack: &mut Stack, buf: &str) -> Result<(), Located> {
+    stack.limits().check_size(buf)?;
+    let loader = Loader::of(stack);
+    let mut program = bytecode::compile_with(buf, stack.words_mut(), &loader)?;
+    if stack.mode() == Mode::Unbounded {
+        program = optimizer::optimize(&program, stack);
     }
+    bytecode::run_located(&program, stack)
 }
 
-fn evaluate_line(stack: &mut Stack, buf: &String) -> rpn::Result<()> {
+/// Evaluates a line token by token, resolving each one as it goes.
+///
+/// This is how lines were run before the bytecode compiler; it is kept as
+/// the reference the benchmark compares against.
+pub fn interpret_line(stack: &mut Stack, buf: &str) -> rpn::Result<()> {
     // Create an iterator over the tokens.
-    let tokens = buf.trim().split_whitespace();
+    let tokens = buf.split_whitespace();
 
     let mut result = Err(rpn::Error::Syntax);
     for t in tokens {
@@ -67,14 +540,19 @@ fn evaluate_line(stack: &mut Stack, buf: &String) -> rpn::Result<()> {
         }
     }
 
-    println!("Result: {:?}", stack.pop());
     result
 }
 
 #[cfg(test)]
+#[allow(clippy::assertions_on_constants)]
 mod tests {
-    use rpn::{Stack, Error, Elt};
-    use parser::evaluate_line;
+    use rpn::{Stack, Error, Elt, Limits, Mode, Op};
+    use parser::{evaluate_line, evaluate_located, get_operation, get_value, op_info, op_token,
+                 OPERATIONS};
+    use bytecode::Located;
+    use complex::Complex;
+    use decimal::Decimal;
+    use matrix::Matrix;
 
     #[test]
     fn test_evaluate_line_bool() {
@@ -156,6 +634,250 @@ mod tests {
         assert!(res < Elt::Int(12));
     }
 
+    #[test]
+    fn test_evaluate_line_word() {
+        let mut stack = Stack::new();
+        let s = ": inc 1 + ;".to_string();
+        assert!(evaluate_line(&mut stack, &s).is_ok());
+        let s = "12 inc inc".to_string();
+        assert!(evaluate_line(&mut stack, &s).is_ok());
+        assert_eq!(stack.pop().unwrap(), Elt::Int(14));
+    }
+
+    #[test]
+    fn test_evaluate_line_hp() {
+        let mut stack = Stack::with_mode(Mode::Hp);
+        let s = "1 2 3 4 5 + +".to_string();
+        assert!(evaluate_line(&mut stack, &s).is_ok());
+        // T was copied down as the stack dropped, unlike a folded `1 2 12`.
+        let levels: Vec<Elt> = stack.iter().cloned().collect();
+        assert_eq!(levels, vec![Elt::Int(12), Elt::Int(2), Elt::Int(2), Elt::Int(2)]);
+    }
+
+    #[test]
+    fn test_evaluate_line_limits() {
+        let mut stack = Stack::new();
+        stack.set_limits(Limits { size: Some(8), ..Limits::sandbox() });
+        let res = evaluate_line(&mut stack, "1 2 3 4 5");
+        if let Err(Error::SizeLimit) = res { } else { panic!(); }
+        let res = evaluate_line(&mut stack, "quit");
+        if let Err(Error::Denied(Op::Quit)) = res { } else { panic!(); }
+        assert_eq!(stack.depth(), 0);
+    }
+
+    #[test]
+    fn test_evaluate_located() {
+        let mut stack = Stack::new();
+        let res = evaluate_located(&mut stack, ": inc 1 + ; 2 3 + inc true +");
+        if let Err(Located { error: Error::Type, pos: Some(10) }) = res { } else { panic!(); }
+        let res = evaluate_located(&mut stack, "1 2 frob");
+        if let Err(Located { error: Error::Syntax, pos: Some(2) }) = res { } else { panic!(); }
+    }
+
+    #[test]
+    fn test_operations() {
+        for (i, info) in OPERATIONS.iter().enumerate() {
+            assert_eq!(get_operation(info.token).unwrap(), info.op);
+            assert_eq!(op_token(info.op), info.token);
+            assert!(OPERATIONS[..i].iter().all(|other| other.op != info.op), "{}", info.token);
+        }
+        assert!(op_info("frob").is_none());
+
+        assert_eq!(op_info("pow").unwrap().describe(),
+                   "pow  ( y x -- y^x )\n\
+                    Raises y to the power x; integer powers of integers are integers.\n\
+                    takes: numbers, quantities\n\
+                    example: 2 10 pow  => 1024");
+        assert_eq!(op_info("quit").unwrap().describe(),
+                   "quit  ( -- )\nQuits the calculator.\nexample: quit");
+    }
+
+    #[test]
+    fn test_operation_examples() {
+        // The others print, read, quit or depend on the random generator
+        // or on HP mode.
+        let checked = OPERATIONS.iter().filter(|info| {
+            !info.op.is_io() && !matches!(info.op, Op::Rand | Op::LastX | Op::Quit)
+        });
+        for info in checked {
+            let (line, leaves) = info.example;
+            let mut stack = Stack::new();
+            assert!(evaluate_line(&mut stack, line).is_ok(), "{}", line);
+            let values: Vec<String> = stack.iter().map(Elt::to_string).collect();
+            let values: Vec<&str> = values.iter().rev().map(String::as_str).collect();
+            assert_eq!(values.join(" "), leaves, "{}", line);
+        }
+    }
+
+    #[test]
+    fn test_get_value_radix() {
+        assert_eq!(get_value("0xff").unwrap(), Elt::Int(255));
+        assert_eq!(get_value("-0x10").unwrap(), Elt::Int(-16));
+        assert_eq!(get_value("0b1010").unwrap(), Elt::Int(10));
+        assert_eq!(get_value("0o17").unwrap(), Elt::Int(15));
+        assert_eq!(get_value("0xffffffffffffffff").unwrap(), Elt::Int(-1));
+        assert_eq!(get_value("9000000000").unwrap(), Elt::Int(9_000_000_000));
+        for t in &["0x", "0xg", "0b102", "0x-1", "0x+1", "0x10000000000000000", "- 1"] {
+            assert!(get_value(t).is_err(), "{}", t);
+        }
+    }
+
+    #[test]
+    fn test_get_value_float() {
+        assert_eq!(get_value("1.5e0").unwrap(), Elt::Float(1.5));
+        assert_eq!(get_value("-.5e0").unwrap(), Elt::Float(-0.5));
+        assert_eq!(get_value("1e3").unwrap(), Elt::Float(1000.0));
+        // More digits than a decimal keeps.
+        if let Elt::Float(_) = get_value("0.1234567890123456789").unwrap() { } else { panic!(); }
+        assert_eq!(get_value("-inf").unwrap(), Elt::Float(f64::NEG_INFINITY));
+        assert_eq!(get_value("1").unwrap(), Elt::Int(1));
+        for t in &["nan", "infinity", "e", "1.5.2", "1e"] {
+            assert!(get_value(t).is_err(), "{}", t);
+        }
+    }
+
+    #[test]
+    fn test_get_value_decimal() {
+        assert_eq!(get_value("19.99").unwrap(), Elt::Decimal(Decimal::new(1999, 2)));
+        assert_eq!(get_value("-.50").unwrap(), Elt::Decimal(Decimal::new(-50, 2)));
+        assert_eq!(get_value("19.90").unwrap().to_string(), "19.90");
+        for t in &[".", "1.-5", "1.5x", "1,000.00"] {
+            assert!(get_value(t).is_err(), "{}", t);
+        }
+
+        let mut stack = Stack::new();
+        assert!(evaluate_line(&mut stack, "0.1 0.2 + 0.3 =").is_ok());
+        assert_eq!(stack.pop().unwrap(), Elt::Bool(true));
+    }
+
+    #[test]
+    fn test_evaluate_line_units() {
+        let mut stack = Stack::new();
+        assert!(evaluate_line(&mut stack, "9.81 m/s^2 2 kg *").is_ok());
+        assert_eq!(stack.pop().unwrap().to_string(), "19.62 N");
+        assert!(evaluate_line(&mut stack, "2 km 500 m + >m").is_ok());
+        assert_eq!(stack.pop().unwrap().to_string(), "2500 m");
+        assert!(evaluate_line(&mut stack, "212 degF >degC").is_ok());
+        assert_eq!(stack.pop().unwrap().to_string(), "100 degC");
+
+        let res = evaluate_located(&mut stack, "5 m 3 s +");
+        if let Err(Located { error: Error::Units, pos: Some(4) }) = res { } else { panic!(); }
+        let res = evaluate_line(&mut stack, "5 m >kft");
+        if let Err(Error::Syntax) = res { } else { panic!(); }
+
+        // Variables and words come before units.
+        assert!(evaluate_line(&mut stack, "2 !m m m *").is_ok());
+        assert_eq!(stack.pop().unwrap(), Elt::Int(4));
+    }
+
+    #[test]
+    fn test_get_value_matrix() {
+        let m = |rows: Vec<Vec<f32>>| Elt::Matrix(Matrix::new(rows).unwrap());
+        assert_eq!(get_value("[[1 2][3 4]]").unwrap(), m(vec![vec![1.0, 2.0], vec![3.0, 4.0]]));
+        assert_eq!(get_value("[[1.5 -2e0] [0x10 0]]").unwrap(), m(vec![vec![1.5, -2.0], vec![16.0, 0.0]]));
+        for t in &["[[]]", "[[1 2][3]]", "[[1 true]]", "[[1 2]", "[1 2]", "[[1 [2]]]", "[[1]]x"] {
+            assert!(get_value(t).is_err(), "{}", t);
+        }
+    }
+
+    #[test]
+    fn test_get_value_string() {
+        assert_eq!(get_value("\"hello world\"").unwrap(), Elt::Str("hello world".to_string()));
+        assert_eq!(get_value("\"\"").unwrap(), Elt::Str(String::new()));
+        for t in &["\"", "\"abc", "abc\"", "\"a\"b\"", "\"a\\\"", "\"\\x\""] {
+            assert!(get_value(t).is_err(), "{}", t);
+        }
+
+        // Escapes read back what strings display as.
+        let s = Elt::Str("say \"hi\"\tC:\\\r\n".to_string());
+        assert_eq!(s.to_string(), r#""say \"hi\"\tC:\\\r\n""#);
+        assert_eq!(get_value(&s.to_string()).unwrap(), s);
+    }
+
+    #[test]
+    fn test_evaluate_line_strings() {
+        let mut stack = Stack::new();
+        assert!(evaluate_line(&mut stack, "\"foo \" \"bar\" + \"foo bar\" =").is_ok());
+        assert_eq!(stack.pop().unwrap(), Elt::Bool(true));
+        assert!(evaluate_line(&mut stack, "\"a  b\"").is_ok());
+        assert_eq!(stack.pop().unwrap().to_string(), "\"a  b\"");
+        for line in &["\"a\" 1 +", "\"1\" 1 =", "\"a\" ~", "\"a\" sqrt", "\"a\" not"] {
+            let res = evaluate_line(&mut stack, line);
+            if let Err(Error::Type) = res { } else { panic!("{}", line); }
+        }
+    }
+
+    #[test]
+    fn test_evaluate_line_try() {
+        let mut stack = Stack::new();
+        assert!(evaluate_line(&mut stack, "1 try [ 2 true + ] catch [ \"oops\" ]").is_ok());
+        assert_eq!(stack.pop().unwrap(), Elt::Str("oops".to_string()));
+        assert_eq!(stack.pop().unwrap(), Elt::Str("Type".to_string()));
+        assert_eq!(stack.pop().unwrap(), Elt::Int(1));
+        assert!(stack.pop().is_err());
+
+        let line = ": risky try [ \"bad\" throw 1 ] catch [ \"caught \" <-> + ] ; 2 risky";
+        assert!(evaluate_line(&mut stack, line).is_ok());
+        assert_eq!(stack.pop().unwrap(), Elt::Str("caught bad".to_string()));
+        assert_eq!(stack.pop().unwrap(), Elt::Int(2));
+
+        let res = evaluate_line(&mut stack, "42 throw");
+        if let Err(Error::Thrown(Elt::Int(42))) = res { } else { panic!(); }
+        let res = evaluate_line(&mut stack, "try [ 1 ] catch");
+        if let Err(Error::Syntax) = res { } else { panic!(); }
+    }
+
+    #[test]
+    fn test_evaluate_line_include() {
+        let mut stack = Stack::new();
+        stack.set_limits(Limits::sandbox());
+        assert!(evaluate_line(&mut stack, "include \"math.rpn\" 3 4 math:hyp").is_ok());
+        assert_eq!(stack.pop().unwrap(), Elt::Float(5.0));
+        let res = evaluate_line(&mut stack, "1 include \"stack.rpn\" 2 include \"/etc/hosts\"");
+        if let Err(Error::IO(_, _)) = res { } else { panic!(); }
+        assert!(stack.pop().is_err());
+
+        let res = evaluate_located(&mut stack, "1 ( a comment ) include \"nothing.rpn\"");
+        if let Err(Located { error: Error::IO(_, _), pos: Some(5) }) = res { } else { panic!(); }
+    }
+
+    #[test]
+    fn test_evaluate_line_matrix() {
+        let mut stack = Stack::new();
+        assert!(evaluate_line(&mut stack, "[[1 2] [3 4]] [[5] [6]] *").is_ok());
+        assert_eq!(stack.pop().unwrap().to_string(), "[[17][39]]");
+        assert!(evaluate_line(&mut stack, "[[1 2][3 4]] 2 identity + transpose 2 *").is_ok());
+        assert_eq!(stack.pop().unwrap().to_string(), "[[4 6][4 10]]");
+        assert!(evaluate_line(&mut stack, "[[2 0][0 3]] det").is_ok());
+        assert_eq!(stack.pop().unwrap(), Elt::Float(6.0));
+
+        let res = evaluate_located(&mut stack, "[[5] [6]] [[1 2] [3 4]] *");
+        if let Err(Located { error: Error::Shape, pos: Some(6) }) = res { } else { panic!(); }
+        for line in &["[[1 2]] det", "[[1 2]] [[1 2 3]] +"] {
+            let res = evaluate_line(&mut stack, line);
+            if let Err(Error::Shape) = res { } else { panic!("{}", line); }
+        }
+        for line in &["[[1 2]] 1 +", "[[1]] sqrt", "[[1]] floor", "2 transpose", "[[1]] identity"] {
+            let res = evaluate_line(&mut stack, line);
+            if let Err(Error::Type) = res { } else { panic!("{}", line); }
+        }
+        let res = evaluate_line(&mut stack, "0 identity");
+        if let Err(Error::Domain) = res { } else { panic!(); }
+    }
+
+    #[test]
+    fn test_get_value_complex() {
+        let z = |re, im| Elt::Complex(Complex::new(re, im));
+        assert_eq!(get_value("3+4i").unwrap(), z(3.0, 4.0));
+        assert_eq!(get_value("-1.5-2e-3i").unwrap(), z(-1.5, -0.002));
+        assert_eq!(get_value("4i").unwrap(), z(0.0, 4.0));
+        assert_eq!(get_value("(3,-4)").unwrap(), z(3.0, -4.0));
+        assert_eq!(get_value(&z(0.5, -1.0).to_string()).unwrap(), z(0.5, -1.0));
+        for t in &["i", "pi", "3+i", "(3,4", "(3;4)", "(3,4,5)", "3+4j", "nani"] {
+            assert!(get_value(t).is_err(), "{}", t);
+        }
+    }
+
     #[test]
     fn test_evaluate_line_quit() {
         let mut stack = Stack::new();
@@ -0,0 +1,134 @@
+/// The size of the charts `plot` draws, in characters, axes and labels
+/// not included.
+pub const WIDTH: usize = 60;
+pub const HEIGHT: usize = 15;
+
+/// The smallest and largest of some numbers, apart enough to divide by
+/// their difference.
+fn range<I: Iterator<Item = f64>>(values: I) -> (f64, f64) {
+    let (lo, hi) = values.fold((f64::INFINITY, f64::NEG_INFINITY), |(lo, hi), v| {
+        (lo.min(v), hi.max(v))
+    });
+    if lo < hi { (lo, hi) } else { (lo - 1.0, hi + 1.0) }
+}
+
+/// Where `v` falls in `[lo, hi]` on a scale of `n` steps, from 0.
+fn scale(v: f64, (lo, hi): (f64, f64), n: usize) -> usize {
+    ((v - lo) / (hi - lo) * (n - 1) as f64).round() as usize
+}
+
+/// A number short enough for an axis.
+fn label(v: f64) -> String {
+    if v != 0.0 && (v.abs() < 1e-3 || v.abs() >= 1e9) {
+        return format!("{:.2e}", v);
+    }
+    let text = format!("{:.3}", v);
+    match text.trim_end_matches('0').trim_end_matches('.') {
+        "-0" => "0".to_string(),
+        text => text.to_string(),
+    }
+}
+
+/// Draws points as a chart of `width` by `height` characters, `*` for
+/// each point, with both scales fitted to the points. The axes are drawn
+/// where x or y is 0, if in range, and the ends of the scales are written
+/// on the left and below.
+pub fn chart(points: &[(f32, f32)], width: usize, height: usize) -> String {
+    let xs = range(points.iter().map(|&(x, _)| f64::from(x)));
+    let ys = range(points.iter().map(|&(_, y)| f64::from(y)));
+
+    let mut grid = vec![vec![' '; width]; height];
+    if ys.0 <= 0.0 && 0.0 <= ys.1 {
+        let row = height - 1 - scale(0.0, ys, height);
+        grid[row] = vec!['-'; width];
+    }
+    if xs.0 <= 0.0 && 0.0 <= xs.1 {
+        let col = scale(0.0, xs, width);
+        for row in &mut grid {
+            row[col] = if row[col] == '-' { '+' } else { '|' };
+        }
+    }
+    for &(x, y) in points {
+        let row = height - 1 - scale(f64::from(y), ys, height);
+        grid[row][scale(f64::from(x), xs, width)] = '*';
+    }
+
+    let (top, bottom) = (label(ys.1), label(ys.0));
+    let margin = top.len().max(bottom.len());
+    let mut lines = Vec::new();
+    for (i, row) in grid.iter().enumerate() {
+        let name = match i {
+            0 => &top,
+            i if i == height - 1 => &bottom,
+            _ => "",
+        };
+        let row: String = row.iter().collect();
+        lines.push(format!("{:>m$} |{}", name, row, m = margin).trim_end().to_string());
+    }
+    lines.push(format!("{:>m$} +{}", "", "-".repeat(width), m = margin));
+    let (left, right) = (label(xs.0), label(xs.1));
+    let gap = width.saturating_sub(left.len() + right.len()).max(1);
+    lines.push(format!("{:>m$}  {}{}{}", "", left, " ".repeat(gap), right, m = margin));
+    lines.join("\n") + "\n"
+}
+
+/// Writes points as a table of two right-aligned columns, x and y.
+pub fn table(points: &[(f32, f32)]) -> String {
+    let cells: Vec<(String, String)> = points.iter()
+        .map(|&(x, y)| (x.to_string(), y.to_string()))
+        .collect();
+    let width = |header: &str, column: &dyn Fn(&(String, String)) -> usize| {
+        cells.iter().map(column).max().unwrap_or(0).max(header.len())
+    };
+    let wx = width("x", &|c| c.0.len());
+    let wy = width("y", &|c| c.1.len());
+
+    let mut text = format!("{:>wx$}  {:>wy$}\n", "x", "y", wx = wx, wy = wy);
+    for (x, y) in &cells {
+        text.push_str(&format!("{:>wx$}  {:>wy$}\n", x, y, wx = wx, wy = wy));
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_label() {
+        assert_eq!(label(2.0), "2");
+        assert_eq!(label(-0.25), "-0.25");
+        assert_eq!(label(1.0 / 3.0), "0.333");
+        assert_eq!(label(-0.0001), "-1.00e-4");
+        assert_eq!(label(-0.0), "0");
+        assert_eq!(label(2e9), "2.00e9");
+    }
+
+    #[test]
+    fn test_chart() {
+        let points: Vec<(f32, f32)> = (-2..=2).map(|x| (x as f32, (x * x) as f32)).collect();
+        assert_eq!(chart(&points, 9, 5), "\
+4 |*   |   *
+  |    |
+  |    |
+  |  * | *
+0 |----*----
+  +---------
+   -2      2
+");
+        // A single point, no axes in range.
+        assert_eq!(chart(&[(3.0, 5.0)], 3, 3), "\
+6 |
+  | *
+4 |
+  +---
+   2 4
+");
+    }
+
+    #[test]
+    fn test_table() {
+        let points = [(0.0, 0.0), (0.5, 0.25), (10.0, -100.0)];
+        assert_eq!(table(&points), "  x     y\n  0     0\n0.5  0.25\n 10  -100\n");
+    }
+}
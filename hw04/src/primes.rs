@@ -0,0 +1,260 @@
+use std::convert::TryFrom;
+
+use hw01::problem3::sieve;
+
+/// How far the table is sieved on first use. Numbers below this square
+/// are factored by trial division alone.
+const FIRST_LIMIT: u32 = 1 << 16;
+
+/// The furthest the table is sieved, the largest `n` of `primes_below`.
+pub const MAX_LIMIT: u32 = 10_000_000;
+
+/// Bases for which the Miller-Rabin test is exact below 2^64.
+const WITNESSES: [u64; 12] = [2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37];
+
+#[derive(Debug, Default)]
+/// The primes below some limit, sieved once and kept across calls. The
+/// table only grows, by sieving again to at least twice its limit.
+///
+/// Primality of larger numbers is tested with Miller-Rabin, and their
+/// factors found with Pollard's rho.
+pub struct PrimeTable {
+    limit: u32,
+    primes: Vec<u32>,
+}
+
+impl PrimeTable {
+    pub fn new() -> PrimeTable {
+        PrimeTable::default()
+    }
+
+    /// Makes sure the table holds every prime below `n`.
+    fn extend(&mut self, n: u32) {
+        if n <= self.limit {
+            return;
+        }
+        let limit = n.max(FIRST_LIMIT).max(self.limit.saturating_mul(2).min(MAX_LIMIT));
+        self.primes = sieve(limit);
+        self.limit = limit;
+    }
+
+    /// The primes below `n`, `None` if `n` is over `MAX_LIMIT`.
+    pub fn primes_below(&mut self, n: u32) -> Option<&[u32]> {
+        if n > MAX_LIMIT {
+            return None;
+        }
+        self.extend(n);
+        let end = self.primes.partition_point(|&p| p < n);
+        Some(&self.primes[..end])
+    }
+
+    pub fn is_prime(&mut self, n: u64) -> bool {
+        self.extend(FIRST_LIMIT);
+        match u32::try_from(n) {
+            Ok(n) if n < self.limit => self.primes.binary_search(&n).is_ok(),
+            _ => miller_rabin(n),
+        }
+    }
+
+    /// The prime factors of `n`, smallest first and repeated as many times
+    /// as they divide it. 1 has none.
+    pub fn factor(&mut self, mut n: u64) -> Vec<u64> {
+        self.extend(FIRST_LIMIT);
+        let mut factors = Vec::new();
+        for &p in &self.primes {
+            let p = u64::from(p);
+            if p * p > n {
+                break;
+            }
+            while n.is_multiple_of(p) {
+                factors.push(p);
+                n /= p;
+            }
+        }
+        if n > 1 {
+            let mut large = Vec::new();
+            split(n, &mut large);
+            large.sort_unstable();
+            factors.extend(large);
+        }
+        factors
+    }
+
+    /// Euler's totient of `n` ≥ 1: how many numbers up to `n` are coprime
+    /// with it.
+    pub fn totient(&mut self, n: u64) -> u64 {
+        let mut factors = self.factor(n);
+        factors.dedup();
+        factors.iter().fold(n, |t, &p| t / p * (p - 1))
+    }
+}
+
+pub fn gcd(mut a: u64, mut b: u64) -> u64 {
+    while b != 0 {
+        let r = a % b;
+        a = b;
+        b = r;
+    }
+    a
+}
+
+fn mul_mod(a: u64, b: u64, m: u64) -> u64 {
+    (u128::from(a) * u128::from(b) % u128::from(m)) as u64
+}
+
+/// `b` to the power `e`, modulo `m` ≥ 1.
+pub fn mod_pow(b: u64, mut e: u64, m: u64) -> u64 {
+    let mut base = b % m;
+    let mut result = 1 % m;
+    while e > 0 {
+        if e & 1 == 1 {
+            result = mul_mod(result, base, m);
+        }
+        base = mul_mod(base, base, m);
+        e >>= 1;
+    }
+    result
+}
+
+/// The inverse of `a` modulo `m` ≥ 1, in `[0, m)`, if `a` and `m` are
+/// coprime.
+pub fn mod_inv(a: i64, m: i64) -> Option<i64> {
+    // Extended Euclid on (a, m), keeping the coefficient of a.
+    let (mut r0, mut r1) = (i128::from(a).rem_euclid(i128::from(m)), i128::from(m));
+    let (mut s0, mut s1) = (1i128, 0i128);
+    while r1 != 0 {
+        let q = r0 / r1;
+        let (r, s) = (r0 - q * r1, s0 - q * s1);
+        r0 = r1;
+        r1 = r;
+        s0 = s1;
+        s1 = s;
+    }
+    if r0 != 1 {
+        return None;
+    }
+    i64::try_from(s0.rem_euclid(i128::from(m))).ok()
+}
+
+fn miller_rabin(n: u64) -> bool {
+    if n < 2 {
+        return false;
+    }
+    for &p in &WITNESSES {
+        if n.is_multiple_of(p) {
+            return n == p;
+        }
+    }
+    let s = (n - 1).trailing_zeros();
+    let d = (n - 1) >> s;
+    WITNESSES.iter().all(|&a| {
+        let mut x = mod_pow(a, d, n);
+        if x == 1 || x == n - 1 {
+            return true;
+        }
+        for _ in 1..s {
+            x = mul_mod(x, x, n);
+            if x == n - 1 {
+                return true;
+            }
+        }
+        false
+    })
+}
+
+/// Adds the prime factors of `n` > 1 to `factors`, in no order.
+fn split(n: u64, factors: &mut Vec<u64>) {
+    if n == 1 {
+        return;
+    }
+    if miller_rabin(n) {
+        factors.push(n);
+        return;
+    }
+    let d = rho(n);
+    split(d, factors);
+    split(n / d, factors);
+}
+
+/// A non-trivial divisor of the composite `n`, by Pollard's rho.
+fn rho(n: u64) -> u64 {
+    if n.is_multiple_of(2) {
+        return 2;
+    }
+    let mut c = 1;
+    loop {
+        let f = |x: u64| ((u128::from(x) * u128::from(x) + c) % u128::from(n)) as u64;
+        let (mut x, mut y, mut d) = (2, 2, 1);
+        while d == 1 {
+            x = f(x);
+            y = f(f(y));
+            d = gcd(x.abs_diff(y), n);
+        }
+        if d != n {
+            return d;
+        }
+        // The cycle closed without a divisor, try another polynomial.
+        c += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_primes_below() {
+        let mut t = PrimeTable::new();
+        assert_eq!(t.primes_below(20).unwrap(), &[2, 3, 5, 7, 11, 13, 17, 19]);
+        assert_eq!(t.primes_below(2).unwrap(), &[] as &[u32]);
+        assert_eq!(t.primes_below(100_000).unwrap().len(), 9592);
+        assert!(t.primes_below(MAX_LIMIT + 1).is_none());
+    }
+
+    #[test]
+    fn test_table_is_kept() {
+        let mut t = PrimeTable::new();
+        t.primes_below(10);
+        assert_eq!(t.limit, FIRST_LIMIT);
+        t.primes_below(70_000);
+        assert_eq!(t.limit, 2 * FIRST_LIMIT);
+        t.primes_below(100);
+        assert_eq!(t.limit, 2 * FIRST_LIMIT);
+    }
+
+    #[test]
+    fn test_is_prime() {
+        let mut t = PrimeTable::new();
+        let primes: Vec<u64> = (0..30).filter(|&n| t.is_prime(n)).collect();
+        assert_eq!(primes, vec![2, 3, 5, 7, 11, 13, 17, 19, 23, 29]);
+        assert!(t.is_prime(1_000_000_007));
+        assert!(!t.is_prime(1_000_000_007 * 3));
+        assert!(t.is_prime(18_446_744_073_709_551_557));
+        // A strong pseudoprime to the first few bases.
+        assert!(!t.is_prime(3_215_031_751));
+    }
+
+    #[test]
+    fn test_factor() {
+        let mut t = PrimeTable::new();
+        assert!(t.factor(1).is_empty());
+        assert_eq!(t.factor(360), vec![2, 2, 2, 3, 3, 5]);
+        assert_eq!(t.factor(1_000_000_007), vec![1_000_000_007]);
+        assert_eq!(t.factor(600_851_475_143), vec![71, 839, 1471, 6857]);
+        assert_eq!(t.factor(1_000_000_007 * 998_244_353), vec![998_244_353, 1_000_000_007]);
+        assert_eq!(t.totient(36), 12);
+        assert_eq!(t.totient(1), 1);
+    }
+
+    #[test]
+    fn test_modular() {
+        assert_eq!(gcd(12, 18), 6);
+        assert_eq!(gcd(0, 5), 5);
+        assert_eq!(mod_pow(4, 13, 497), 445);
+        assert_eq!(mod_pow(5, 0, 1), 0);
+        assert_eq!(mod_inv(3, 11), Some(4));
+        assert_eq!(mod_inv(-3, 11), Some(7));
+        assert_eq!(mod_inv(6, 9), None);
+        assert_eq!(mod_inv(5, 1), Some(0));
+    }
+}
@@ -1,12 +1,117 @@
+use std::cmp::Ordering;
+use std::convert::TryFrom;
+use std::f64::consts;
+use std::fmt;
+use std::fs::{self, OpenOptions};
 use std::result;
-use std::io;
-use rand;
+use std::io::{self, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::slice;
+use std::time::Duration;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 
-#[derive(Eq, PartialEq, Ord, PartialOrd, Debug)]
-/// An element of the stack. May be either integer or boolean.
+use bytecode::Dictionary;
+use complex::Complex;
+use console::{Console, StdConsole};
+use decimal::{Decimal, Rounding, MAX_SCALE};
+use matrix::Matrix;
+use parser::{get_value, OPERATIONS};
+use plot;
+use primes::{self, PrimeTable};
+use units::{Quantity, Unit};
+
+#[derive(PartialEq, PartialOrd, Debug, Clone)]
+/// An element of the stack. May be an integer, an exact decimal, a float,
+/// a complex number, a quantity with a unit, a matrix, a boolean or a
+/// string.
 pub enum Elt {
-    Int(i32),
+    Int(i64),
+    Decimal(Decimal),
+    Float(f64),
+    Complex(Complex),
+    Quantity(Quantity),
+    Matrix(Matrix),
     Bool(bool),
+    Str(String),
+}
+
+impl Elt {
+    /// The value of a real number as a float, `None` for a complex number,
+    /// a quantity, a matrix, a boolean or a string.
+    pub fn as_float(&self) -> Option<f64> {
+        match *self {
+            Elt::Int(i) => Some(i as f64),
+            Elt::Decimal(d) => Some(d.to_f64()),
+            Elt::Float(x) => Some(x),
+            Elt::Complex(_) | Elt::Quantity(_) | Elt::Matrix(_) | Elt::Bool(_) | Elt::Str(_) => None,
+        }
+    }
+
+    /// The value of an integer or a decimal as a decimal.
+    pub fn as_decimal(&self) -> Option<Decimal> {
+        match *self {
+            Elt::Int(i) => Some(Decimal::from_int(i)),
+            Elt::Decimal(d) => Some(d),
+            _ => None,
+        }
+    }
+
+    /// Compares two real numbers, or two quantities of the same dimension,
+    /// by value, `None` for other values and NaN. Integers and decimals
+    /// compare exactly.
+    pub fn compare(&self, other: &Elt) -> Option<Ordering> {
+        if let (Elt::Quantity(a), Elt::Quantity(b)) = (self, other) {
+            return a.compare(b);
+        }
+        match (self.as_decimal(), other.as_decimal()) {
+            (Some(a), Some(b)) => Some(a.cmp(&b)),
+            _ => self.as_float()?.partial_cmp(&other.as_float()?),
+        }
+    }
+
+    /// The value of a number as a complex number, `None` for a quantity, a
+    /// matrix, a boolean or a string.
+    pub fn as_complex(&self) -> Option<Complex> {
+        match *self {
+            Elt::Complex(z) => Some(z),
+            _ => self.as_float().map(|x| Complex::new(x, 0.0)),
+        }
+    }
+}
+
+impl fmt::Display for Elt {
+    /// Formats the element the way it is written in the input. Floats
+    /// always have an exponent, `1e0` rather than `1.0`, which would be
+    /// a decimal.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Elt::Int(i) => write!(f, "{}", i),
+            Elt::Decimal(d) => write!(f, "{}", d),
+            Elt::Float(x) => write!(f, "{:e}", x),
+            Elt::Complex(z) => write!(f, "{}", z),
+            Elt::Quantity(ref q) => write!(f, "{}", q),
+            Elt::Matrix(ref m) => write!(f, "{}", m),
+            Elt::Bool(b) => write!(f, "{}", b),
+            Elt::Str(ref s) => {
+                f.write_str("\"")?;
+                for c in s.chars() {
+                    match c {
+                        '"' => f.write_str("\\\"")?,
+                        '\\' => f.write_str("\\\\")?,
+                        '\n' => f.write_str("\\n")?,
+                        '\r' => f.write_str("\\r")?,
+                        '\t' => f.write_str("\\t")?,
+                        c => write!(f, "{}", c)?,
+                    }
+                }
+                f.write_str("\"")
+            },
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -18,194 +123,2421 @@ pub enum Error {
     Type,
     /// Unable to parse the input.
     Syntax,
-    /// Some IO error occurred.
-    IO(io::Error),
+    /// Read a variable that was never stored to.
+    Unset,
+    /// A result does not fit: an integer out of the range of `i64`, or
+    /// an infinite float.
+    Overflow,
+    /// A function was given a value it is not defined for, e.g. `sqrt` of
+    /// a negative number.
+    Domain,
+    /// Quantities of different dimensions were combined, e.g. metres
+    /// added to seconds, or a quantity and a plain number.
+    Units,
+    /// Matrices of sizes an operation does not allow, e.g. the product
+    /// of a 2x3 and a 2x3 matrix.
+    Shape,
+    /// Pushed more values than `Limits::depth` allows.
+    DepthLimit,
+    /// Ran more instructions than `Limits::steps` allows.
+    StepLimit,
+    /// A line of input or a string is longer than `Limits::size` allows.
+    SizeLimit,
+    /// Ran for longer than `Limits::time` allows.
+    TimeLimit,
+    /// Defined more words and variables than `Limits::words` allows.
+    WordLimit,
+    /// Made a matrix of more entries than `Limits::cells` allows.
+    CellLimit,
+    /// The operation is not in the `Limits::ops` allowlist, or does I/O
+    /// and `Limits::io` is off.
+    Denied(Op),
+    /// Some IO error occurred, on the file at the path when there is one.
+    IO(io::Error, Option<PathBuf>),
     /// The user quit the program (with `quit`).
     Quit,
+    /// A value was thrown with `throw`.
+    Thrown(Elt),
+    /// A file includes itself, directly or through other files.
+    Cycle(PathBuf),
+}
+
+impl Error {
+    /// The name of the kind of error, e.g. `"Underflow"`, for tools that
+    /// tell errors apart without parsing messages.
+    pub fn kind(&self) -> &'static str {
+        match *self {
+            Error::Underflow => "Underflow",
+            Error::Type => "Type",
+            Error::Syntax => "Syntax",
+            Error::Unset => "Unset",
+            Error::Overflow => "Overflow",
+            Error::Domain => "Domain",
+            Error::Units => "Units",
+            Error::Shape => "Shape",
+            Error::DepthLimit => "DepthLimit",
+            Error::StepLimit => "StepLimit",
+            Error::SizeLimit => "SizeLimit",
+            Error::TimeLimit => "TimeLimit",
+            Error::WordLimit => "WordLimit",
+            Error::CellLimit => "CellLimit",
+            Error::Denied(_) => "Denied",
+            Error::IO(..) => "IO",
+            Error::Quit => "Quit",
+            Error::Thrown(_) => "Thrown",
+            Error::Cycle(_) => "Cycle",
+        }
+    }
+
+    /// An I/O error that is not about a file, for `map_err`.
+    pub fn io(error: io::Error) -> Error {
+        Error::IO(error, None)
+    }
+
+    /// Makes I/O errors about the file at `path`, for `map_err`.
+    pub fn file<P: AsRef<Path>>(path: P) -> impl Fn(io::Error) -> Error {
+        let path = path.as_ref().to_path_buf();
+        move |error| Error::IO(error, Some(path.clone()))
+    }
+
+    /// Whether a `try` block catches the error. Quitting and running out
+    /// of steps or time end the whole run.
+    pub fn is_catchable(&self) -> bool {
+        !matches!(*self, Error::Quit | Error::StepLimit | Error::TimeLimit)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Types of RPN calculator operations.
+pub enum Op {
+    /// Adds two numbers: pop x, pop y, push x + y.
+    Add,
+    /// Multiplies two numbers: pop x, pop y, push x * y.
+    Mul,
+    /// Checks equality of two values: pop x, pop y, push x == y.
+    Eq,
+    /// Negates a value: pop x, push ~x.
+    Neg,
+    /// Swaps two values: pop x, pop y, push x, push y.
+    Swap,
+    /// Computes a random number: pop x, push random number in [0, x).
+    Rand,
+    /// Duplicates a value: pop x, push x, push x. In HP mode the next
+    /// value entered then replaces x instead of lifting the stack.
+    Enter,
+    /// Bitwise and of two integers, or logical and of two booleans:
+    /// pop x, pop y, push y & x.
+    And,
+    /// Bitwise or of two integers, or logical or of two booleans.
+    Or,
+    /// Bitwise exclusive or of two integers, or of two booleans.
+    Xor,
+    /// Flips every bit of the word of an integer, or negates a boolean.
+    Not,
+    /// Shifts left: pop n, pop x, push x shifted left by n bits within the
+    /// word. A negative n shifts right.
+    Shl,
+    /// Shifts right, filling with zeros: pop n, pop x, push x shifted
+    /// right by n bits within the word. A negative n shifts left.
+    Shr,
+    /// Rotates the word left: pop n, pop x, push x rotated left by n bits.
+    Rol,
+    /// Rotates the word right: pop n, pop x, push x rotated right by n bits.
+    Ror,
+    /// Raises a number to a power: pop x, pop y, push y to the x. Integer
+    /// powers of integers are integers.
+    Pow,
+    /// Square root: pop x, push √x.
+    Sqrt,
+    /// Exponential: pop x, push e to the x.
+    Exp,
+    /// Natural logarithm.
+    Ln,
+    /// Base 10 logarithm.
+    Log10,
+    /// Base 2 logarithm.
+    Log2,
+    /// Sine of an angle, in the unit of the stack's `Angle`.
+    Sin,
+    /// Cosine.
+    Cos,
+    /// Tangent.
+    Tan,
+    /// Arcsine, giving an angle in the unit of the stack's `Angle`.
+    Asin,
+    /// Arccosine.
+    Acos,
+    /// Arctangent.
+    Atan,
+    /// Hyperbolic sine.
+    Sinh,
+    /// Hyperbolic cosine.
+    Cosh,
+    /// Hyperbolic tangent.
+    Tanh,
+    /// Inverse hyperbolic sine.
+    Asinh,
+    /// Inverse hyperbolic cosine.
+    Acosh,
+    /// Inverse hyperbolic tangent.
+    Atanh,
+    /// Rounds down to a whole number. Integers are left as they are.
+    Floor,
+    /// Rounds up to a whole number.
+    Ceil,
+    /// Rounds to the nearest whole number, halves away from zero.
+    Round,
+    /// Complex conjugate. Real numbers are left as they are.
+    Conj,
+    /// Absolute value of a real number, or magnitude of a complex one.
+    Abs,
+    /// Angle of a complex number, in the unit of the stack's `Angle`.
+    Arg,
+    /// Converts to polar form: pop z, push its magnitude, push its angle.
+    Polar,
+    /// Converts from polar form: pop angle, pop magnitude, push the
+    /// complex number.
+    Rect,
+    /// Transposes a matrix.
+    Transpose,
+    /// Determinant of a square matrix.
+    Det,
+    /// Identity matrix: pop n, push the identity matrix of size n.
+    Identity,
+    /// Pushes every prime below n: pop n, push 2, 3, 5, ...
+    Primes,
+    /// Primality test: pop n, push whether n is prime.
+    IsPrime,
+    /// Prime factorization: pop n, push its prime factors, smallest first,
+    /// each as many times as it divides n.
+    Factor,
+    /// Greatest common divisor of two integers, never negative.
+    Gcd,
+    /// Least common multiple of two integers, never negative.
+    Lcm,
+    /// Modular power: pop m, pop e, pop b, push b to the e modulo m. A
+    /// negative e is a power of the inverse of b.
+    ModPow,
+    /// Modular inverse: pop m, pop a, push x with a * x = 1 modulo m.
+    ModInv,
+    /// Euler's totient: pop n, push how many numbers up to n are coprime
+    /// with it.
+    Totient,
+    /// Pushes π.
+    Pi,
+    /// Pushes e.
+    E,
+    /// Pushes the value the last operation consumed from X. Only HP mode
+    /// has a LastX register, on an unbounded stack this underflows.
+    LastX,
+    /// Fails with an error holding a value: pop x, raise x. A `try` block
+    /// catches it like any other error.
+    Throw,
+    /// Writes a value as it is displayed, and a newline: pop x.
+    Dot,
+    /// Writes a character: pop its code point.
+    Emit,
+    /// Writes the text of a string, or a value as it is displayed, with no
+    /// newline: pop x.
+    Print,
+    /// Reads a line from the console and pushes it as a string.
+    ReadLine,
+    /// Reads a line from the console and pushes the number written on it.
+    ReadNumber,
+    /// Reads a file: pop its path, push its contents as a string.
+    ReadFile,
+    /// Writes a file: pop its path, pop the contents, written like by
+    /// `print`. The file is created or truncated.
+    WriteFile,
+    /// Like `WriteFile`, adding to the end of the file.
+    AppendFile,
+    /// Draws a chart of points: pop a matrix of rows `x y`, as made by a
+    /// `sample` block.
+    Plot,
+    /// Writes points as a table of x and y: pop a matrix of rows `x y`.
+    Table,
+    /// Quit the calculator.
+    Quit,
+}
+
+impl Op {
+    /// Whether the operation uses the console or files.
+    pub fn is_io(self) -> bool {
+        matches!(self, Op::Dot | Op::Emit | Op::Print | Op::ReadLine | Op::ReadNumber |
+                       Op::ReadFile | Op::WriteFile | Op::AppendFile | Op::Plot | Op::Table)
+    }
+}
+
+/// The text of a string, for operations only defined on strings.
+fn string(e: Elt) -> Result<String> {
+    match e {
+        Elt::Str(s) => Ok(s),
+        _ => Err(Error::Type),
+    }
+}
+
+/// The text of a string, or a value as it is displayed.
+fn text(e: &Elt) -> String {
+    match *e {
+        Elt::Str(ref s) => s.clone(),
+        ref e => e.to_string(),
+    }
+}
+
+/// The value of an integer, for operations only defined on integers.
+fn int(e: Elt) -> Result<i64> {
+    match e {
+        Elt::Int(i) => Ok(i),
+        _ => Err(Error::Type),
+    }
+}
+
+/// The word sizes bitwise operations can work on, in bits.
+pub const WORD_SIZES: [u32; 4] = [8, 16, 32, 64];
+
+/// The bits of `v` that fit in a word of `bits` bits, as an unsigned
+/// number.
+pub fn to_word(v: i64, bits: u32) -> u64 {
+    if bits >= 64 {
+        v as u64
+    } else {
+        v as u64 & ((1 << bits) - 1)
+    }
+}
+
+/// A word of `bits` bits read as a two's complement signed number.
+pub fn from_word(w: u64, bits: u32) -> i64 {
+    let shift = 64 - bits.min(64);
+    ((w << shift) as i64) >> shift
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// The unit of the angles trigonometric functions take and give.
+pub enum Angle {
+    Rad,
+    Deg,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// How a stack holds its values.
+pub enum Mode {
+    /// Holds as many values as pushed; popping an empty stack underflows.
+    Unbounded,
+    /// The four X, Y, Z and T registers of a classic HP calculator.
+    Hp,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// Bounds on what evaluating a line may use, each one failing with its own
+/// `Error`. `None` means unbounded.
+pub struct Limits {
+    /// The most values an unbounded stack holds.
+    pub depth: Option<usize>,
+    /// The most instructions a line runs, word calls included.
+    pub steps: Option<u64>,
+    /// The longest line of input or string, in bytes.
+    pub size: Option<usize>,
+    /// The longest a line may run.
+    pub time: Option<Duration>,
+    /// The most words and variables defined, counting each redefinition.
+    pub words: Option<usize>,
+    /// The most entries a matrix holds.
+    pub cells: Option<usize>,
+    /// The only operations available, all of them when `None`.
+    pub ops: Option<Vec<Op>>,
+    /// Whether operations may use the console and files.
+    pub io: bool,
+}
+
+impl Default for Limits {
+    /// No bounds, and I/O allowed.
+    fn default() -> Limits {
+        Limits { depth: None, steps: None, size: None, time: None, words: None, cells: None, ops: None,
+                 io: true }
+    }
+}
+
+impl Limits {
+    /// Limits for evaluating untrusted input: every line ends quickly and
+    /// in bounded memory, with all operations but `quit` and I/O
+    /// available.
+    pub fn sandbox() -> Limits {
+        Limits {
+            depth: Some(10_000),
+            steps: Some(1_000_000),
+            size: Some(64 * 1024),
+            time: Some(Duration::from_secs(1)),
+            words: Some(1_000),
+            cells: Some(65_536),
+            ops: Some(OPERATIONS.iter()
+                          .map(|info| info.op)
+                          .filter(|&op| op != Op::Quit)
+                          .collect()),
+            io: false,
+        }
+    }
+
+    /// Whether `op` may be evaluated.
+    pub fn allows(&self, op: Op) -> bool {
+        (self.io || !op.is_io()) && self.ops.as_ref().is_none_or(|ops| ops.contains(&op))
+    }
+
+    /// Checks that a line of input is not too long to compile, or a string
+    /// to keep.
+    pub fn check_size(&self, buf: &str) -> Result<()> {
+        match self.size {
+            Some(max) if buf.len() > max => Err(Error::SizeLimit),
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks that a matrix of `height` rows and `width` columns is not
+    /// too big to make, before making it.
+    pub fn check_cells(&self, height: usize, width: usize) -> Result<()> {
+        match self.cells {
+            Some(max) if height.checked_mul(width).is_none_or(|n| n > max) => Err(Error::CellLimit),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// the stack data struct
+pub struct Stack {
+    data: Storage,
+    words: Dictionary,
+    limits: Limits,
+    /// The LastX register of HP mode, for `Op::LastX`.
+    last_x: Option<Elt>,
+    /// The generator behind `Op::Rand`, seeded from the OS on first use
+    /// unless seeded explicitly.
+    rng: Option<StdRng>,
+    /// The word size of bitwise operations, one of `WORD_SIZES`.
+    word_size: u32,
+    angle: Angle,
+    /// The most digits after the point decimal products keep, and how they
+    /// lose the others.
+    scale: u32,
+    rounding: Rounding,
+    /// Whether float operations follow IEEE 754, giving NaN or an infinity
+    /// rather than failing with `Error::Domain` or `Error::Overflow`.
+    ieee: bool,
+    /// The primes found so far, shared with the `scratch` stacks.
+    primes: Rc<RefCell<PrimeTable>>,
+    /// Where console operations write and read.
+    console: Box<dyn Console>,
+    /// The directories `include` searches, see `bytecode::Loader`.
+    include_path: Vec<PathBuf>,
+}
+
+#[derive(Clone)]
+/// The values of a stack, in the representation of its `Mode`.
+enum Storage {
+    List(List),
+    Registers(Registers),
+}
+
+impl Storage {
+    fn push(&mut self, elt: Elt) {
+        match *self {
+            Storage::List(ref mut l) => l.push(elt),
+            Storage::Registers(ref mut r) => r.push(elt),
+        }
+    }
+
+    fn pop(&mut self) -> Option<Elt> {
+        match *self {
+            Storage::List(ref mut l) => l.pop(),
+            Storage::Registers(ref mut r) => Some(r.pop()),
+        }
+    }
+
+    fn same_as(&self, other: &Storage) -> bool {
+        match (self, other) {
+            (Storage::List(a), Storage::List(b)) => a.same_as(b),
+            (Storage::Registers(a), Storage::Registers(b)) => {
+                a.regs == b.regs && a.lift == b.lift
+            },
+            _ => false,
+        }
+    }
+}
+
+#[derive(Clone)]
+/// The registers of an HP-style stack: X (the top), Y, Z and T.
+///
+/// Pushing lifts the stack: T is lost and X, Y and Z move up one level.
+/// Popping drops it: Y, Z and T move down one level and T keeps its
+/// value, so the stack never underflows.
+struct Registers {
+    regs: [Elt; 4],
+    /// Whether entering a value lifts the stack, or replaces X after ENTER.
+    lift: bool,
+}
+
+impl Registers {
+    fn new() -> Registers {
+        Registers {
+            regs: [Elt::Int(0), Elt::Int(0), Elt::Int(0), Elt::Int(0)],
+            lift: true,
+        }
+    }
+
+    fn push(&mut self, elt: Elt) {
+        if self.lift {
+            self.regs.rotate_right(1);
+        }
+        self.regs[0] = elt;
+        self.lift = true;
+    }
+
+    fn pop(&mut self) -> Elt {
+        // X moves to the T slot, to be swapped for the copy of T.
+        self.regs.rotate_left(1);
+        let t = self.regs[2].clone();
+        mem::replace(&mut self.regs[3], t)
+    }
+}
+
+/// A node of the list holding the stack values.
+struct Node {
+    elt: Elt,
+    next: Option<Rc<Node>>,
+}
+
+#[derive(Clone, Default)]
+/// The stack values, as a persistent linked list with the top first.
+///
+/// Cloning the list only clones the pointer to its top, and pushing or
+/// popping only touches the top nodes: lists cloned from each other share
+/// every node below the values they pushed or popped since.
+struct List {
+    top: Option<Rc<Node>>,
+    len: usize,
+}
+
+impl List {
+    fn push(&mut self, elt: Elt) {
+        let next = self.top.take();
+        self.top = Some(Rc::new(Node { elt, next }));
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<Elt> {
+        self.top.take().map(|node| {
+            self.len -= 1;
+            match Rc::try_unwrap(node) {
+                Ok(node) => {
+                    self.top = node.next;
+                    node.elt
+                },
+                // Still part of another list: leave the node to it.
+                Err(node) => {
+                    self.top = node.next.clone();
+                    node.elt.clone()
+                },
+            }
+        })
+    }
+
+    fn same_as(&self, other: &List) -> bool {
+        match (&self.top, &other.top) {
+            (Some(a), Some(b)) => Rc::ptr_eq(a, b),
+            (None, None) => true,
+            _ => false,
+        }
+    }
+}
+
+impl Drop for List {
+    /// Drops the nodes in a loop: the default recursive drop could
+    /// overflow the call stack on a long list.
+    fn drop(&mut self) {
+        let mut top = self.top.take();
+        while let Some(node) = top {
+            top = match Rc::try_unwrap(node) {
+                Ok(mut node) => node.next.take(),
+                Err(_) => None,
+            };
+        }
+    }
+}
+
+#[derive(Clone)]
+/// The values of a stack at some point, to go back to with `Stack::restore`.
+///
+/// Taking a snapshot does not copy the values, they are shared with the
+/// stack.
+pub struct Snapshot {
+    data: Storage,
+}
+
+impl Default for Stack {
+    fn default() -> Stack {
+        Stack::new()
+    }
+}
+
+/// Iterator over the values of a `Stack`, from the top down.
+pub struct Iter<'a> {
+    inner: IterInner<'a>,
+}
+
+enum IterInner<'a> {
+    List(Option<&'a Node>),
+    Registers(slice::Iter<'a, Elt>),
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = &'a Elt;
+
+    fn next(&mut self) -> Option<&'a Elt> {
+        match self.inner {
+            IterInner::List(ref mut node) => node.map(|n| {
+                *node = n.next.as_deref();
+                &n.elt
+            }),
+            IterInner::Registers(ref mut regs) => regs.next(),
+        }
+    }
 }
 
-#[derive(Debug)]
-/// Types of RPN calculator operations.
-pub enum Op {
-    /// Adds two numbers: pop x, pop y, push x + y.
-    Add,
-    /// Checks equality of two values: pop x, pop y, push x == y.
-    Eq,
-    /// Negates a value: pop x, push ~x.
-    Neg,
-    /// Swaps two values: pop x, pop y, push x, push y.
-    Swap,
-    /// Computes a random number: pop x, push random number in [0, x).
-    Rand,
-    /// Quit the calculator.
-    Quit,
-}
+/// Result alias for std::result::Result<T, Error>
+pub type Result<T> = result::Result<T, Error>;
+
+impl Stack {
+    /// Creates a new Stack
+    pub fn new() -> Stack {
+        Stack::with_mode(Mode::Unbounded)
+    }
+
+    /// Creates an empty stack holding its values as `mode` says.
+    pub fn with_mode(mode: Mode) -> Stack {
+        let mut stack = Stack {
+            data: Storage::List(List::default()),
+            words: Dictionary::new(),
+            limits: Limits::default(),
+            last_x: None,
+            rng: None,
+            primes: Rc::new(RefCell::new(PrimeTable::new())),
+            console: Box::new(StdConsole),
+            include_path: Vec::new(),
+            word_size: 64,
+            angle: Angle::Rad,
+            scale: 6,
+            rounding: Rounding::HalfEven,
+            ieee: false,
+        };
+        stack.set_mode(mode);
+        stack
+    }
+
+    /// How the stack holds its values.
+    pub fn mode(&self) -> Mode {
+        match self.data {
+            Storage::List(_) => Mode::Unbounded,
+            Storage::Registers(_) => Mode::Hp,
+        }
+    }
+
+    /// Switches to another mode, keeping the values on top. Going to HP
+    /// mode keeps the top four values (missing ones become 0); leaving it
+    /// keeps all four registers.
+    pub fn set_mode(&mut self, mode: Mode) {
+        if mode == self.mode() {
+            return;
+        }
+
+        let mut values: Vec<Elt> = self.iter().take(4).cloned().collect();
+        values.reverse();
+        self.data = match mode {
+            Mode::Unbounded => {
+                self.last_x = None;
+                Storage::List(List::default())
+            },
+            Mode::Hp => {
+                self.last_x = Some(Elt::Int(0));
+                Storage::Registers(Registers::new())
+            },
+        };
+        for v in values {
+            self.data.push(v);
+        }
+    }
+
+    /// The user words defined on this stack.
+    pub fn words(&self) -> &Dictionary {
+        &self.words
+    }
+
+    /// Mutable access to the user words, used when compiling definitions.
+    pub fn words_mut(&mut self) -> &mut Dictionary {
+        &mut self.words
+    }
+
+    /// What evaluating on this stack may use.
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    /// Makes console operations write and read `console` rather than
+    /// the standard output and input.
+    pub fn set_console(&mut self, console: Box<dyn Console>) {
+        self.console = console;
+    }
+
+    /// The console operations write and read.
+    pub fn console(&mut self) -> &mut dyn Console {
+        &mut *self.console
+    }
+
+    /// The directories `include` searches.
+    pub fn include_path(&self) -> &[PathBuf] {
+        &self.include_path
+    }
+
+    pub fn set_include_path(&mut self, path: Vec<PathBuf>) {
+        self.include_path = path;
+    }
+
+    /// Changes the limits. Values already over the depth limit stay.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.words.set_max(limits.words);
+        self.limits = limits;
+    }
+
+    /// Pushes a value onto the stack, within the depth and cell limits.
+    pub fn push(&mut self, val: Elt) -> Result<()> {
+        if let Storage::List(ref l) = self.data {
+            if self.limits.depth.is_some_and(|max| l.len >= max) {
+                return Err(Error::DepthLimit);
+            }
+        }
+        if let Elt::Matrix(ref m) = val {
+            self.limits.check_cells(m.height(), m.width())?;
+        }
+        self.data.push(val);
+        Ok(())
+    }
+
+    /// The number of values on the stack.
+    pub fn depth(&self) -> usize {
+        match self.data {
+            Storage::List(ref l) => l.len,
+            Storage::Registers(ref r) => r.regs.len(),
+        }
+    }
+
+    /// Iterates over the values on the stack, from the top down.
+    pub fn iter(&self) -> Iter<'_> {
+        let inner = match self.data {
+            Storage::List(ref l) => IterInner::List(l.top.as_deref()),
+            Storage::Registers(ref r) => IterInner::Registers(r.regs.iter()),
+        };
+        Iter { inner }
+    }
+
+    /// The number of bits bitwise operations work on.
+    pub fn word_size(&self) -> u32 {
+        self.word_size
+    }
+
+    /// Sets the word size of bitwise operations, one of `WORD_SIZES`.
+    /// Values on the stack are left as they are.
+    pub fn set_word_size(&mut self, bits: u32) -> Result<()> {
+        if !WORD_SIZES.contains(&bits) {
+            return Err(Error::Syntax);
+        }
+        self.word_size = bits;
+        Ok(())
+    }
+
+    /// The unit of angles.
+    pub fn angle(&self) -> Angle {
+        self.angle
+    }
+
+    pub fn set_angle(&mut self, angle: Angle) {
+        self.angle = angle;
+    }
+
+    /// The most digits after the point decimal products keep.
+    pub fn scale(&self) -> u32 {
+        self.scale
+    }
+
+    /// Sets the scale of decimal products, from 1 to `MAX_SCALE`: decimals
+    /// keep at least one digit after the point, so they do not read back
+    /// as integers.
+    pub fn set_scale(&mut self, scale: u32) -> Result<()> {
+        if scale == 0 || scale > MAX_SCALE {
+            return Err(Error::Syntax);
+        }
+        self.scale = scale;
+        Ok(())
+    }
+
+    /// How decimal products are rounded to the scale.
+    pub fn rounding(&self) -> Rounding {
+        self.rounding
+    }
+
+    pub fn set_rounding(&mut self, rounding: Rounding) {
+        self.rounding = rounding;
+    }
+
+    /// Whether float operations follow IEEE 754 rather than failing.
+    pub fn ieee(&self) -> bool {
+        self.ieee
+    }
+
+    pub fn set_ieee(&mut self, ieee: bool) {
+        self.ieee = ieee;
+    }
+
+    /// Empties the stack and forgets its words, variables and random
+    /// numbers. Everything else is kept: the mode and the other settings,
+    /// the limits, the include path and the console.
+    pub fn reset(&mut self) {
+        let old = mem::take(self);
+        let mut words = Dictionary::new();
+        words.set_max(old.limits.words);
+        *self = Stack {
+            data: Stack::with_mode(old.mode()).data,
+            words,
+            last_x: None,
+            rng: None,
+            ..old
+        };
+    }
+
+    /// An empty stack evaluating operations like this one: with the same
+    /// word size, angle unit, decimal scale and rounding, float semantics
+    /// and limits. Used to evaluate ahead of time.
+    pub fn scratch(&self) -> Stack {
+        let mut stack = Stack::new();
+        stack.word_size = self.word_size;
+        stack.angle = self.angle;
+        stack.scale = self.scale;
+        stack.rounding = self.rounding;
+        stack.ieee = self.ieee;
+        stack.primes = self.primes.clone();
+        stack.limits = self.limits.clone();
+        stack
+    }
+
+    /// Seeds the random number generator, so `Op::Rand` draws the same
+    /// numbers every time.
+    pub fn seed(&mut self, seed: u64) {
+        self.rng = Some(StdRng::seed_from_u64(seed));
+    }
+
+    /// Draws a new seed and reseeds the generator with it. A stack seeded
+    /// with the returned value draws the same numbers as this one from
+    /// now on.
+    pub fn reseed(&mut self) -> u64 {
+        let seed = self.rng().gen();
+        self.seed(seed);
+        seed
+    }
+
+    fn rng(&mut self) -> &mut StdRng {
+        self.rng.get_or_insert_with(StdRng::from_entropy)
+    }
+
+    /// Takes a snapshot of the values on the stack.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot { data: self.data.clone() }
+    }
+
+    /// Puts the stack values back to what they were at `snapshot`.
+    pub fn restore(&mut self, snapshot: Snapshot) {
+        self.data = snapshot.data;
+    }
+
+    /// Whether the stack values are still the ones of `snapshot`.
+    pub fn is_at(&self, snapshot: &Snapshot) -> bool {
+        self.data.same_as(&snapshot.data)
+    }
+
+    /// Gives the number on top a unit: pop x, push x `unit`. A quantity
+    /// is multiplied by the unit, `3 kg m` is `3 kg*m`.
+    pub fn apply_unit(&mut self, unit: &Unit) -> Result<()> {
+        self.replace_x(|s, x| s.with_unit(x, unit))
+    }
+
+    /// Converts the quantity on top to another unit of the same dimension.
+    pub fn convert(&mut self, unit: &Unit) -> Result<()> {
+        self.replace_x(|s, x| match x {
+            Elt::Quantity(q) => s.quantity(q.to(unit).ok_or(Error::Units)?),
+            Elt::Complex(_) | Elt::Bool(_) => Err(Error::Type),
+            _ => Err(Error::Units),
+        })
+    }
+
+    /// Replaces the top value by `f` of it, as an operation would: in HP
+    /// mode this re-enables the stack lift and sets LastX.
+    fn replace_x<F: FnOnce(&Stack, Elt) -> Result<Elt>>(&mut self, f: F) -> Result<()> {
+        if let Storage::Registers(ref mut r) = self.data {
+            r.lift = true;
+        }
+        let x = self.pop()?;
+        let r = f(self, x.clone())?;
+        if self.mode() == Mode::Hp {
+            self.last_x = Some(x);
+        }
+        self.push(r)
+    }
+
+    /// Tries to pop a value off of the stack.
+    pub fn pop(&mut self) -> Result<Elt> {
+        self.data.pop().ok_or(Error::Underflow)
+    }
+
+    /// Tries to evaluate an operator using values on the stack.
+    pub fn eval(&mut self, op: Op) -> Result<()> {
+        if !self.limits.allows(op) {
+            return Err(Error::Denied(op));
+        }
+
+        let x = match self.data {
+            Storage::List(_) => return self.apply(op),
+            Storage::Registers(ref mut r) => {
+                // Any operation re-enables the stack lift ENTER disabled.
+                r.lift = true;
+                r.regs[0].clone()
+            },
+        };
+
+        let result = self.apply(op);
+        if result.is_ok() {
+            match op {
+                Op::Swap | Op::Enter | Op::Pi | Op::E | Op::LastX | Op::Quit => {},
+                _ => self.last_x = Some(x),
+            }
+        }
+        result
+    }
+
+    fn apply(&mut self, op: Op) -> Result<()> {
+        match op {
+            Op::Add => {
+                let a = self.pop();
+                let b = self.pop();
+        
+                a.and_then(|a| b.and_then(|b| self.add(a, b)))
+                 .and_then(|r| self.push(r))
+            },
+            Op::Mul => {
+                let a = self.pop();
+                let b = self.pop();
+
+                a.and_then(|a| b.and_then(|b| self.mul(a, b)))
+                 .and_then(|r| self.push(r))
+            },
+            Op::Neg => {
+                // how to do this by replacing the last value of the vector?
+                self.pop().and_then(|e| self.neg(e)).and_then(|r| self.push(r))
+            },
+            Op::Eq => {
+                let a = self.pop();
+                let b = self.pop();
+
+                a.and_then(|a| b.and_then(|b| self.eq(a, b)))
+                 .and_then(|r| self.push(r))
+            },
+            Op::Swap => {
+                let a = self.pop();
+                let b = self.pop();
+
+                a.and_then(|a| b.and_then(|b| {
+                    self.push(a).and_then(|_|self.push(b))
+                }))
+            },
+            Op::And | Op::Or | Op::Xor | Op::Shl | Op::Shr | Op::Rol | Op::Ror => {
+                let a = self.pop();
+                let b = self.pop();
+
+                a.and_then(|a| b.and_then(|b| self.bitwise(op, b, a)))
+                 .and_then(|r| self.push(r))
+            },
+            Op::Not => {
+                self.pop().and_then(|e| self.not(e)).and_then(|r| self.push(r))
+            },
+            Op::Pow => {
+                let a = self.pop();
+                let b = self.pop();
+
+                a.and_then(|a| b.and_then(|b| self.pow(b, a)))
+                 .and_then(|r| self.push(r))
+            },
+            Op::Sqrt | Op::Exp | Op::Ln | Op::Log10 | Op::Log2 |
+            Op::Sin | Op::Cos | Op::Tan | Op::Asin | Op::Acos | Op::Atan |
+            Op::Sinh | Op::Cosh | Op::Tanh | Op::Asinh | Op::Acosh | Op::Atanh |
+            Op::Floor | Op::Ceil | Op::Round | Op::Conj | Op::Abs | Op::Arg => {
+                self.pop().and_then(|e| self.function(op, e)).and_then(|r| self.push(r))
+            },
+            Op::Polar => {
+                let z = self.pop()?.as_complex().ok_or(Error::Type)?;
+                self.push(Elt::Float(z.abs()))?;
+                self.push(Elt::Float(self.angle_unit(z.arg())))
+            },
+            Op::Rect => {
+                let a = self.pop();
+                let b = self.pop();
+
+                a.and_then(|a| b.and_then(|b| self.rect(b, a)))
+                 .and_then(|r| self.push(r))
+            },
+            Op::Transpose | Op::Det | Op::Identity => {
+                self.pop().and_then(|e| self.matrix_function(op, e)).and_then(|r| self.push(r))
+            },
+            Op::Primes | Op::IsPrime | Op::Factor | Op::Totient => {
+                let n = self.pop().and_then(int)?;
+                for r in self.number_function(op, n)? {
+                    self.push(r)?;
+                }
+                Ok(())
+            },
+            Op::Gcd | Op::Lcm | Op::ModInv => {
+                let a = self.pop();
+                let b = self.pop();
+
+                a.and_then(|a| b.and_then(|b| self.integers(op, int(b)?, int(a)?)))
+                 .and_then(|r| self.push(r))
+            },
+            Op::ModPow => {
+                let m = self.pop();
+                let e = self.pop();
+                let b = self.pop();
+
+                m.and_then(|m| e.and_then(|e| b.and_then(|b| self.mod_pow(int(b)?, int(e)?, int(m)?))))
+                 .and_then(|r| self.push(r))
+            },
+            Op::Pi => self.push(Elt::Float(consts::PI)),
+            Op::E => self.push(Elt::Float(consts::E)),
+            Op::Rand => {
+                let a = self.pop();
+
+                a.and_then(|a| self.rand(a))
+                 .and_then(|r| self.push(r))
+            },
+            Op::Enter => {
+                let x = self.iter().next().cloned().ok_or(Error::Underflow)?;
+                self.push(x)?;
+                if let Storage::Registers(ref mut r) = self.data {
+                    r.lift = false;
+                }
+                Ok(())
+            },
+            Op::LastX => {
+                let x = self.last_x.clone().ok_or(Error::Underflow)?;
+                self.push(x)
+            },
+            Op::Throw => Err(Error::Thrown(self.pop()?)),
+            Op::Dot | Op::Emit | Op::Print | Op::ReadLine | Op::ReadNumber |
+            Op::ReadFile | Op::WriteFile | Op::AppendFile | Op::Plot | Op::Table => self.io(op),
+            Op::Quit => Err(Error::Quit),
+        }
+    }
+
+    /// Evaluates an operation using the console or files.
+    fn io(&mut self, op: Op) -> Result<()> {
+        match op {
+            Op::Dot => {
+                let x = self.pop()?;
+                self.console.write(&format!("{}\n", x)).map_err(Error::io)
+            },
+            Op::Emit => {
+                let code = u32::try_from(self.pop().and_then(int)?).map_err(|_| Error::Domain)?;
+                let c = char::from_u32(code).ok_or(Error::Domain)?;
+                self.console.write(c.encode_utf8(&mut [0; 4])).map_err(Error::io)
+            },
+            Op::Print => {
+                let x = self.pop()?;
+                self.console.write(&text(&x)).map_err(Error::io)
+            },
+            Op::ReadLine | Op::ReadNumber => {
+                let line = self.console.read_line().map_err(Error::io)?
+                    .ok_or_else(|| Error::io(io::ErrorKind::UnexpectedEof.into()))?;
+                self.limits.check_size(&line)?;
+                if op == Op::ReadLine {
+                    return self.push(Elt::Str(line));
+                }
+                match get_value(line.trim())? {
+                    Elt::Bool(_) | Elt::Matrix(_) | Elt::Str(_) => Err(Error::Syntax),
+                    x => self.push(x),
+                }
+            },
+            Op::ReadFile => {
+                let path = self.pop().and_then(string)?;
+                if let Some(max) = self.limits.size {
+                    let len = fs::metadata(&path).map_err(Error::file(&path))?.len();
+                    if len > max as u64 {
+                        return Err(Error::SizeLimit);
+                    }
+                }
+                let contents = fs::read_to_string(&path).map_err(Error::file(&path))?;
+                self.push(Elt::Str(contents))
+            },
+            Op::Plot | Op::Table => {
+                let points = match self.pop()? {
+                    Elt::Matrix(m) => m,
+                    _ => return Err(Error::Type),
+                };
+                if points.width() != 2 {
+                    return Err(Error::Shape);
+                }
+                let points: Vec<(f32, f32)> = points.rows().iter().map(|row| (row[0], row[1])).collect();
+                let text = if op == Op::Plot {
+                    plot::chart(&points, plot::WIDTH, plot::HEIGHT)
+                } else {
+                    plot::table(&points)
+                };
+                self.console.write(&text).map_err(Error::io)
+            },
+            // `WriteFile` and `AppendFile`.
+            _ => {
+                let path = self.pop();
+                let contents = self.pop();
+                let path = path.and_then(string)?;
+                let contents = text(&contents?);
+                let mut options = OpenOptions::new();
+                options.create(true);
+                if op == Op::AppendFile {
+                    options.append(true);
+                } else {
+                    options.write(true).truncate(true);
+                }
+                options.open(&path)
+                    .and_then(|mut file| file.write_all(contents.as_bytes()))
+                    .map_err(Error::file(&path))
+            },
+        }
+    }
+
+    /// A float result, unless it is NaN or infinite and the stack does
+    /// not follow IEEE 754.
+    fn float(&self, x: f64) -> Result<Elt> {
+        if self.ieee || x.is_finite() {
+            Ok(Elt::Float(x))
+        } else if x.is_nan() {
+            Err(Error::Domain)
+        } else {
+            Err(Error::Overflow)
+        }
+    }
+
+    /// A complex result, checked like `float` part by part.
+    fn complex(&self, z: Complex) -> Result<Elt> {
+        self.float(z.re)?;
+        self.float(z.im)?;
+        Ok(Elt::Complex(z))
+    }
+
+    /// A quantity result, checked like `float`. A quantity whose units
+    /// cancelled out is a plain float.
+    fn quantity(&self, q: Quantity) -> Result<Elt> {
+        if q.is_dimensionless() {
+            return self.float(q.si_value());
+        }
+        self.float(q.value())?;
+        Ok(Elt::Quantity(q))
+    }
+
+    /// A matrix result, checked like `float` entry by entry.
+    fn matrix(&self, m: Matrix) -> Result<Elt> {
+        if self.ieee || m.is_finite() {
+            Ok(Elt::Matrix(m))
+        } else if m.has_nan() {
+            Err(Error::Domain)
+        } else {
+            Err(Error::Overflow)
+        }
+    }
+
+    /// The operands of an operation on quantities, if one of `a` and `b`
+    /// is a quantity: the other one must be a quantity too.
+    fn quantities(&self, a: &Elt, b: &Elt) -> Option<Result<(Quantity, Quantity)>> {
+        match (a, b) {
+            (Elt::Quantity(x), Elt::Quantity(y)) => Some(Ok((x.clone(), y.clone()))),
+            (&Elt::Quantity(_), other) | (other, &Elt::Quantity(_)) => {
+                Some(Err(if other.as_float().is_some() { Error::Units } else { Error::Type }))
+            },
+            _ => None,
+        }
+    }
+
+    /// Gives `e` the unit `unit`.
+    fn with_unit(&self, e: Elt, unit: &Unit) -> Result<Elt> {
+        if let Elt::Quantity(q) = e {
+            return self.quantity(q.product(&Quantity::new(1.0, unit.clone())).ok_or(Error::Units)?);
+        }
+        let x = e.as_float().ok_or(Error::Type)?;
+        self.quantity(Quantity::new(x, unit.clone()))
+    }
+
+    /// The operands of a decimal operation, if both `a` and `b` are
+    /// integers or decimals.
+    fn decimals(&self, a: &Elt, b: &Elt) -> Option<(Decimal, Decimal)> {
+        Some((a.as_decimal()?, b.as_decimal()?))
+    }
+
+    /// The operands of a float operation: both real numbers.
+    fn floats(&self, a: &Elt, b: &Elt) -> Result<(f64, f64)> {
+        match (a.as_float(), b.as_float()) {
+            (Some(x), Some(y)) => Ok((x, y)),
+            _ => Err(Error::Type),
+        }
+    }
+
+    /// The operands of a complex operation, if one of `a` and `b` is a
+    /// complex number: the other one is promoted.
+    fn complexes(&self, a: &Elt, b: &Elt) -> Option<Result<(Complex, Complex)>> {
+        match (a, b) {
+            (&Elt::Complex(_), _) | (_, &Elt::Complex(_)) => {
+                Some(match (a.as_complex(), b.as_complex()) {
+                    (Some(x), Some(y)) => Ok((x, y)),
+                    _ => Err(Error::Type),
+                })
+            },
+            _ => None,
+        }
+    }
+
+    /// An angle in the stack's unit, in radians.
+    fn radians(&self, angle: f64) -> f64 {
+        match self.angle {
+            Angle::Rad => angle,
+            Angle::Deg => angle.to_radians(),
+        }
+    }
+
+    /// An angle in radians, in the stack's unit.
+    fn angle_unit(&self, angle: f64) -> f64 {
+        match self.angle {
+            Angle::Rad => angle,
+            Angle::Deg => angle.to_degrees(),
+        }
+    }
+
+    /// Quantities add up in the unit of `b`, pushed first. Adding strings
+    /// joins them, `b` first.
+    fn add(&self, a: Elt, b: Elt) -> Result<Elt> {
+        if let (Elt::Str(x), Elt::Str(y)) = (&a, &b) {
+            let joined = format!("{}{}", y, x);
+            self.limits.check_size(&joined)?;
+            return Ok(Elt::Str(joined));
+        }
+        if let (Elt::Matrix(x), Elt::Matrix(y)) = (&a, &b) {
+            return self.matrix(y.checked_add(x).ok_or(Error::Shape)?);
+        }
+        if let Some(qs) = self.quantities(&a, &b) {
+            let (x, y) = qs?;
+            return self.quantity(y.checked_add(&x).ok_or(Error::Units)?);
+        }
+        if let Elt::Int(x) = a {
+            if let Elt::Int(y) = b {
+                return x.checked_add(y).map(Elt::Int).ok_or(Error::Overflow);
+            }
+        }
+        if let Some(zs) = self.complexes(&a, &b) {
+            let (x, y) = zs?;
+            return self.complex(x + y);
+        }
+        if let Some((x, y)) = self.decimals(&a, &b) {
+            return x.checked_add(y).map(Elt::Decimal).ok_or(Error::Overflow);
+        }
+
+        let (x, y) = self.floats(&a, &b)?;
+        self.float(x + y)
+    }
+
+    /// Products of decimals are rounded to the scale of the stack. A
+    /// quantity times a number keeps its unit. Matrices multiply `b`,
+    /// pushed first, by `a`.
+    fn mul(&self, a: Elt, b: Elt) -> Result<Elt> {
+        match (&a, &b) {
+            (Elt::Matrix(x), Elt::Matrix(y)) => {
+                self.limits.check_cells(y.height(), x.width())?;
+                return self.matrix(y.checked_mul(x).ok_or(Error::Shape)?);
+            },
+            (Elt::Matrix(m), n) | (n, Elt::Matrix(m)) => {
+                let n = n.as_float().ok_or(Error::Type)? as f32;
+                return self.matrix(m.map(|x| x * n));
+            },
+            (Elt::Quantity(x), Elt::Quantity(y)) => {
+                return self.quantity(y.product(x).ok_or(Error::Units)?);
+            },
+            (&Elt::Quantity(ref q), n) | (n, &Elt::Quantity(ref q)) => {
+                let n = n.as_float().ok_or(Error::Type)?;
+                return self.quantity(q.map(|v| v * n));
+            },
+            _ => {},
+        }
+        if let Some(zs) = self.complexes(&a, &b) {
+            let (x, y) = zs?;
+            return self.complex(x * y);
+        }
+        if let (&Elt::Int(x), &Elt::Int(y)) = (&a, &b) {
+            return x.checked_mul(y).map(Elt::Int).ok_or(Error::Overflow);
+        }
+        if let Some((x, y)) = self.decimals(&a, &b) {
+            return x.checked_mul(y)
+                .map(|d| Elt::Decimal(d.round(self.scale, self.rounding)))
+                .ok_or(Error::Overflow);
+        }
+
+        let (x, y) = self.floats(&a, &b)?;
+        self.float(x * y)
+    }
+
+    fn neg(&self, e: Elt) -> Result<Elt> {
+        match e {
+            Elt::Int(i) => i.checked_neg().map(Elt::Int).ok_or(Error::Overflow),
+            Elt::Decimal(d) => d.checked_neg().map(Elt::Decimal).ok_or(Error::Overflow),
+            Elt::Float(x) => Ok(Elt::Float(-x)),
+            Elt::Complex(z) => Ok(Elt::Complex(-z)),
+            Elt::Quantity(q) => Ok(Elt::Quantity(q.map(|v| -v))),
+            Elt::Matrix(m) => Ok(Elt::Matrix(m.map(|x| -x))),
+            Elt::Bool(b) => Ok(Elt::Bool(!b)),
+            Elt::Str(_) => Err(Error::Type),
+        }
+    }
+
+    /// Numbers are equal when they have the same value, `1 1.0 =` is true,
+    /// and so are quantities, `1 km 1000 m =`. Strings are only equal to
+    /// strings.
+    fn eq(&self, a: Elt, b: Elt) -> Result<Elt> {
+        if let Some(qs) = self.quantities(&a, &b) {
+            let (x, y) = qs?;
+            if !x.same_dim(&y) {
+                return Err(Error::Units);
+            }
+            return Ok(Elt::Bool(x.compare(&y) == Some(Ordering::Equal)));
+        }
+        if let Some(zs) = self.complexes(&a, &b) {
+            let (x, y) = zs?;
+            return Ok(Elt::Bool(x == y));
+        }
+        match (a, b) {
+            (Elt::Bool(ab), Elt::Bool(bb)) => Ok(Elt::Bool(ab == bb)),
+            (Elt::Matrix(x), Elt::Matrix(y)) => Ok(Elt::Bool(x == y)),
+            (Elt::Str(x), Elt::Str(y)) => Ok(Elt::Bool(x == y)),
+            (Elt::Bool(_), _) | (_, Elt::Bool(_)) => Err(Error::Type),
+            (Elt::Matrix(_), _) | (_, Elt::Matrix(_)) => Err(Error::Type),
+            (Elt::Str(_), _) | (_, Elt::Str(_)) => Err(Error::Type),
+            (a, b) => Ok(Elt::Bool(a.compare(&b) == Some(Ordering::Equal))),
+        }
+    }
+
+    /// Raises `y` (pushed first) to the power `x`. Quantities only have
+    /// integer powers.
+    fn pow(&self, y: Elt, x: Elt) -> Result<Elt> {
+        match (&y, &x) {
+            (Elt::Quantity(b), &Elt::Int(e)) => {
+                let r = i8::try_from(e).ok().and_then(|e| b.powi(e));
+                return self.quantity(r.ok_or(Error::Units)?);
+            },
+            (_, &Elt::Bool(_)) | (&Elt::Bool(_), _) => {},
+            (&Elt::Quantity(_), _) | (_, &Elt::Quantity(_)) => return Err(Error::Units),
+            _ => {},
+        }
+        if let Some(zs) = self.complexes(&y, &x) {
+            let (b, e) = zs?;
+            if !b.is_zero() {
+                return self.complex(b.pow(e));
+            }
+            return if e.is_zero() {
+                Ok(Elt::Complex(Complex::new(1.0, 0.0)))
+            } else if e.re > 0.0 {
+                Ok(Elt::Complex(Complex::new(0.0, 0.0)))
+            } else if self.ieee {
+                Ok(Elt::Complex(Complex::new(f64::INFINITY, 0.0)))
+            } else {
+                Err(Error::Domain)
+            };
+        }
+
+        if let (&Elt::Int(b), &Elt::Int(e)) = (&y, &x) {
+            if e >= 0 {
+                return u32::try_from(e).ok()
+                    .and_then(|e| b.checked_pow(e))
+                    .map(Elt::Int)
+                    .ok_or(Error::Overflow);
+            }
+        }
+        if let (&Elt::Decimal(b), &Elt::Int(e)) = (&y, &x) {
+            if e >= 0 {
+                return u32::try_from(e).ok()
+                    .and_then(|e| b.checked_pow(e, self.scale, self.rounding))
+                    .map(Elt::Decimal)
+                    .ok_or(Error::Overflow);
+            }
+        }
+
+        let (b, e) = self.floats(&y, &x)?;
+        // Zero to a negative power is a pole, not an overflow.
+        if b == 0.0 && e < 0.0 && !self.ieee {
+            return Err(Error::Domain);
+        }
+        self.float(b.powf(e))
+    }
+
+    /// Builds a complex number from its magnitude `r` and angle `theta`.
+    fn rect(&self, r: Elt, theta: Elt) -> Result<Elt> {
+        let (r, theta) = self.floats(&r, &theta)?;
+        self.complex(Complex::from_polar(r, self.radians(theta)))
+    }
+
+    /// Applies a function of one number.
+    fn function(&self, op: Op, e: Elt) -> Result<Elt> {
+        match (op, &e) {
+            (_, &Elt::Bool(_)) | (_, &Elt::Matrix(_)) | (_, &Elt::Str(_)) => return Err(Error::Type),
+            (_, Elt::Quantity(q)) => return self.quantity_function(op, q),
+            (Op::Abs, &Elt::Int(i)) => return i.checked_abs().map(Elt::Int).ok_or(Error::Overflow),
+            (Op::Abs, &Elt::Decimal(d)) => return d.checked_abs().map(Elt::Decimal).ok_or(Error::Overflow),
+            (Op::Abs, &Elt::Float(x)) => return Ok(Elt::Float(x.abs())),
+            (Op::Abs, &Elt::Complex(z)) => return self.float(z.abs()),
+            (Op::Conj, &Elt::Complex(z)) => return Ok(Elt::Complex(z.conj())),
+            (Op::Arg, _) => return self.float(self.angle_unit(e.as_complex().ok_or(Error::Type)?.arg())),
+            (_, &Elt::Complex(z)) => return self.complex_function(op, z),
+            (Op::Floor, &Elt::Float(x)) => return Ok(Elt::Float(x.floor())),
+            (Op::Ceil, &Elt::Float(x)) => return Ok(Elt::Float(x.ceil())),
+            (Op::Round, &Elt::Float(x)) => return Ok(Elt::Float(x.round())),
+            (Op::Floor, &Elt::Decimal(d)) => return d.floor().map(Elt::Decimal).ok_or(Error::Overflow),
+            (Op::Ceil, &Elt::Decimal(d)) => return d.ceil().map(Elt::Decimal).ok_or(Error::Overflow),
+            (Op::Round, &Elt::Decimal(d)) => return d.round_whole().map(Elt::Decimal).ok_or(Error::Overflow),
+            // Real numbers are their own conjugate, integers already whole.
+            (Op::Conj, _) | (Op::Floor, _) | (Op::Ceil, _) | (Op::Round, _) => return Ok(e),
+            _ => {},
+        }
+
+        let x = e.as_float().ok_or(Error::Type)?;
+        let in_domain = match op {
+            Op::Sqrt => x >= 0.0,
+            Op::Ln | Op::Log10 | Op::Log2 => x > 0.0,
+            Op::Asin | Op::Acos => x.abs() <= 1.0,
+            Op::Acosh => x >= 1.0,
+            Op::Atanh => x.abs() < 1.0,
+            _ => true,
+        };
+        if !in_domain && !self.ieee {
+            return Err(Error::Domain);
+        }
+
+        let r = match op {
+            Op::Sqrt => x.sqrt(),
+            Op::Exp => x.exp(),
+            Op::Ln => x.ln(),
+            Op::Log10 => x.log10(),
+            Op::Log2 => x.log2(),
+            Op::Sin => self.radians(x).sin(),
+            Op::Cos => self.radians(x).cos(),
+            Op::Tan => self.radians(x).tan(),
+            Op::Asin => self.angle_unit(x.asin()),
+            Op::Acos => self.angle_unit(x.acos()),
+            Op::Atan => self.angle_unit(x.atan()),
+            Op::Sinh => x.sinh(),
+            Op::Cosh => x.cosh(),
+            Op::Tanh => x.tanh(),
+            Op::Asinh => x.asinh(),
+            Op::Acosh => x.acosh(),
+            Op::Atanh => x.atanh(),
+            _ => return Err(Error::Type),
+        };
+        self.float(r)
+    }
+
+    /// Applies a function of one number to a complex number. Only `sqrt`,
+    /// `exp` and `ln` are defined on complex numbers.
+    fn complex_function(&self, op: Op, z: Complex) -> Result<Elt> {
+        match op {
+            Op::Sqrt => self.complex(z.sqrt()),
+            Op::Exp => self.complex(z.exp()),
+            Op::Ln if z.is_zero() && !self.ieee => Err(Error::Domain),
+            Op::Ln => self.complex(z.ln()),
+            _ => Err(Error::Type),
+        }
+    }
+
+    /// Applies a function of one number to a quantity. The others are only
+    /// defined on plain numbers.
+    fn quantity_function(&self, op: Op, q: &Quantity) -> Result<Elt> {
+        match op {
+            Op::Sqrt => self.quantity(q.sqrt().ok_or(Error::Units)?),
+            Op::Abs => Ok(Elt::Quantity(q.map(f64::abs))),
+            Op::Floor => Ok(Elt::Quantity(q.map(f64::floor))),
+            Op::Ceil => Ok(Elt::Quantity(q.map(f64::ceil))),
+            Op::Round => Ok(Elt::Quantity(q.map(f64::round))),
+            Op::Conj => Ok(Elt::Quantity(q.clone())),
+            _ => Err(Error::Units),
+        }
+    }
+
+    /// Applies a matrix operation: `identity` takes a size, the others a
+    /// matrix.
+    fn matrix_function(&self, op: Op, e: Elt) -> Result<Elt> {
+        let m = match (op, e) {
+            (Op::Identity, Elt::Int(n)) => {
+                let n = usize::try_from(n).map_err(|_| Error::Domain)?;
+                self.limits.check_cells(n, n)?;
+                return Matrix::identity(n).map(Elt::Matrix).ok_or(Error::Domain);
+            },
+            (Op::Identity, _) => return Err(Error::Type),
+            (_, Elt::Matrix(m)) => m,
+            _ => return Err(Error::Type),
+        };
+        match op {
+            Op::Transpose => Ok(Elt::Matrix(m.transpose())),
+            Op::Det => self.float(m.det().ok_or(Error::Shape)?),
+            _ => Err(Error::Type),
+        }
+    }
+
+    /// Applies a number theory function of one integer, which may push
+    /// any number of values.
+    fn number_function(&self, op: Op, n: i64) -> Result<Vec<Elt>> {
+        let mut table = self.primes.borrow_mut();
+        if op == Op::IsPrime {
+            return Ok(vec![Elt::Bool(n > 0 && table.is_prime(n as u64))]);
+        }
+        if n < 1 {
+            return if op == Op::Primes { Ok(vec![]) } else { Err(Error::Domain) };
+        }
+        let n = n as u64;
+        let ints = |v: Vec<u64>| v.into_iter().map(|p| Elt::Int(p as i64)).collect();
+        match op {
+            Op::Primes => {
+                let n = u32::try_from(n).map_err(|_| Error::Domain)?;
+                let primes = table.primes_below(n).ok_or(Error::Domain)?;
+                Ok(primes.iter().map(|&p| Elt::Int(i64::from(p))).collect())
+            },
+            Op::Factor => Ok(ints(table.factor(n))),
+            Op::Totient => Ok(ints(vec![table.totient(n)])),
+            _ => Err(Error::Type),
+        }
+    }
+
+    /// Applies a number theory function of two integers, `b` pushed first.
+    fn integers(&self, op: Op, b: i64, a: i64) -> Result<Elt> {
+        let g = primes::gcd(b.unsigned_abs(), a.unsigned_abs());
+        let r = match op {
+            Op::Gcd => i64::try_from(g).ok(),
+            Op::Lcm if g == 0 => Some(0),
+            Op::Lcm => (b.unsigned_abs() / g).checked_mul(a.unsigned_abs()).and_then(|l| i64::try_from(l).ok()),
+            Op::ModInv if a < 1 => return Err(Error::Domain),
+            Op::ModInv => return primes::mod_inv(b, a).map(Elt::Int).ok_or(Error::Domain),
+            _ => return Err(Error::Type),
+        };
+        r.map(Elt::Int).ok_or(Error::Overflow)
+    }
+
+    /// `b` to the power `e` modulo `m`.
+    fn mod_pow(&self, b: i64, e: i64, m: i64) -> Result<Elt> {
+        if m < 1 {
+            return Err(Error::Domain);
+        }
+        let b = if e < 0 { primes::mod_inv(b, m).ok_or(Error::Domain)? } else { b.rem_euclid(m) };
+        Ok(Elt::Int(primes::mod_pow(b as u64, e.unsigned_abs(), m as u64) as i64))
+    }
+
+    /// Applies a binary bitwise operation to `y` (pushed first) and `x`.
+    fn bitwise(&self, op: Op, y: Elt, x: Elt) -> Result<Elt> {
+        let bits = self.word_size;
+        let (y, x) = match (y, x) {
+            (Elt::Bool(y), Elt::Bool(x)) => return match op {
+                Op::And => Ok(Elt::Bool(y && x)),
+                Op::Or => Ok(Elt::Bool(y || x)),
+                Op::Xor => Ok(Elt::Bool(y ^ x)),
+                _ => Err(Error::Type),
+            },
+            (Elt::Int(y), Elt::Int(x)) => (y, x),
+            _ => return Err(Error::Type),
+        };
+
+        let w = to_word(y, bits);
+        // Shifting out every bit leaves zero; rotating goes around the word.
+        let shl = |n: u64| if n < u64::from(bits) { w << n } else { 0 };
+        let shr = |n: u64| if n < u64::from(bits) { w >> n } else { 0 };
+        let rol = |n: i64| {
+            let n = n.rem_euclid(i64::from(bits)) as u32;
+            if n == 0 { w } else { (w << n) | (w >> (bits - n)) }
+        };
+        let r = match op {
+            Op::And => w & to_word(x, bits),
+            Op::Or => w | to_word(x, bits),
+            Op::Xor => w ^ to_word(x, bits),
+            Op::Shl if x >= 0 => shl(x as u64),
+            Op::Shl => shr(x.unsigned_abs()),
+            Op::Shr if x >= 0 => shr(x as u64),
+            Op::Shr => shl(x.unsigned_abs()),
+            Op::Rol => rol(x),
+            Op::Ror => rol(x.wrapping_neg()),
+            _ => return Err(Error::Type),
+        };
+        Ok(Elt::Int(from_word(to_word(r as i64, bits), bits)))
+    }
+
+    fn not(&self, e: Elt) -> Result<Elt> {
+        match e {
+            Elt::Int(i) => Ok(Elt::Int(from_word(to_word(!i, self.word_size), self.word_size))),
+            Elt::Decimal(_) | Elt::Float(_) | Elt::Complex(_) | Elt::Quantity(_) | Elt::Matrix(_) |
+            Elt::Str(_) => Err(Error::Type),
+            Elt::Bool(b) => Ok(Elt::Bool(!b)),
+        }
+    }
+
+    fn rand(&mut self, a: Elt) -> Result<Elt> {
+        match a {
+            Elt::Int(i) => Ok(Elt::Int((self.rng().gen::<f64>() * i as f64) as i64)),
+            Elt::Float(x) => Ok(Elt::Float(self.rng().gen::<f64>() * x)),
+            _ => Err(Error::Type),
+        }
+    }
+
+}
+
+#[cfg(test)]
+#[allow(clippy::assertions_on_constants)]
+mod tests {
+    use super::*;
+    use console::Buffer;
+
+    #[test]
+    fn test_pop_empty1() {
+        let mut s = Stack::new();
+
+        let res = s.pop();
+        assert!(res.is_err());
+        if let Err(Error::Underflow) = res { } else { assert!(false); }
+    }
+
+    #[test]
+    fn test_pop_empty2() {
+        let mut s = Stack::new();
+        s.push(Elt::Int(0)).unwrap();
+
+        let res = s.pop();
+        assert!(res.is_ok());
+
+        let res = s.pop();
+        assert!(res.is_err());
+        if let Err(Error::Underflow) = res { } else { assert!(false); }
+    }
+
+    #[test]
+    fn test_iter() {
+        let mut s = Stack::new();
+        s.push(Elt::Int(1)).unwrap();
+        s.push(Elt::Bool(true)).unwrap();
+
+        let elts: Vec<&Elt> = s.iter().collect();
+        assert_eq!(elts, vec![&Elt::Bool(true), &Elt::Int(1)]);
+        assert_eq!(s.depth(), 2);
+    }
+
+    #[test]
+    fn test_snapshot_restore() {
+        let mut s = Stack::new();
+        s.push(Elt::Int(1)).unwrap();
+        s.push(Elt::Int(2)).unwrap();
+        let snap = s.snapshot();
+        assert!(s.is_at(&snap));
+
+        s.eval(Op::Add).unwrap();
+        s.push(Elt::Bool(true)).unwrap();
+        assert!(!s.is_at(&snap));
+        assert_eq!(s.depth(), 2);
+
+        s.restore(snap.clone());
+        assert_eq!(s.depth(), 2);
+        assert_eq!(s.pop().unwrap(), Elt::Int(2));
+        assert_eq!(s.pop().unwrap(), Elt::Int(1));
+
+        // Popping from the stack left the snapshot alone.
+        s.restore(snap);
+        assert_eq!(s.pop().unwrap(), Elt::Int(2));
+    }
+
+    #[test]
+    fn test_drop_long_stack() {
+        let mut s = Stack::new();
+        for i in 0..1_000_000 {
+            s.push(Elt::Int(i)).unwrap();
+        }
+        let _snap = s.snapshot();
+    }
+
+    fn levels(s: &Stack) -> Vec<Elt> {
+        s.iter().cloned().collect()
+    }
+
+    #[test]
+    fn test_hp_lift_and_drop() {
+        let mut s = Stack::with_mode(Mode::Hp);
+        assert_eq!(s.depth(), 4);
+        for i in 1..6 {
+            s.push(Elt::Int(i)).unwrap();
+        }
+        // 1 was lifted out of T.
+        assert_eq!(levels(&s), vec![Elt::Int(5), Elt::Int(4), Elt::Int(3), Elt::Int(2)]);
+
+        assert!(s.eval(Op::Add).is_ok());
+        assert_eq!(levels(&s), vec![Elt::Int(9), Elt::Int(3), Elt::Int(2), Elt::Int(2)]);
+
+        // Never underflows: T keeps copying itself down.
+        for _ in 0..6 {
+            assert!(s.pop().is_ok());
+        }
+        assert_eq!(levels(&s), vec![Elt::Int(2); 4]);
+    }
+
+    #[test]
+    fn test_hp_enter() {
+        let mut s = Stack::with_mode(Mode::Hp);
+        s.push(Elt::Int(3)).unwrap();
+        assert!(s.eval(Op::Enter).is_ok());
+        // The next value replaces the copy of X instead of lifting.
+        s.push(Elt::Int(4)).unwrap();
+        assert_eq!(levels(&s), vec![Elt::Int(4), Elt::Int(3), Elt::Int(0), Elt::Int(0)]);
+        assert!(s.eval(Op::Add).is_ok());
+        s.push(Elt::Int(1)).unwrap();
+        assert_eq!(levels(&s), vec![Elt::Int(1), Elt::Int(7), Elt::Int(0), Elt::Int(0)]);
+    }
+
+    #[test]
+    fn test_hp_last_x() {
+        let mut s = Stack::with_mode(Mode::Hp);
+        assert!(s.eval(Op::LastX).is_ok());
+        assert_eq!(s.pop().unwrap(), Elt::Int(0));
+
+        s.push(Elt::Int(10)).unwrap();
+        s.push(Elt::Int(5)).unwrap();
+        assert!(s.eval(Op::Add).is_ok());
+        assert!(s.eval(Op::Swap).is_ok());
+        assert!(s.eval(Op::LastX).is_ok());
+        assert_eq!(levels(&s)[..3], [Elt::Int(5), Elt::Int(0), Elt::Int(15)]);
+    }
+
+    #[test]
+    fn test_unbounded_enter_and_last_x() {
+        let mut s = Stack::new();
+        s.push(Elt::Int(2)).unwrap();
+        assert!(s.eval(Op::Enter).is_ok());
+        assert_eq!(levels(&s), vec![Elt::Int(2), Elt::Int(2)]);
+        assert!(s.eval(Op::Add).is_ok());
+
+        let res = s.eval(Op::LastX);
+        if let Err(Error::Underflow) = res { } else { panic!(); }
+        s.pop().unwrap();
+        let res = s.eval(Op::Enter);
+        if let Err(Error::Underflow) = res { } else { panic!(); }
+    }
+
+    #[test]
+    fn test_set_mode() {
+        let mut s = Stack::new();
+        for i in 1..6 {
+            s.push(Elt::Int(i)).unwrap();
+        }
+        s.set_mode(Mode::Hp);
+        assert_eq!(levels(&s), vec![Elt::Int(5), Elt::Int(4), Elt::Int(3), Elt::Int(2)]);
+        s.set_mode(Mode::Unbounded);
+        assert_eq!(s.depth(), 4);
+        assert_eq!(s.pop().unwrap(), Elt::Int(5));
+
+        let mut s = Stack::new();
+        s.push(Elt::Bool(true)).unwrap();
+        s.set_mode(Mode::Hp);
+        assert_eq!(levels(&s), vec![Elt::Bool(true), Elt::Int(0), Elt::Int(0), Elt::Int(0)]);
+    }
+
+    #[test]
+    fn test_eval_add1() {
+        let mut s = Stack::new();
+        s.push(Elt::Int(1)).unwrap();
+        s.push(Elt::Int(1)).unwrap();
+
+        assert!(s.eval(Op::Add).is_ok());
+        assert_eq!(s.pop().unwrap(), Elt::Int(2));
+    }
+
+    #[test]
+    fn test_eval_add2() {
+        let mut s = Stack::new();
+        s.push(Elt::Int(1)).unwrap();
+        s.push(Elt::Bool(false)).unwrap();
+
+        let res = s.eval(Op::Add);
+        assert!(res.is_err());
+        if let Err(Error::Type) = res { } else { assert!(false); }
+    }
+
+    #[test]
+    fn test_eval_add3() {
+        let mut s = Stack::new();
+        s.push(Elt::Bool(true)).unwrap();
+        s.push(Elt::Bool(false)).unwrap();
+
+        let res = s.eval(Op::Add);
+        assert!(res.is_err());
+        if let Err(Error::Type) = res { } else { assert!(false); }
+    }
+
+    #[test]
+    fn test_eval_mul() {
+        let mut s = Stack::new();
+        s.push(Elt::Int(6)).unwrap();
+        s.push(Elt::Int(-7)).unwrap();
+        assert!(s.eval(Op::Mul).is_ok());
+        assert_eq!(s.pop().unwrap(), Elt::Int(-42));
+
+        s.push(Elt::Int(2)).unwrap();
+        s.push(Elt::Bool(true)).unwrap();
+        let res = s.eval(Op::Mul);
+        if let Err(Error::Type) = res { } else { panic!(); }
+
+        s.push(Elt::Int(1 << 32)).unwrap();
+        s.push(Elt::Int(1 << 32)).unwrap();
+        let res = s.eval(Op::Mul);
+        if let Err(Error::Overflow) = res { } else { panic!(); }
+    }
+
+    fn eval_ints(s: &mut Stack, y: i64, x: i64, op: Op) -> i64 {
+        s.push(Elt::Int(y)).unwrap();
+        s.push(Elt::Int(x)).unwrap();
+        s.eval(op).unwrap();
+        match s.pop().unwrap() {
+            Elt::Int(i) => i,
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_eval_bitwise() {
+        let mut s = Stack::new();
+        assert_eq!(eval_ints(&mut s, 0b1100, 0b1010, Op::And), 0b1000);
+        assert_eq!(eval_ints(&mut s, 0b1100, 0b1010, Op::Or), 0b1110);
+        assert_eq!(eval_ints(&mut s, 0b1100, 0b1010, Op::Xor), 0b0110);
+        s.push(Elt::Int(0)).unwrap();
+        s.eval(Op::Not).unwrap();
+        assert_eq!(s.pop().unwrap(), Elt::Int(-1));
+
+        s.push(Elt::Bool(true)).unwrap();
+        s.push(Elt::Bool(false)).unwrap();
+        s.eval(Op::Or).unwrap();
+        assert_eq!(s.pop().unwrap(), Elt::Bool(true));
+
+        s.push(Elt::Int(1)).unwrap();
+        s.push(Elt::Bool(false)).unwrap();
+        let res = s.eval(Op::And);
+        if let Err(Error::Type) = res { } else { panic!(); }
+    }
+
+    #[test]
+    fn test_eval_shifts() {
+        let mut s = Stack::new();
+        assert_eq!(eval_ints(&mut s, 1, 4, Op::Shl), 16);
+        assert_eq!(eval_ints(&mut s, 16, 4, Op::Shr), 1);
+        assert_eq!(eval_ints(&mut s, 16, -1, Op::Shr), 32);
+        assert_eq!(eval_ints(&mut s, -1, 60, Op::Shr), 15);
+        assert_eq!(eval_ints(&mut s, 1, 64, Op::Shl), 0);
+        assert_eq!(eval_ints(&mut s, 1, i64::MIN, Op::Shl), 0);
+        assert_eq!(eval_ints(&mut s, 1, -1, Op::Rol), i64::MIN);
+        assert_eq!(eval_ints(&mut s, 3, 1, Op::Ror), i64::MIN + 1);
+    }
+
+    #[test]
+    fn test_word_size() {
+        let mut s = Stack::new();
+        assert!(s.set_word_size(12).is_err());
+        s.set_word_size(8).unwrap();
+        assert_eq!(eval_ints(&mut s, 0x81, 1, Op::Rol), 3);
+        assert_eq!(eval_ints(&mut s, 0x40, 1, Op::Shl), -128);
+        assert_eq!(eval_ints(&mut s, -128, 7, Op::Shr), 1);
+        assert_eq!(eval_ints(&mut s, 0x1ff, 0xff, Op::And), -1);
+        s.push(Elt::Int(0x0f)).unwrap();
+        s.eval(Op::Not).unwrap();
+        assert_eq!(s.pop().unwrap(), Elt::Int(-16));
+        assert_eq!(s.scratch().word_size(), 8);
+    }
+
+    fn eval_float(s: &mut Stack, x: Elt, op: Op) -> Result<f64> {
+        s.push(x)?;
+        s.eval(op)?;
+        match s.pop()? {
+            Elt::Float(f) => Ok(f),
+            _ => panic!(),
+        }
+    }
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    #[test]
+    fn test_eval_floats() {
+        let mut s = Stack::new();
+        s.push(Elt::Int(1)).unwrap();
+        s.push(Elt::Float(0.5)).unwrap();
+        assert!(s.eval(Op::Add).is_ok());
+        assert_eq!(s.pop().unwrap(), Elt::Float(1.5));
+
+        s.push(Elt::Float(2.0)).unwrap();
+        s.push(Elt::Int(2)).unwrap();
+        assert!(s.eval(Op::Eq).is_ok());
+        assert_eq!(s.pop().unwrap(), Elt::Bool(true));
+
+        s.push(Elt::Float(1.5)).unwrap();
+        s.push(Elt::Bool(true)).unwrap();
+        let res = s.eval(Op::Mul);
+        if let Err(Error::Type) = res { } else { panic!(); }
+    }
+
+    #[test]
+    fn test_eval_functions() {
+        let mut s = Stack::new();
+        assert_eq!(eval_float(&mut s, Elt::Int(16), Op::Sqrt).unwrap(), 4.0);
+        assert_eq!(eval_float(&mut s, Elt::Int(1000), Op::Log10).unwrap(), 3.0);
+        assert_eq!(eval_float(&mut s, Elt::Int(0), Op::Exp).unwrap(), 1.0);
+        assert!(close(eval_float(&mut s, Elt::Float(consts::FRAC_PI_2), Op::Sin).unwrap(), 1.0));
+        assert_eq!(eval_float(&mut s, Elt::Int(8), Op::Log2).unwrap(), 3.0);
+        assert_eq!(eval_float(&mut s, Elt::Float(-2.5), Op::Round).unwrap(), -3.0);
+        assert_eq!(eval_float(&mut s, Elt::Float(-2.5), Op::Floor).unwrap(), -3.0);
+
+        s.push(Elt::Int(7)).unwrap();
+        assert!(s.eval(Op::Ceil).is_ok());
+        assert_eq!(s.pop().unwrap(), Elt::Int(7));
 
-/// the stack data struct
-pub struct Stack {
-    data: Vec<Elt>,
-}
+        assert!(s.eval(Op::Pi).is_ok());
+        assert_eq!(s.pop().unwrap(), Elt::Float(consts::PI));
+    }
 
-/// Result alias for std::result::Result<T, Error>
-pub type Result<T> = result::Result<T, Error>;
+    #[test]
+    fn test_eval_pow() {
+        let mut s = Stack::new();
+        assert_eq!(eval_ints(&mut s, 2, 10, Op::Pow), 1024);
+        s.push(Elt::Int(2)).unwrap();
+        s.push(Elt::Int(64)).unwrap();
+        let res = s.eval(Op::Pow);
+        if let Err(Error::Overflow) = res { } else { panic!(); }
 
-impl Stack {
-    /// Creates a new Stack
-    pub fn new() -> Stack {
-        Stack {
-            data: Vec::new(),
+        s.push(Elt::Int(2)).unwrap();
+        assert_eq!(eval_float(&mut s, Elt::Int(-1), Op::Pow).unwrap(), 0.5);
+        s.push(Elt::Int(0)).unwrap();
+        let res = eval_float(&mut s, Elt::Int(-1), Op::Pow);
+        if let Err(Error::Domain) = res { } else { panic!(); }
+    }
+
+    #[test]
+    fn test_domain_errors() {
+        let mut s = Stack::new();
+        for &(x, op) in &[(-1.0, Op::Sqrt), (0.0, Op::Ln), (2.0, Op::Asin), (0.5, Op::Acosh)] {
+            let res = eval_float(&mut s, Elt::Float(x), op);
+            if let Err(Error::Domain) = res { } else { panic!(); }
         }
+        let res = eval_float(&mut s, Elt::Int(1000), Op::Exp);
+        if let Err(Error::Overflow) = res { } else { panic!(); }
+
+        s.set_ieee(true);
+        assert!(eval_float(&mut s, Elt::Int(-1), Op::Sqrt).unwrap().is_nan());
+        assert_eq!(eval_float(&mut s, Elt::Int(0), Op::Ln).unwrap(), f64::NEG_INFINITY);
+        assert_eq!(eval_float(&mut s, Elt::Int(1000), Op::Exp).unwrap(), f64::INFINITY);
     }
 
-    /// Pushes a value onto the stack.
-    pub fn push(&mut self, val: Elt) -> Result<()> {
-        self.data.push(val);
-        Ok(())
+    #[test]
+    fn test_degrees() {
+        let mut s = Stack::new();
+        s.set_angle(Angle::Deg);
+        assert!(close(eval_float(&mut s, Elt::Int(90), Op::Sin).unwrap(), 1.0));
+        assert!(close(eval_float(&mut s, Elt::Int(1), Op::Atan).unwrap(), 45.0));
+        // Only trigonometric functions take angles.
+        assert!(close(eval_float(&mut s, Elt::Int(0), Op::Cosh).unwrap(), 1.0));
+        assert_eq!(s.scratch().angle(), Angle::Deg);
     }
 
-    /// Tries to pop a value off of the stack.
-    pub fn pop(&mut self) -> Result<Elt> {
-        self.data.pop().map_or(Err(Error::Underflow), |v| Ok(v))
+    fn complex(re: f64, im: f64) -> Elt {
+        Elt::Complex(Complex::new(re, im))
     }
 
-    /// Tries to evaluate an operator using values on the stack.
-    pub fn eval(&mut self, op: Op) -> Result<()> {
-        match op {
-            Op::Add => {
-                let a = self.pop();
-                let b = self.pop();
-        
-                a.and_then(|a| b.and_then(|b| self.add(a, b)))
-                 .and_then(|r| self.push(r))
-            },
-            Op::Neg => {
-                // how to do this by replacing the last value of the vector?
-                self.pop().and_then(|e| self.neg(e)).and_then(|r| self.push(r))
-            },
-            Op::Eq => {
-                let a = self.pop();
-                let b = self.pop();
+    #[test]
+    fn test_eval_complex() {
+        let mut s = Stack::new();
+        s.push(complex(3.0, 4.0)).unwrap();
+        s.push(Elt::Int(1)).unwrap();
+        assert!(s.eval(Op::Add).is_ok());
+        assert_eq!(s.pop().unwrap(), complex(4.0, 4.0));
 
-                a.and_then(|a| b.and_then(|b| self.eq(a, b)))
-                 .and_then(|r| self.push(r))
-            },
-            Op::Swap => {
-                let a = self.pop();
-                let b = self.pop();
+        s.push(complex(0.0, 1.0)).unwrap();
+        s.push(complex(0.0, 1.0)).unwrap();
+        assert!(s.eval(Op::Mul).is_ok());
+        s.push(Elt::Int(-1)).unwrap();
+        assert!(s.eval(Op::Eq).is_ok());
+        assert_eq!(s.pop().unwrap(), Elt::Bool(true));
 
-                a.and_then(|a| b.and_then(|b| {
-                    self.push(a).and_then(|_|self.push(b))
-                }))
-            },
-            Op::Rand => {
-                let a = self.pop();
+        s.push(Elt::Float(-4.0)).unwrap();
+        let res = s.eval(Op::Sqrt);
+        if let Err(Error::Domain) = res { } else { panic!(); }
+        s.push(complex(-4.0, 0.0)).unwrap();
+        assert!(s.eval(Op::Sqrt).is_ok());
+        assert_eq!(s.pop().unwrap(), complex(0.0, 2.0));
 
-                a.and_then(|a| self.rand(a))
-                 .and_then(|r| self.push(r))
-            },
-            Op::Quit => Err(Error::Quit),
-        }
+        s.push(complex(0.0, 0.0)).unwrap();
+        let res = s.eval(Op::Ln);
+        if let Err(Error::Domain) = res { } else { panic!(); }
+        s.push(complex(1.0, 1.0)).unwrap();
+        let res = s.eval(Op::Sin);
+        if let Err(Error::Type) = res { } else { panic!(); }
+        s.push(complex(1.0, 1.0)).unwrap();
+        s.push(Elt::Bool(true)).unwrap();
+        let res = s.eval(Op::Add);
+        if let Err(Error::Type) = res { } else { panic!(); }
     }
 
-    fn add(&self, a: Elt, b: Elt) -> Result<Elt> {
-        if let Elt::Int(x) = a {
-            if let Elt::Int(y) = b {
-                return Ok(Elt::Int(x + y));
-            }
-        }
+    #[test]
+    fn test_eval_conj_abs_arg() {
+        let mut s = Stack::new();
+        s.push(complex(3.0, 4.0)).unwrap();
+        assert!(s.eval(Op::Conj).is_ok());
+        assert_eq!(s.pop().unwrap(), complex(3.0, -4.0));
+        assert_eq!(eval_float(&mut s, complex(3.0, 4.0), Op::Abs).unwrap(), 5.0);
+        assert_eq!(eval_float(&mut s, Elt::Int(-1), Op::Arg).unwrap(), consts::PI);
 
-        Err(Error::Type)
+        s.push(Elt::Int(-3)).unwrap();
+        assert!(s.eval(Op::Abs).is_ok());
+        assert_eq!(s.pop().unwrap(), Elt::Int(3));
+        s.push(Elt::Int(i64::MIN)).unwrap();
+        let res = s.eval(Op::Abs);
+        if let Err(Error::Overflow) = res { } else { panic!(); }
     }
 
-    fn neg(&self, e: Elt) -> Result<Elt> {
-        match e {
-            Elt::Int(i) => Ok(Elt::Int(-i)),
-            Elt::Bool(b) => Ok(Elt::Bool(!b)),
+    #[test]
+    fn test_eval_polar_rect() {
+        let mut s = Stack::new();
+        s.set_angle(Angle::Deg);
+        s.push(complex(0.0, 2.0)).unwrap();
+        assert!(s.eval(Op::Polar).is_ok());
+        assert_eq!(levels(&s), vec![Elt::Float(90.0), Elt::Float(2.0)]);
+        assert!(s.eval(Op::Rect).is_ok());
+        match s.pop().unwrap() {
+            Elt::Complex(z) => assert!(z.re.abs() < 1e-9 && z.im == 2.0),
+            _ => panic!(),
         }
     }
 
-    fn eq(&self, a: Elt, b: Elt) -> Result<Elt> {
-        match a {
-            Elt::Int(ai) => {
-                match b {
-                    Elt::Int(bi) => Ok(Elt::Bool(ai == bi)),
-                    _ => Err(Error::Type),
-                }
-            },
-            Elt::Bool(ab) => {
-                match b {
-                    Elt::Bool(bb) => Ok(Elt::Bool(ab == bb)),
-                    _ => Err(Error::Type),
-                }
-            }
-        }
+    fn decimal(units: i128, scale: u32) -> Elt {
+        Elt::Decimal(Decimal::new(units, scale))
     }
 
-    fn rand(&self, a: Elt) -> Result<Elt> {
-        match a {
-            Elt::Int(i) => Ok(Elt::Int((rand::random::<f32>() * i as f32) as i32)),
-            _ => Err(Error::Type),
+    #[test]
+    fn test_eval_decimals() {
+        let mut s = Stack::new();
+        s.push(decimal(1999, 2)).unwrap();
+        s.push(Elt::Int(3)).unwrap();
+        assert!(s.eval(Op::Mul).is_ok());
+        s.push(decimal(1, 3)).unwrap();
+        assert!(s.eval(Op::Add).is_ok());
+        assert_eq!(s.pop().unwrap().to_string(), "59.971");
+
+        // Products keep the scale of the stack.
+        s.set_scale(2).unwrap();
+        s.push(decimal(1999, 2)).unwrap();
+        s.push(decimal(75, 3)).unwrap();
+        assert!(s.eval(Op::Mul).is_ok());
+        assert_eq!(s.pop().unwrap().to_string(), "1.50");
+        s.set_rounding(Rounding::Truncate);
+        s.push(decimal(1999, 2)).unwrap();
+        s.push(decimal(75, 3)).unwrap();
+        assert!(s.eval(Op::Mul).is_ok());
+        assert_eq!(s.pop().unwrap().to_string(), "1.49");
+        assert!(s.set_scale(0).is_err());
+
+        s.push(decimal(105, 2)).unwrap();
+        s.push(Elt::Int(2)).unwrap();
+        assert!(s.eval(Op::Pow).is_ok());
+        assert_eq!(s.pop().unwrap().to_string(), "1.10");
+        s.push(decimal(-125, 2)).unwrap();
+        assert!(s.eval(Op::Floor).is_ok());
+        assert_eq!(s.pop().unwrap().to_string(), "-2.00");
+    }
+
+    #[test]
+    fn test_eval_decimals_mixed() {
+        let mut s = Stack::new();
+        s.push(decimal(5, 1)).unwrap();
+        s.push(Elt::Float(0.25)).unwrap();
+        assert!(s.eval(Op::Add).is_ok());
+        assert_eq!(s.pop().unwrap(), Elt::Float(0.75));
+
+        s.push(decimal(30, 2)).unwrap();
+        s.push(decimal(3, 1)).unwrap();
+        assert!(s.eval(Op::Eq).is_ok());
+        assert_eq!(s.pop().unwrap(), Elt::Bool(true));
+        s.push(decimal(20, 1)).unwrap();
+        s.push(Elt::Int(2)).unwrap();
+        assert!(s.eval(Op::Eq).is_ok());
+        assert_eq!(s.pop().unwrap(), Elt::Bool(true));
+
+        assert_eq!(eval_float(&mut s, decimal(4, 0), Op::Sqrt).unwrap(), 2.0);
+        s.push(decimal(1, 1)).unwrap();
+        let res = s.eval(Op::Not);
+        if let Err(Error::Type) = res { } else { panic!(); }
+    }
+
+    fn quantity(s: &mut Stack, value: Elt, unit: &str) -> Result<()> {
+        s.push(value)?;
+        s.apply_unit(&Unit::parse(unit).unwrap())
+    }
+
+    #[test]
+    fn test_units() {
+        let mut s = Stack::new();
+        quantity(&mut s, Elt::Int(5), "m").unwrap();
+        quantity(&mut s, Elt::Int(3), "s").unwrap();
+        let res = s.eval(Op::Add);
+        if let Err(Error::Units) = res { } else { panic!(); }
+
+        quantity(&mut s, Elt::Int(5), "m").unwrap();
+        quantity(&mut s, Elt::Int(50), "cm").unwrap();
+        assert!(s.eval(Op::Add).is_ok());
+        assert_eq!(s.pop().unwrap().to_string(), "5.5 m");
+
+        quantity(&mut s, Elt::Int(2), "kg").unwrap();
+        quantity(&mut s, Elt::Decimal(Decimal::new(981, 2)), "m/s^2").unwrap();
+        assert!(s.eval(Op::Mul).is_ok());
+        assert_eq!(s.pop().unwrap().to_string(), "19.62 N");
+
+        quantity(&mut s, Elt::Int(3), "m").unwrap();
+        s.push(Elt::Int(2)).unwrap();
+        assert!(s.eval(Op::Mul).is_ok());
+        s.push(Elt::Int(2)).unwrap();
+        assert!(s.eval(Op::Pow).is_ok());
+        assert_eq!(s.pop().unwrap().to_string(), "36 m^2");
+
+        // Units that cancel out leave a plain number.
+        quantity(&mut s, Elt::Int(1), "km").unwrap();
+        quantity(&mut s, Elt::Int(2), "1/m").unwrap();
+        assert!(s.eval(Op::Mul).is_ok());
+        assert_eq!(s.pop().unwrap(), Elt::Float(2000.0));
+    }
+
+    #[test]
+    fn test_units_errors() {
+        let mut s = Stack::new();
+        for &op in &[Op::Add, Op::Eq, Op::Pow] {
+            quantity(&mut s, Elt::Int(5), "m").unwrap();
+            s.push(Elt::Float(1.5)).unwrap();
+            let res = s.eval(op);
+            if let Err(Error::Units) = res { } else { panic!("{:?}", op); }
+        }
+        quantity(&mut s, Elt::Int(5), "m").unwrap();
+        s.push(Elt::Bool(true)).unwrap();
+        let res = s.eval(Op::Add);
+        if let Err(Error::Type) = res { } else { panic!(); }
+
+        for &op in &[Op::Sqrt, Op::Sin, Op::Ln] {
+            quantity(&mut s, Elt::Int(5), "m").unwrap();
+            let res = s.eval(op);
+            if let Err(Error::Units) = res { } else { panic!("{:?}", op); }
         }
+        quantity(&mut s, Elt::Int(20), "degC").unwrap();
+        quantity(&mut s, Elt::Int(2), "s").unwrap();
+        let res = s.eval(Op::Mul);
+        if let Err(Error::Units) = res { } else { panic!(); }
+        s.push(Elt::Bool(true)).unwrap();
+        let res = s.apply_unit(&Unit::parse("m").unwrap());
+        if let Err(Error::Type) = res { } else { panic!(); }
     }
 
-}
+    #[test]
+    fn test_convert_units() {
+        let mut s = Stack::new();
+        quantity(&mut s, Elt::Int(10), "ft").unwrap();
+        assert!(s.convert(&Unit::parse("m").unwrap()).is_ok());
+        if let Elt::Quantity(q) = s.pop().unwrap() {
+            assert!(close(q.value(), 3.048));
+            assert_eq!(q.unit().to_string(), "m");
+        } else {
+            panic!();
+        }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+        quantity(&mut s, Elt::Int(100), "degC").unwrap();
+        assert!(s.convert(&Unit::parse("K").unwrap()).is_ok());
+        assert_eq!(s.pop().unwrap().to_string(), "373.15 K");
+
+        quantity(&mut s, Elt::Int(10), "ft").unwrap();
+        let res = s.convert(&Unit::parse("s").unwrap());
+        if let Err(Error::Units) = res { } else { panic!(); }
+        s.push(Elt::Int(10)).unwrap();
+        let res = s.convert(&Unit::parse("m").unwrap());
+        if let Err(Error::Units) = res { } else { panic!(); }
+    }
 
     #[test]
-    fn test_pop_empty1() {
+    fn test_units_compare() {
         let mut s = Stack::new();
+        quantity(&mut s, Elt::Int(1), "km").unwrap();
+        quantity(&mut s, Elt::Int(1000), "m").unwrap();
+        assert!(s.eval(Op::Eq).is_ok());
+        assert_eq!(s.pop().unwrap(), Elt::Bool(true));
 
-        let res = s.pop();
-        assert!(res.is_err());
-        if let Err(Error::Underflow) = res { } else { assert!(false); }
+        let a = Elt::Quantity(Quantity::new(1.0, Unit::parse("mi").unwrap()));
+        let b = Elt::Quantity(Quantity::new(1.0, Unit::parse("km").unwrap()));
+        let c = Elt::Quantity(Quantity::new(1.0, Unit::parse("s").unwrap()));
+        assert_eq!(a.compare(&b), Some(Ordering::Greater));
+        assert_eq!(a.compare(&c), None);
+        assert_eq!(a.compare(&Elt::Int(1)), None);
     }
 
     #[test]
-    fn test_pop_empty2() {
+    fn test_units_hp() {
+        let mut s = Stack::with_mode(Mode::Hp);
+        s.push(Elt::Int(3)).unwrap();
+        assert!(s.eval(Op::Enter).is_ok());
+        assert!(s.apply_unit(&Unit::parse("kg").unwrap()).is_ok());
+        assert_eq!(levels(&s)[..2], [Elt::Quantity(Quantity::new(3.0, Unit::parse("kg").unwrap())), Elt::Int(3)]);
+        assert!(s.eval(Op::LastX).is_ok());
+        assert_eq!(s.pop().unwrap(), Elt::Int(3));
+    }
+
+    fn eval_ints_all(s: &mut Stack, args: &[i64], op: Op) -> Result<Vec<Elt>> {
+        for &a in args {
+            s.push(Elt::Int(a))?;
+        }
+        s.eval(op)?;
+        let mut results: Vec<Elt> = s.iter().cloned().collect();
+        results.reverse();
+        while s.pop().is_ok() {}
+        Ok(results)
+    }
+
+    #[test]
+    fn test_eval_primes() {
         let mut s = Stack::new();
-        s.push(Elt::Int(0)).unwrap();
+        let ints = |v: &[i64]| v.iter().map(|&i| Elt::Int(i)).collect::<Vec<_>>();
+        assert_eq!(eval_ints_all(&mut s, &[12], Op::Primes).unwrap(), ints(&[2, 3, 5, 7, 11]));
+        assert_eq!(eval_ints_all(&mut s, &[-5], Op::Primes).unwrap(), vec![]);
+        assert_eq!(eval_ints_all(&mut s, &[360], Op::Factor).unwrap(), ints(&[2, 2, 2, 3, 3, 5]));
+        assert_eq!(eval_ints_all(&mut s, &[97], Op::IsPrime).unwrap(), vec![Elt::Bool(true)]);
+        assert_eq!(eval_ints_all(&mut s, &[-7], Op::IsPrime).unwrap(), vec![Elt::Bool(false)]);
+        assert_eq!(eval_ints_all(&mut s, &[36], Op::Totient).unwrap(), ints(&[12]));
 
-        let res = s.pop();
-        assert!(res.is_ok());
+        for &(n, op) in &[(0, Op::Factor), (-4, Op::Totient), (i64::MAX, Op::Primes)] {
+            let res = eval_ints_all(&mut s, &[n], op);
+            if let Err(Error::Domain) = res { } else { panic!("{:?}", op); }
+        }
+        s.push(Elt::Float(7.0)).unwrap();
+        let res = s.eval(Op::IsPrime);
+        if let Err(Error::Type) = res { } else { panic!(); }
+    }
 
-        let res = s.pop();
-        assert!(res.is_err());
-        if let Err(Error::Underflow) = res { } else { assert!(false); }
+    #[test]
+    fn test_eval_gcd_lcm() {
+        let mut s = Stack::new();
+        assert_eq!(eval_ints(&mut s, 12, -18, Op::Gcd), 6);
+        assert_eq!(eval_ints(&mut s, 0, 0, Op::Gcd), 0);
+        assert_eq!(eval_ints(&mut s, 4, 6, Op::Lcm), 12);
+        assert_eq!(eval_ints(&mut s, 0, 6, Op::Lcm), 0);
+        let res = eval_ints_all(&mut s, &[i64::MAX, i64::MAX - 1], Op::Lcm);
+        if let Err(Error::Overflow) = res { } else { panic!(); }
+        let res = eval_ints_all(&mut s, &[i64::MIN, 0], Op::Gcd);
+        if let Err(Error::Overflow) = res { } else { panic!(); }
     }
 
     #[test]
-    fn test_eval_add1() {
+    fn test_eval_throw() {
         let mut s = Stack::new();
         s.push(Elt::Int(1)).unwrap();
-        s.push(Elt::Int(1)).unwrap();
+        s.push(Elt::Str("bad".to_string())).unwrap();
+        let res = s.eval(Op::Throw);
+        if let Err(Error::Thrown(Elt::Str(ref v))) = res { assert_eq!(v, "bad"); } else { panic!(); }
+        assert_eq!(res.unwrap_err().kind(), "Thrown");
+        assert_eq!(s.pop().unwrap(), Elt::Int(1));
+        let res = s.eval(Op::Throw);
+        if let Err(Error::Underflow) = res { } else { panic!(); }
+    }
 
+    #[test]
+    fn test_eval_strings() {
+        let mut s = Stack::new();
+        s.push(Elt::Str("ab".to_string())).unwrap();
+        s.push(Elt::Str("cd".to_string())).unwrap();
         assert!(s.eval(Op::Add).is_ok());
-        assert_eq!(s.pop().unwrap(), Elt::Int(2));
+        assert_eq!(s.pop().unwrap(), Elt::Str("abcd".to_string()));
+        assert_eq!(Elt::Str("a b".to_string()).to_string(), "\"a b\"");
+
+        s.set_limits(Limits { size: Some(3), ..Limits::default() });
+        s.push(Elt::Str("ab".to_string())).unwrap();
+        s.push(Elt::Str("cd".to_string())).unwrap();
+        let res = s.eval(Op::Add);
+        if let Err(Error::SizeLimit) = res { } else { panic!(); }
+    }
+
+    /// A stack on a console reading `input`, and that console.
+    fn with_console(input: &[&str]) -> (Stack, Buffer) {
+        let console = Buffer::new(input);
+        let mut s = Stack::new();
+        s.set_console(Box::new(console.clone()));
+        (s, console)
     }
 
     #[test]
-    fn test_eval_add2() {
+    fn test_eval_console() {
+        let (mut s, console) = with_console(&[" 42 ", "hello", "1.5", "oops", "true"]);
+        s.push(Elt::Str("a b".to_string())).unwrap();
+        s.push(Elt::Int(7)).unwrap();
+        s.push(Elt::Int(0x263A)).unwrap();
+        s.push(Elt::Str("x = ".to_string())).unwrap();
+        for &op in &[Op::Print, Op::Emit, Op::Dot, Op::Dot] {
+            assert!(s.eval(op).is_ok());
+        }
+        assert_eq!(console.output(), "x = \u{263A}7\n\"a b\"\n");
+
+        assert!(s.eval(Op::ReadNumber).is_ok());
+        assert_eq!(s.pop().unwrap(), Elt::Int(42));
+        assert!(s.eval(Op::ReadLine).is_ok());
+        assert_eq!(s.pop().unwrap(), Elt::Str("hello".to_string()));
+        assert!(s.eval(Op::ReadNumber).is_ok());
+        assert_eq!(s.pop().unwrap(), Elt::Decimal(Decimal::new(15, 1)));
+        for _ in 0..2 {
+            let res = s.eval(Op::ReadNumber);
+            if let Err(Error::Syntax) = res { } else { panic!(); }
+        }
+        let res = s.eval(Op::ReadLine);
+        if let Err(Error::IO(ref e, None)) = res {
+            assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+        } else { panic!(); }
+
+        s.push(Elt::Int(-1)).unwrap();
+        let res = s.eval(Op::Emit);
+        if let Err(Error::Domain) = res { } else { panic!(); }
+    }
+
+    #[test]
+    fn test_eval_files() {
+        let path = ::std::env::temp_dir().join(format!("hw04-io-{}", ::std::process::id()));
+        let name = Elt::Str(path.to_str().unwrap().to_string());
         let mut s = Stack::new();
+        for (contents, op) in [(Elt::Str("a\n".to_string()), Op::WriteFile),
+                               (Elt::Int(12), Op::AppendFile)] {
+            s.push(contents).unwrap();
+            s.push(name.clone()).unwrap();
+            assert!(s.eval(op).is_ok());
+        }
+        s.push(name.clone()).unwrap();
+        assert!(s.eval(Op::ReadFile).is_ok());
+        assert_eq!(s.pop().unwrap(), Elt::Str("a\n12".to_string()));
+
+        s.set_limits(Limits { size: Some(3), ..Limits::default() });
+        s.push(name.clone()).unwrap();
+        let res = s.eval(Op::ReadFile);
+        if let Err(Error::SizeLimit) = res { } else { panic!(); }
+        fs::remove_file(&path).unwrap();
+
+        s.push(name).unwrap();
+        let res = s.eval(Op::ReadFile);
+        if let Err(Error::IO(_, Some(p))) = res { assert_eq!(p, path); } else { panic!(); }
         s.push(Elt::Int(1)).unwrap();
-        s.push(Elt::Bool(false)).unwrap();
+        let res = s.eval(Op::ReadFile);
+        if let Err(Error::Type) = res { } else { panic!(); }
+    }
 
-        let res = s.eval(Op::Add);
-        assert!(res.is_err());
-        if let Err(Error::Type) = res { } else { assert!(false); }
+    #[test]
+    fn test_eval_plot() {
+        let (mut s, console) = with_console(&[]);
+        let points = Matrix::new(vec![vec![0.0, 1.0], vec![0.5, -2.0]]).unwrap();
+        for &op in &[Op::Table, Op::Plot] {
+            s.push(Elt::Matrix(points.clone())).unwrap();
+            assert!(s.eval(op).is_ok());
+        }
+        let output = console.output();
+        assert!(output.starts_with("  x   y\n  0   1\n0.5  -2\n 1 |*"));
+        assert_eq!(output.lines().count(), 3 + plot::HEIGHT + 2);
+
+        s.push(Elt::Int(1)).unwrap();
+        let res = s.eval(Op::Plot);
+        if let Err(Error::Type) = res { } else { panic!(); }
+        s.push(Elt::Matrix(Matrix::new(vec![vec![1.0, 2.0, 3.0]]).unwrap())).unwrap();
+        let res = s.eval(Op::Table);
+        if let Err(Error::Shape) = res { } else { panic!(); }
     }
 
     #[test]
-    fn test_eval_add3() {
+    fn test_cell_limit() {
         let mut s = Stack::new();
-        s.push(Elt::Bool(true)).unwrap();
-        s.push(Elt::Bool(false)).unwrap();
+        s.set_limits(Limits { cells: Some(4), ..Limits::default() });
+        let m = |rows: Vec<Vec<f32>>| Elt::Matrix(Matrix::new(rows).unwrap());
+        let res = s.push(m(vec![vec![1.0; 5]]));
+        if let Err(Error::CellLimit) = res { } else { panic!(); }
+        assert_eq!(s.depth(), 0);
 
-        let res = s.eval(Op::Add);
-        assert!(res.is_err());
-        if let Err(Error::Type) = res { } else { assert!(false); }
+        // The product is checked before it is made.
+        s.push(m(vec![vec![1.0], vec![2.0], vec![3.0]])).unwrap();
+        s.push(m(vec![vec![1.0, 2.0, 3.0]])).unwrap();
+        let res = s.eval(Op::Mul);
+        if let Err(Error::CellLimit) = res { } else { panic!(); }
+        s.push(m(vec![vec![1.0, 2.0, 3.0]])).unwrap();
+        s.push(m(vec![vec![1.0], vec![2.0], vec![3.0]])).unwrap();
+        assert!(s.eval(Op::Mul).is_ok());
+        assert_eq!(s.pop().unwrap(), m(vec![vec![14.0]]));
+
+        s.push(Elt::Int(2)).unwrap();
+        assert!(s.eval(Op::Identity).is_ok());
+        s.push(Elt::Int(3)).unwrap();
+        let res = s.eval(Op::Identity);
+        if let Err(Error::CellLimit) = res { } else { panic!(); }
+    }
+
+    #[test]
+    fn test_io_denied() {
+        let (mut s, console) = with_console(&["1"]);
+        s.set_limits(Limits::sandbox());
+        s.push(Elt::Int(1)).unwrap();
+        for &op in &[Op::Dot, Op::ReadLine, Op::WriteFile, Op::Plot] {
+            let res = s.eval(op);
+            if let Err(Error::Denied(o)) = res { assert_eq!(o, op); } else { panic!(); }
+        }
+        assert_eq!(console.output(), "");
+        assert_eq!(s.depth(), 1);
+    }
+
+    #[test]
+    fn test_eval_modular() {
+        let mut s = Stack::new();
+        assert_eq!(eval_ints_all(&mut s, &[4, 13, 497], Op::ModPow).unwrap(), vec![Elt::Int(445)]);
+        assert_eq!(eval_ints_all(&mut s, &[-2, 3, 5], Op::ModPow).unwrap(), vec![Elt::Int(2)]);
+        assert_eq!(eval_ints_all(&mut s, &[3, -1, 11], Op::ModPow).unwrap(), vec![Elt::Int(4)]);
+        assert_eq!(eval_ints(&mut s, 3, 11, Op::ModInv), 4);
+
+        for args in &[&[6, 9][..], &[3, 0], &[2, -1, 4], &[2, 3, 0]] {
+            let op = if args.len() == 2 { Op::ModInv } else { Op::ModPow };
+            let res = eval_ints_all(&mut s, args, op);
+            if let Err(Error::Domain) = res { } else { panic!("{:?}", args); }
+        }
+    }
+
+    #[test]
+    fn test_scratch_shares_primes() {
+        let s = Stack::new();
+        s.primes.borrow_mut().primes_below(1000);
+        assert!(Rc::ptr_eq(&s.primes, &s.scratch().primes));
+    }
+
+    #[test]
+    fn test_float_display() {
+        assert_eq!(Elt::Float(1.0).to_string(), "1e0");
+        assert_eq!(Elt::Float(0.1).to_string(), "1e-1");
+        assert_eq!(Elt::Float(1.5e300).to_string(), "1.5e300");
+        assert_eq!(Elt::Float(f64::NAN).to_string(), "NaN");
+    }
+
+    #[test]
+    fn test_words() {
+        assert_eq!(to_word(-1, 8), 0xff);
+        assert_eq!(to_word(-1, 64), u64::MAX);
+        assert_eq!(from_word(0xff, 8), -1);
+        assert_eq!(from_word(0x7f, 8), 127);
+        assert_eq!(from_word(u64::MAX, 64), -1);
     }
 
     #[test]
@@ -293,6 +2625,65 @@ mod tests {
         if let Err(Error::Type) = res { } else { assert!(false); }
     }
 
+    #[test]
+    fn test_eval_rand_seeded() {
+        let draw = |s: &mut Stack| -> Vec<Elt> {
+            (0..10).map(|_| {
+                s.push(Elt::Int(1000)).unwrap();
+                s.eval(Op::Rand).unwrap();
+                s.pop().unwrap()
+            }).collect()
+        };
+
+        let mut a = Stack::new();
+        let mut b = Stack::new();
+        a.seed(42);
+        b.seed(42);
+        assert_eq!(draw(&mut a), draw(&mut b));
+
+        let seed = a.reseed();
+        b.seed(seed);
+        assert_eq!(draw(&mut a), draw(&mut b));
+    }
+
+    #[test]
+    fn test_eval_overflow() {
+        let mut s = Stack::new();
+        s.push(Elt::Int(i64::MAX)).unwrap();
+        s.push(Elt::Int(1)).unwrap();
+        let res = s.eval(Op::Add);
+        if let Err(Error::Overflow) = res { } else { panic!(); }
+
+        s.push(Elt::Int(i64::MIN)).unwrap();
+        let res = s.eval(Op::Neg);
+        if let Err(Error::Overflow) = res { } else { panic!(); }
+    }
+
+    #[test]
+    fn test_depth_limit() {
+        let mut s = Stack::new();
+        s.set_limits(Limits { depth: Some(2), ..Limits::default() });
+        s.push(Elt::Int(1)).unwrap();
+        assert!(s.eval(Op::Enter).is_ok());
+        let res = s.eval(Op::Enter);
+        if let Err(Error::DepthLimit) = res { } else { panic!(); }
+
+        // Registers never grow, so HP mode is not limited.
+        s.set_mode(Mode::Hp);
+        assert!(s.push(Elt::Int(3)).is_ok());
+    }
+
+    #[test]
+    fn test_denied_op() {
+        let mut s = Stack::new();
+        s.set_limits(Limits { ops: Some(vec![Op::Add]), ..Limits::default() });
+        s.push(Elt::Int(10)).unwrap();
+        let res = s.eval(Op::Rand);
+        if let Err(Error::Denied(Op::Rand)) = res { } else { panic!(); }
+        assert_eq!(s.depth(), 1);
+        assert!(!Limits::sandbox().allows(Op::Quit));
+    }
+
     #[test]
     fn test_eval_quit() {
         let mut s = Stack::new();
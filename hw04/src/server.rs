@@ -0,0 +1,353 @@
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, ToSocketAddrs};
+use std::str;
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+
+use serde_json::{self, Value};
+
+use console::{Console, Stderr};
+use output::{error_to_json, stack_to_json};
+use parser::evaluate_located;
+use rpn::{self, Limits, Stack};
+
+/// The most sessions a server keeps at once.
+pub const MAX_SESSIONS: usize = 1024;
+
+/// The longest request line a server reads, in bytes.
+pub const MAX_REQUEST: usize = 1024 * 1024;
+
+/// Answers JSON requests, one per line, each running a program in a
+/// session of its own.
+///
+/// A request is `{"session": "<id>", "program": "<rpn>"}`; the session is
+/// created on first use. `{"session": "<id>", "close": true}` forgets it.
+/// The response is `{"session": "<id>", "stack": [...]}` with the stack
+/// bottom first, plus an `"error"` (see `output::error_to_json`) when the
+/// program failed: the stack is then as the error left it.
+pub struct Server {
+    sessions: HashMap<String, Stack>,
+    /// The limits of every new session.
+    limits: Limits,
+    /// Where connections that fail are reported, standard error unless
+    /// set with `set_console`.
+    console: Box<dyn Console>,
+}
+
+/// A request the server could not make sense of.
+fn bad_request(message: &str) -> Value {
+    json!({ "error": { "kind": "Request", "message": message } })
+}
+
+/// Reads the next line of `input` into `line`, without its newline, if it
+/// is at most `max` bytes long. A longer one is read to its end but not
+/// kept, so it never takes more than `max` bytes. Returns whether the
+/// line fitted, `None` at the end of the input.
+fn read_request<R: BufRead>(input: &mut R, max: usize, line: &mut Vec<u8>) -> io::Result<Option<bool>> {
+    line.clear();
+    if input.by_ref().take(max as u64 + 1).read_until(b'\n', line)? == 0 {
+        return Ok(None);
+    }
+    if line.last() == Some(&b'\n') {
+        line.pop();
+        return Ok(Some(true));
+    }
+    if line.len() <= max {
+        return Ok(Some(true));
+    }
+
+    // Too long: skip the rest of the line.
+    loop {
+        let (skipped, found) = {
+            let buf = input.fill_buf()?;
+            match buf.iter().position(|&b| b == b'\n') {
+                Some(i) => (i + 1, true),
+                None => (buf.len(), buf.is_empty()),
+            }
+        };
+        input.consume(skipped);
+        if found {
+            return Ok(Some(false));
+        }
+    }
+}
+
+impl Server {
+    /// Creates a server whose sessions evaluate within `limits`.
+    pub fn new(limits: Limits) -> Server {
+        Server {
+            sessions: HashMap::new(),
+            limits,
+            console: Box::new(Stderr),
+        }
+    }
+
+    /// Reports failed connections on `console` from now on.
+    pub fn set_console(&mut self, console: Box<dyn Console>) {
+        self.console = console;
+    }
+
+    /// Answers one request line.
+    pub fn answer(&mut self, line: &str) -> Value {
+        let request: Value = match serde_json::from_str(line) {
+            Ok(request) => request,
+            Err(_) => return bad_request("not JSON"),
+        };
+        let id = match request["session"].as_str() {
+            Some(id) => id.to_string(),
+            None => return bad_request("no session id"),
+        };
+
+        if request["close"] == Value::Bool(true) {
+            let closed = self.sessions.remove(&id).is_some();
+            return json!({ "session": id, "closed": closed });
+        }
+        let program = match request["program"].as_str() {
+            Some(program) => program,
+            None => return bad_request("no program"),
+        };
+
+        if !self.sessions.contains_key(&id) && self.sessions.len() >= MAX_SESSIONS {
+            return bad_request("too many sessions");
+        }
+        let limits = &self.limits;
+        let stack = self.sessions.entry(id.clone()).or_insert_with(|| {
+            let mut stack = Stack::new();
+            stack.set_limits(limits.clone());
+            stack
+        });
+
+        let mut response = json!({ "session": id });
+        if let Err(e) = evaluate_located(stack, program) {
+            response["error"] = error_to_json(&e, program);
+        }
+        response["stack"] = stack_to_json(stack);
+        response
+    }
+
+    /// Answers every request line of `input` on `output`, until the end of
+    /// the input. Blank lines are skipped, lines longer than `MAX_REQUEST`
+    /// fail with `SizeLimit`.
+    pub fn serve<R: BufRead, W: Write>(&mut self, mut input: R, mut output: W) -> rpn::Result<()> {
+        let mut line = Vec::new();
+        while let Some(fits) = read_request(&mut input, MAX_REQUEST, &mut line).map_err(rpn::Error::io)? {
+            let response = match str::from_utf8(&line) {
+                _ if !fits => json!({ "error": error_to_json(&rpn::Error::SizeLimit.into(), "") }),
+                Ok(line) if line.trim().is_empty() => continue,
+                Ok(line) => self.answer(line),
+                Err(_) => bad_request("not UTF-8"),
+            };
+            writeln!(output, "{}", response)
+                .and_then(|_| output.flush())
+                .map_err(rpn::Error::io)?;
+        }
+        Ok(())
+    }
+
+    /// Serves `tcp:<host:port>` or `unix:<path>`, one connection at a time;
+    /// sessions outlive the connections. `-` serves stdin and stdout. TCP
+    /// is only served on loopback addresses unless `public` is set, see
+    /// `bind_tcp`.
+    pub fn listen(&mut self, address: &str, public: bool) -> rpn::Result<()> {
+        if address == "-" {
+            let stdin = io::stdin();
+            return self.serve(stdin.lock(), io::stdout());
+        }
+        if let Some(addr) = address.strip_prefix("tcp:") {
+            let listener = bind_tcp(addr, public).map_err(rpn::Error::io)?;
+            for conn in listener.incoming() {
+                let conn = conn.map_err(rpn::Error::io)?;
+                let reader = BufReader::new(conn.try_clone().map_err(rpn::Error::io)?);
+                self.serve_connection(reader, conn)?;
+            }
+            return Ok(());
+        }
+        #[cfg(unix)]
+        {
+            if let Some(path) = address.strip_prefix("unix:") {
+                let listener = UnixListener::bind(path).map_err(rpn::Error::io)?;
+                for conn in listener.incoming() {
+                    let conn = conn.map_err(rpn::Error::io)?;
+                    let reader = BufReader::new(conn.try_clone().map_err(rpn::Error::io)?);
+                    self.serve_connection(reader, conn)?;
+                }
+                return Ok(());
+            }
+        }
+        Err(rpn::Error::Syntax)
+    }
+
+    /// Serves a client until it disconnects. A broken connection only ends
+    /// that client, not the server: it is reported on the console.
+    fn serve_connection<R: BufRead, W: Write>(&mut self, input: R, output: W) -> rpn::Result<()> {
+        match self.serve(input, output) {
+            Err(e) => {
+                let report = format!("Error: connection closed: {:?}\n", e);
+                self.console.write(&report).map_err(rpn::Error::io)
+            },
+            Ok(()) => Ok(()),
+        }
+    }
+}
+
+/// Binds a TCP listener to `addr`, a `host:port` or a port alone, which is
+/// served on the loopback address. Addresses other hosts can reach are
+/// refused unless `public` is set.
+fn bind_tcp(addr: &str, public: bool) -> io::Result<TcpListener> {
+    let addrs: Vec<SocketAddr> = match addr.parse::<u16>() {
+        Ok(port) => vec![SocketAddr::from(([127, 0, 0, 1], port))],
+        Err(_) => addr.to_socket_addrs()?.collect(),
+    };
+    if !public && addrs.iter().any(|a| !a.ip().is_loopback()) {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied,
+                                  "not a loopback address, serve it with --public"));
+    }
+    TcpListener::bind(&addrs[..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use console::Buffer;
+
+    #[test]
+    fn test_sessions() {
+        let mut server = Server::new(Limits::sandbox());
+        let r = server.answer(r#"{"session": "a", "program": "1 2 +"}"#);
+        assert_eq!(r, json!({ "session": "a", "stack": [3] }));
+        let r = server.answer(r#"{"session": "b", "program": "true"}"#);
+        assert_eq!(r, json!({ "session": "b", "stack": [true] }));
+        let r = server.answer(r#"{"session": "a", "program": "4"}"#);
+        assert_eq!(r["stack"], json!([3, 4]));
+
+        let r = server.answer(r#"{"session": "a", "close": true}"#);
+        assert_eq!(r["closed"], json!(true));
+        let r = server.answer(r#"{"session": "a", "program": ""}"#);
+        assert_eq!(r["stack"], json!([]));
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut server = Server::new(Limits::sandbox());
+        let r = server.answer(r#"{"session": "a", "program": "1 2 true +"}"#);
+        assert_eq!(r["error"]["kind"], json!("Type"));
+        assert_eq!(r["error"]["position"], json!(3));
+        assert_eq!(r["stack"], json!([1]));
+
+        let r = server.answer(r#"{"session": "a", "program": "quit"}"#);
+        assert_eq!(r["error"]["kind"], json!("Denied"));
+
+        for request in &["1 2 +", r#"{"program": "1"}"#, r#"{"session": "a"}"#] {
+            let r = server.answer(request);
+            assert_eq!(r["error"]["kind"], json!("Request"));
+            assert!(r.get("session").is_none());
+        }
+    }
+
+    #[test]
+    fn test_serve() {
+        let mut server = Server::new(Limits::sandbox());
+        let input = "{\"session\": \"s\", \"program\": \"5 ~\"}\n\n{\"session\": \"s\", \"program\": \"enter +\"}\n";
+        let mut output = Vec::new();
+        assert!(server.serve(input.as_bytes(), &mut output).is_ok());
+        let lines: Vec<Value> = String::from_utf8(output).unwrap().lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(lines, vec![json!({ "session": "s", "stack": [-5] }),
+                               json!({ "session": "s", "stack": [-10] })]);
+    }
+
+    #[test]
+    fn test_read_request() {
+        let mut input = "12345\n123456\n1234567890\n\n123".as_bytes();
+        let mut line = Vec::new();
+        let mut read = Vec::new();
+        while let Some(fits) = read_request(&mut input, 6, &mut line).unwrap() {
+            read.push((fits, String::from_utf8(line.clone()).unwrap()));
+        }
+        assert_eq!(read, vec![(true, "12345".to_string()), (true, "123456".to_string()),
+                              (false, "1234567".to_string()), (true, String::new()),
+                              (true, "123".to_string())]);
+    }
+
+    #[test]
+    fn test_serve_long_request() {
+        let mut server = Server::new(Limits::sandbox());
+        let long = format!("{{\"session\": \"s\", \"program\": \"{}\"}}", "1 ".repeat(MAX_REQUEST));
+        let input = format!("{}\n{{\"session\": \"s\", \"program\": \"2\"}}\n", long);
+        let mut output = Vec::new();
+        assert!(server.serve(input.as_bytes(), &mut output).is_ok());
+        let lines: Vec<Value> = String::from_utf8(output).unwrap().lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(lines[0]["error"]["kind"], json!("SizeLimit"));
+        assert_eq!(lines[1], json!({ "session": "s", "stack": [2] }));
+    }
+
+    #[test]
+    fn test_word_limit() {
+        let mut server = Server::new(Limits::sandbox());
+        let r = server.answer(r#"{"session": "a", "program": "1 !v"}"#);
+        assert!(r.get("error").is_none());
+        for _ in 1..Limits::sandbox().words.unwrap() {
+            let r = server.answer(r#"{"session": "a", "program": ": w 1 ;"}"#);
+            assert!(r.get("error").is_none());
+        }
+        // Each redefinition counts, storing to a known variable does not.
+        let r = server.answer(r#"{"session": "a", "program": ": w 2 ;"}"#);
+        assert_eq!(r["error"]["kind"], json!("WordLimit"));
+        let r = server.answer(r#"{"session": "a", "program": "5 !v v !u"}"#);
+        assert_eq!(r["error"]["kind"], json!("WordLimit"));
+        let r = server.answer(r#"{"session": "a", "program": "5 !v v"}"#);
+        assert_eq!(r["stack"], json!([5]));
+        let r = server.answer(r#"{"session": "b", "program": ": w 2 ; w"}"#);
+        assert_eq!(r["stack"], json!([2]));
+    }
+
+    #[test]
+    fn test_max_sessions() {
+        let mut server = Server::new(Limits::sandbox());
+        for i in 0..MAX_SESSIONS {
+            let r = server.answer(&format!(r#"{{"session": "{}", "program": ""}}"#, i));
+            assert!(r.get("error").is_none());
+        }
+        let r = server.answer(r#"{"session": "one more", "program": ""}"#);
+        assert_eq!(r["error"]["kind"], json!("Request"));
+        let r = server.answer(r#"{"session": "0", "program": "1"}"#);
+        assert_eq!(r["stack"], json!([1]));
+    }
+
+    #[test]
+    fn test_bind_tcp() {
+        let listener = bind_tcp("0", false).unwrap();
+        assert!(listener.local_addr().unwrap().ip().is_loopback());
+        assert!(bind_tcp("127.0.0.1:0", false).is_ok());
+        let res = bind_tcp("0.0.0.0:0", false);
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        assert!(bind_tcp("0.0.0.0:0", true).is_ok());
+    }
+
+    /// An output that fails like a connection closed by the client.
+    struct Closed;
+
+    impl Write for Closed {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::BrokenPipe.into())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_serve_connection_reports() {
+        let console = Buffer::new(&[]);
+        let mut server = Server::new(Limits::sandbox());
+        server.set_console(Box::new(console.clone()));
+        let input = "{\"session\": \"a\", \"program\": \"1\"}\n";
+        assert!(server.serve_connection(input.as_bytes(), Closed).is_ok());
+        assert!(console.output().starts_with("Error: connection closed: IO("));
+    }
+}
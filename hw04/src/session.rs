@@ -0,0 +1,252 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+
+use bytecode::to_source;
+use parser::Repl;
+use rpn::{self, Angle, Mode, Stack};
+
+/// Writes the state of a stack as the input lines that rebuild it on any
+/// session: its mode and all other settings (`:reset` keeps them), a seed
+/// for the random numbers to come, the variables, every word definition
+/// and the values on the stack. Limits are the session's own and are not
+/// written.
+///
+/// Variables come first, so the words reading them compile. Definitions
+/// are written in the order they were made, shadowed ones included, so
+/// every word calls the same definitions once reloaded.
+/// Dumping reseeds the stack, see `Stack::reseed`.
+pub fn dump(stack: &mut Stack) -> Vec<String> {
+    let dirs: Vec<String> = stack.include_path().iter()
+        .map(|dir| dir.display().to_string())
+        .collect();
+    // The mode goes first: leaving HP mode keeps the registers, `:reset`
+    // then empties the stack in the right mode.
+    let mut lines = vec![
+        format!(":mode {}", if stack.mode() == Mode::Hp { "hp" } else { "unbounded" }),
+        ":reset".to_string(),
+        format!(":word {}", stack.word_size()),
+        format!(":angle {}", if stack.angle() == Angle::Deg { "deg" } else { "rad" }),
+        format!(":scale {}", stack.scale()),
+        format!(":rounding {}", stack.rounding().name()),
+        format!(":ieee {}", if stack.ieee() { "on" } else { "off" }),
+        format!(":path {}", if dirs.is_empty() { "off".to_string() } else { dirs.join(" ") }),
+    ];
+    lines.push(format!(":seed {}", stack.reseed()));
+
+    let words = stack.words();
+    for (name, val) in words.variables() {
+        lines.push(format!("{} !{}", val, name));
+    }
+    for (name, body) in words.definitions() {
+        lines.push(format!(": {} {} ;", name, to_source(body, words)));
+    }
+
+    let mut values: Vec<String> = stack.iter().map(|v| v.to_string()).collect();
+    if !values.is_empty() {
+        values.reverse();
+        lines.push(values.join(" "));
+    }
+    lines
+}
+
+/// Saves the session to `path`, see `dump`.
+pub fn save<P: AsRef<Path>>(stack: &mut Stack, path: P) -> rpn::Result<()> {
+    let mut contents = dump(stack).join("\n");
+    contents.push('\n');
+    fs::write(&path, contents).map_err(rpn::Error::file(&path))
+}
+
+/// Reads the lines of a session or journal file.
+pub fn read_lines<P: AsRef<Path>>(path: P) -> rpn::Result<Vec<String>> {
+    let file = File::open(&path).map_err(rpn::Error::file(&path))?;
+    BufReader::new(file).lines()
+        .collect::<Result<_, _>>()
+        .map_err(rpn::Error::file(&path))
+}
+
+/// Loads a session saved with `save` into `repl`, replacing its stack.
+pub fn load<P: AsRef<Path>>(repl: &mut Repl, path: P) -> rpn::Result<()> {
+    for line in read_lines(path)? {
+        repl.handle(&line)?;
+    }
+    Ok(())
+}
+
+/// An append-only record of every input line of a session.
+///
+/// A journal starts with a dump of the session it was opened on, so
+/// replaying it on a fresh session goes through exactly the same states.
+pub struct Journal {
+    file: File,
+}
+
+impl Journal {
+    /// Opens (or creates) the journal at `path` and appends a dump of
+    /// `stack` to it.
+    pub fn open<P: AsRef<Path>>(path: P, stack: &mut Stack) -> rpn::Result<Journal> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(rpn::Error::file(&path))?;
+
+        let mut journal = Journal { file };
+        for line in dump(stack) {
+            journal.record(&line)?;
+        }
+        Ok(journal)
+    }
+
+    /// Appends a line, making sure it reached the file.
+    pub fn record(&mut self, line: &str) -> rpn::Result<()> {
+        writeln!(self.file, "{}", line.trim_end())
+            .and_then(|_| self.file.flush())
+            .map_err(rpn::Error::io)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use decimal::Rounding;
+    use rpn::Elt;
+
+    fn temp_path(name: &str) -> ::std::path::PathBuf {
+        env::temp_dir().join(format!("hw04-{}-{}", name, ::std::process::id()))
+    }
+
+    fn values(stack: &Stack) -> Vec<Elt> {
+        stack.iter().cloned().collect()
+    }
+
+    #[test]
+    fn test_dump() {
+        let mut repl = Repl::new();
+        repl.evaluate(": one 1 ; : two one one + ; : one 10 ; 5 !x two true").unwrap();
+        let lines = dump(&mut repl.stack);
+        assert_eq!(&lines[..8], [":mode unbounded", ":reset", ":word 64", ":angle rad", ":scale 6",
+                                 ":rounding half-even", ":ieee off", ":path off"]);
+        assert!(lines[8].starts_with(":seed "));
+        assert_eq!(&lines[9..], ["5 !x", ": one 1 ;", ": two one one + ;", ": one 10 ;", "2 true"]);
+
+        repl.stack.set_include_path(vec!["lib".into(), "/usr/share/rpn".into()]);
+        assert!(dump(&mut repl.stack).contains(&":path lib /usr/share/rpn".to_string()));
+    }
+
+    #[test]
+    fn test_save_load() {
+        let path = temp_path("session");
+        let mut repl = Repl::new();
+        repl.evaluate(": one 1 ; : two one one + ; : one 10 ; 5 !x two true").unwrap();
+        save(&mut repl.stack, &path).unwrap();
+
+        let mut loaded = Repl::new();
+        loaded.evaluate("99").unwrap();
+        load(&mut loaded, &path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(values(&loaded.stack), values(&repl.stack));
+
+        // Same words, variables and random numbers.
+        for line in &["two one x", "1000 #", "1000 #"] {
+            repl.evaluate(line).unwrap();
+            loaded.evaluate(line).unwrap();
+        }
+        assert_eq!(values(&loaded.stack), values(&repl.stack));
+    }
+
+    #[test]
+    fn test_save_load_words_using_variables() {
+        let path = temp_path("session-vars");
+        let mut repl = Repl::new();
+        repl.evaluate("5 !x : addx x + ; : setx !x ; 1 addx").unwrap();
+        save(&mut repl.stack, &path).unwrap();
+
+        let mut loaded = Repl::new();
+        load(&mut loaded, &path).unwrap();
+        fs::remove_file(&path).unwrap();
+        for line in &["2 addx", "7 setx 1 addx"] {
+            repl.evaluate(line).unwrap();
+            loaded.evaluate(line).unwrap();
+        }
+        assert_eq!(values(&loaded.stack), values(&repl.stack));
+    }
+
+    #[test]
+    fn test_save_load_strings() {
+        let path = temp_path("session-strings");
+        let mut repl = Repl::new();
+        for text in &["two\nlines\r\n", "a \"quote\"", "C:\\dir\\", "\t", " "] {
+            repl.stack.push(Elt::Str(text.to_string())).unwrap();
+        }
+        repl.stack.push(Elt::Str("x\ny".to_string())).unwrap();
+        repl.evaluate("!s").unwrap();
+        save(&mut repl.stack, &path).unwrap();
+
+        let mut loaded = Repl::new();
+        load(&mut loaded, &path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(values(&loaded.stack), values(&repl.stack));
+        loaded.evaluate("s").unwrap();
+        assert_eq!(loaded.stack.pop().unwrap(), Elt::Str("x\ny".to_string()));
+    }
+
+    #[test]
+    fn test_save_load_hp() {
+        let path = temp_path("session-hp");
+        let mut repl = Repl::new();
+        repl.stack.set_mode(Mode::Hp);
+        repl.stack.set_word_size(16).unwrap();
+        repl.stack.set_angle(Angle::Deg);
+        repl.stack.set_scale(2).unwrap();
+        repl.stack.set_rounding(Rounding::Truncate);
+        repl.evaluate("1 2 3 4").unwrap();
+        save(&mut repl.stack, &path).unwrap();
+
+        let mut loaded = Repl::new();
+        load(&mut loaded, &path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.stack.mode(), Mode::Hp);
+        assert_eq!(loaded.stack.word_size(), 16);
+        assert_eq!(loaded.stack.angle(), Angle::Deg);
+        assert_eq!((loaded.stack.scale(), loaded.stack.rounding()), (2, Rounding::Truncate));
+        assert_eq!(values(&loaded.stack), values(&repl.stack));
+
+        // `:reset` keeps the settings, the save puts back the defaults.
+        let mut fresh = Repl::new();
+        fresh.evaluate("1 2").unwrap();
+        save(&mut fresh.stack, &path).unwrap();
+        load(&mut loaded, &path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.stack.mode(), Mode::Unbounded);
+        assert_eq!((loaded.stack.word_size(), loaded.stack.angle()), (64, Angle::Rad));
+        assert_eq!((loaded.stack.scale(), loaded.stack.rounding()), (6, Rounding::HalfEven));
+        assert_eq!(values(&loaded.stack), values(&fresh.stack));
+    }
+
+    #[test]
+    fn test_load_missing_file() {
+        let path = temp_path("missing");
+        let res = load(&mut Repl::new(), &path);
+        if let Err(rpn::Error::IO(_, Some(p))) = res { assert_eq!(p, path); } else { panic!(); }
+    }
+
+    #[test]
+    fn test_journal_replay() {
+        let path = temp_path("journal");
+        let mut repl = Repl::new();
+        repl.evaluate("7 !x 1 2").unwrap();
+        repl.journal = Some(Journal::open(&path, &mut repl.stack).unwrap());
+        for line in &["x +", "100 #", ":undo", "true ~"] {
+            repl.handle(line).unwrap();
+        }
+
+        let mut replayed = Repl::new();
+        for line in read_lines(&path).unwrap() {
+            replayed.handle(&line).unwrap();
+        }
+        fs::remove_file(&path).unwrap();
+        assert_eq!(values(&replayed.stack), values(&repl.stack));
+    }
+}
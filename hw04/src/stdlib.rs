@@ -0,0 +1,15 @@
+/// The directory the files of the standard library seem to be in.
+pub const DIR: &str = "<std>";
+
+/// The standard library: files of words written in RPN, which `include`
+/// finds by name when no other file of that name exists, e.g.
+/// `include "math.rpn"`.
+pub const FILES: &[(&str, &str)] = &[
+    ("math.rpn", include_str!("../lib/math.rpn")),
+    ("stack.rpn", include_str!("../lib/stack.rpn")),
+];
+
+/// The source of the file of the standard library named `name`.
+pub fn get(name: &str) -> Option<&'static str> {
+    FILES.iter().find(|&&(n, _)| n == name).map(|&(_, text)| text)
+}
@@ -0,0 +1,574 @@
+use std::io::{self, Write};
+
+use termion::event::Key;
+use termion::input::TermRead;
+use termion::raw::IntoRawMode;
+use termion::screen::AlternateScreen;
+use termion::{clear, cursor, terminal_size};
+
+use bytecode;
+use commands::is_command;
+use console::Buffer;
+use parser::{op_info, Repl, OPERATIONS};
+use rpn::{self, Elt};
+
+/// The widest the stack pane gets, in columns.
+const STACK_WIDTH: usize = 32;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+/// A line being edited, with a cursor between its characters.
+pub struct LineEditor {
+    chars: Vec<char>,
+    cursor: usize,
+}
+
+impl LineEditor {
+    pub fn text(&self) -> String {
+        self.chars.iter().collect()
+    }
+
+    /// Replaces the text, with the cursor at its end.
+    pub fn set(&mut self, text: &str) {
+        self.chars = text.chars().collect();
+        self.cursor = self.chars.len();
+    }
+
+    /// Empties the line and returns what it held.
+    pub fn take(&mut self) -> String {
+        let text = self.text();
+        self.set("");
+        text
+    }
+
+    pub fn insert(&mut self, c: char) {
+        self.chars.insert(self.cursor, c);
+        self.cursor += 1;
+    }
+
+    /// Inserts a token, with spaces around it to keep it apart from the
+    /// ones next to it.
+    pub fn insert_token(&mut self, token: &str) {
+        if self.cursor > 0 && self.chars[self.cursor - 1] != ' ' {
+            self.insert(' ');
+        }
+        for c in token.chars() {
+            self.insert(c);
+        }
+        if self.chars.get(self.cursor) != Some(&' ') {
+            self.insert(' ');
+        }
+    }
+
+    /// Deletes the character before the cursor.
+    pub fn backspace(&mut self) {
+        if self.cursor > 0 {
+            self.cursor -= 1;
+            self.chars.remove(self.cursor);
+        }
+    }
+
+    /// Deletes the character under the cursor.
+    pub fn delete(&mut self) {
+        if self.cursor < self.chars.len() {
+            self.chars.remove(self.cursor);
+        }
+    }
+
+    pub fn left(&mut self) {
+        self.cursor = self.cursor.saturating_sub(1);
+    }
+
+    pub fn right(&mut self) {
+        self.cursor = (self.cursor + 1).min(self.chars.len());
+    }
+
+    pub fn home(&mut self) {
+        self.cursor = 0;
+    }
+
+    pub fn end(&mut self) {
+        self.cursor = self.chars.len();
+    }
+
+    /// The token the cursor is in or just after, empty between tokens.
+    pub fn word(&self) -> String {
+        let start = self.chars[..self.cursor].iter()
+            .rposition(|&c| c == ' ')
+            .map_or(0, |i| i + 1);
+        let end = self.chars[self.cursor..].iter()
+            .position(|&c| c == ' ')
+            .map_or(self.chars.len(), |i| self.cursor + i);
+        self.chars[start..end].iter().collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Which pane the keys go to.
+enum Focus {
+    Input,
+    Stack,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// A line of the history pane's log and what running it showed.
+struct Entry {
+    /// The line typed, `None` for a message about the session.
+    line: Option<String>,
+    output: String,
+}
+
+/// A full-screen REPL: the stack on the left, the lines run so far and
+/// what they printed (or help on the operations) on the right, and the
+/// line being edited at the bottom.
+///
+/// Values of the stack can be selected to copy them into the line or drop
+/// them. The debugger talks over the standard input and output, so it only
+/// runs in the plain REPL.
+pub struct Tui {
+    pub repl: Repl,
+    /// The console of the stack, whose output goes to the log.
+    console: Buffer,
+    input: LineEditor,
+    log: Vec<Entry>,
+    /// The lines typed, oldest first, and the one recalled with the arrow
+    /// keys, if any.
+    recall: Vec<String>,
+    recalled: Option<usize>,
+    focus: Focus,
+    /// The level of the value selected in the stack pane, 0 for the top.
+    selected: usize,
+    /// Whether the right pane shows help rather than the log.
+    help: bool,
+    /// Set once the session is over.
+    pub done: bool,
+}
+
+impl Tui {
+    /// Takes over `repl`, whose output from now on goes to the log.
+    pub fn new(mut repl: Repl) -> Tui {
+        let console = Buffer::new(&[]);
+        repl.stack.set_console(Box::new(console.clone()));
+        Tui {
+            repl,
+            console,
+            input: LineEditor::default(),
+            log: Vec::new(),
+            recall: Vec::new(),
+            recalled: None,
+            focus: Focus::Input,
+            selected: 0,
+            help: false,
+            done: false,
+        }
+    }
+
+    /// Adds a message to the log.
+    pub fn report(&mut self, message: &str) {
+        self.log.push(Entry { line: None, output: message.to_string() });
+    }
+
+    /// Handles a key press.
+    pub fn key(&mut self, key: Key) {
+        match key {
+            Key::Ctrl('c') => self.done = true,
+            Key::Ctrl('d') if self.input.chars.is_empty() => self.done = true,
+            Key::F(1) => self.help = !self.help,
+            Key::Char('\t') if self.focus == Focus::Input && self.repl.stack.depth() > 0 => {
+                self.focus = Focus::Stack;
+                self.selected = 0;
+            },
+            Key::Char('\t') | Key::Esc if self.focus == Focus::Stack => self.focus = Focus::Input,
+            _ if self.focus == Focus::Stack => self.stack_key(key),
+            _ => self.input_key(key),
+        }
+    }
+
+    fn input_key(&mut self, key: Key) {
+        match key {
+            Key::Char('\n') => self.submit(),
+            Key::Char('\t') => {},
+            Key::Char(c) => self.input.insert(c),
+            Key::Backspace => self.input.backspace(),
+            Key::Delete => self.input.delete(),
+            Key::Left => self.input.left(),
+            Key::Right => self.input.right(),
+            Key::Home | Key::Ctrl('a') => self.input.home(),
+            Key::End | Key::Ctrl('e') => self.input.end(),
+            Key::Esc => {
+                self.input.set("");
+                self.recalled = None;
+            },
+            Key::Up => self.recall_line(-1),
+            Key::Down => self.recall_line(1),
+            _ => {},
+        }
+    }
+
+    fn stack_key(&mut self, key: Key) {
+        let depth = self.repl.stack.depth();
+        match key {
+            Key::Up | Key::Char('k') => self.selected = (self.selected + 1).min(depth.saturating_sub(1)),
+            Key::Down | Key::Char('j') => self.selected = self.selected.saturating_sub(1),
+            Key::Char('c') | Key::Char('\n') => {
+                if let Some(v) = self.repl.stack.iter().nth(self.selected) {
+                    self.input.insert_token(&v.to_string());
+                }
+                self.focus = Focus::Input;
+            },
+            Key::Char('d') | Key::Delete | Key::Backspace => self.drop_selected(),
+            _ => {},
+        }
+    }
+
+    /// Moves through the lines typed, `-1` back and `1` forward. Going
+    /// forward past the last one empties the line.
+    fn recall_line(&mut self, step: isize) {
+        if self.recall.is_empty() {
+            return;
+        }
+        let last = self.recall.len() - 1;
+        self.recalled = match (self.recalled, step < 0) {
+            (None, true) => Some(last),
+            (None, false) => None,
+            (Some(i), true) => Some(i.saturating_sub(1)),
+            (Some(i), false) if i < last => Some(i + 1),
+            (Some(_), false) => None,
+        };
+        match self.recalled {
+            Some(i) => self.input.set(&self.recall[i]),
+            None => self.input.set(""),
+        }
+    }
+
+    /// Runs the line being edited, like the plain REPL would.
+    fn submit(&mut self) {
+        let line = self.input.take();
+        self.recalled = None;
+        if line.trim().is_empty() {
+            return;
+        }
+        self.recall.push(line.clone());
+
+        if self.repl.debugger.is_active() && !is_command(&line) {
+            let output = "Error: the debugger only runs in the plain REPL, see :trace off".to_string();
+            self.log.push(Entry { line: Some(line), output });
+            return;
+        }
+        let result = self.repl.handle(&line);
+        let mut output = self.console.take_output();
+        match result {
+            Err(rpn::Error::Quit) => self.done = true,
+            Err(e) => output.push_str(&format!("Error: {:?}\n", e)),
+            Ok(()) => {},
+        }
+        self.log.push(Entry { line: Some(line), output: output.trim_end().to_string() });
+    }
+
+    /// Drops the value selected in the stack pane. It can be undone like
+    /// a line.
+    fn drop_selected(&mut self) {
+        let stack = &mut self.repl.stack;
+        let before = stack.snapshot();
+        let mut above = Vec::new();
+        for _ in 0..self.selected {
+            above.extend(stack.pop().ok());
+        }
+        let dropped = stack.pop();
+        for v in above.into_iter().rev() {
+            // What was popped fits back.
+            let _ = stack.push(v);
+        }
+        if dropped.is_ok() {
+            self.repl.history.record(before, &self.repl.stack);
+        }
+
+        let depth = self.repl.stack.depth();
+        if depth == 0 {
+            self.focus = Focus::Input;
+        }
+        self.selected = self.selected.min(depth.saturating_sub(1));
+    }
+
+    /// The screen, as `height` lines of at most `width` characters.
+    pub fn render(&self, width: usize, height: usize) -> Vec<String> {
+        let rows = height.saturating_sub(3);
+        let left = (width / 3).min(STACK_WIDTH);
+        let right = width.saturating_sub(left + 1);
+
+        let title = if self.help { " Help" } else { " History" };
+        let mut lines = vec![format!("{}│{}", fit(" Stack", left), fit(title, right))];
+        let stack = last(self.stack_lines(), rows);
+        let other = last(if self.help { self.help_lines() } else { self.log_lines() }, rows);
+        // Both panes are aligned to the bottom.
+        for i in 0..rows {
+            let row = |pane: &[String]| {
+                (i + pane.len()).checked_sub(rows).map_or("", |j| pane[j].as_str()).to_string()
+            };
+            lines.push(format!("{}│{}", fit(&row(&stack), left), fit(&row(&other), right)));
+        }
+
+        let (visible, _) = self.visible_input(width);
+        lines.push(fit(&visible, width));
+        lines.push(fit(self.hints(), width));
+        lines.truncate(height);
+        lines
+    }
+
+    /// Where the cursor goes on the screen, as a column and a row from 0.
+    pub fn cursor(&self, width: usize, height: usize) -> (usize, usize) {
+        let (_, column) = self.visible_input(width);
+        (column, height.saturating_sub(2))
+    }
+
+    /// The prompt and the part of the line that fits in `width` columns,
+    /// scrolled to keep the cursor in view, and the column of the cursor.
+    fn visible_input(&self, width: usize) -> (String, usize) {
+        let prompt: Vec<char> = self.repl.prompt.chars().collect();
+        let room = width.saturating_sub(prompt.len() + 1).max(1);
+        let start = (self.input.cursor + 1).saturating_sub(room);
+        let shown: String = self.input.chars[start..].iter().collect();
+        (format!("{}{}", self.repl.prompt, shown), prompt.len() + self.input.cursor - start)
+    }
+
+    fn stack_lines(&self) -> Vec<String> {
+        let stack = &self.repl.stack;
+        let values: Vec<&Elt> = stack.iter().collect();
+        values.iter().enumerate().rev()
+            .map(|(level, v)| {
+                let marker = if self.focus == Focus::Stack && level == self.selected { '>' } else { ' ' };
+                format!("{}{:>2}: {}", marker, level + 1,
+                        self.repl.display.value(v, stack.word_size()))
+            })
+            .collect()
+    }
+
+    fn log_lines(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        for entry in &self.log {
+            if let Some(ref line) = entry.line {
+                lines.push(format!("{}{}", self.repl.prompt, line));
+            }
+            lines.extend(entry.output.lines().map(|l| format!("  {}", l)));
+        }
+        lines
+    }
+
+    /// Help on the token being typed: all about it if it is an operation
+    /// or a word, else the operations starting with it.
+    fn help_lines(&self) -> Vec<String> {
+        let word = self.input.word();
+        let words = self.repl.stack.words();
+        if let Some(info) = op_info(&word) {
+            return info.describe().lines().map(String::from).collect();
+        }
+        if let Some(addr) = words.lookup(&word) {
+            return vec![format!(": {} {} ;", word, bytecode::to_source(words.body(addr), words))];
+        }
+        OPERATIONS.iter()
+            .filter(|info| info.token.starts_with(&word))
+            .map(|info| format!("{:<12} {:<16} {}", info.token, info.effect, info.doc))
+            .collect()
+    }
+
+    fn hints(&self) -> &'static str {
+        match self.focus {
+            Focus::Input => " Enter run  ↑↓ recall  Tab stack  F1 help  Ctrl-D quit",
+            Focus::Stack => " ↑↓ select  c copy  d drop  Tab back",
+        }
+    }
+
+    /// Draws the screen on a terminal.
+    fn draw<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let (width, height) = terminal_size()?;
+        let (width, height) = (usize::from(width), usize::from(height));
+        write!(out, "{}", clear::All)?;
+        for (i, line) in self.render(width, height).iter().enumerate() {
+            write!(out, "{}{}", cursor::Goto(1, i as u16 + 1), line)?;
+        }
+        let (x, y) = self.cursor(width, height);
+        write!(out, "{}", cursor::Goto(x as u16 + 1, y as u16 + 1))?;
+        out.flush()
+    }
+
+    /// Runs the session on the terminal until `quit` or Ctrl-D.
+    pub fn run(mut self) -> rpn::Result<()> {
+        // What setting the session up printed.
+        let output = self.console.take_output();
+        if !output.is_empty() {
+            self.report(output.trim_end());
+        }
+        let stdin = io::stdin();
+        let raw = io::stdout().into_raw_mode().map_err(rpn::Error::io)?;
+        let mut screen = AlternateScreen::from(raw);
+        self.draw(&mut screen).map_err(rpn::Error::io)?;
+        for key in stdin.keys() {
+            self.key(key.map_err(rpn::Error::io)?);
+            if self.done {
+                break;
+            }
+            self.draw(&mut screen).map_err(rpn::Error::io)?;
+        }
+        Ok(())
+    }
+}
+
+/// The last `n` lines.
+fn last(mut lines: Vec<String>, n: usize) -> Vec<String> {
+    let extra = lines.len().saturating_sub(n);
+    lines.drain(..extra);
+    lines
+}
+
+/// Cuts or pads text to exactly `width` characters.
+fn fit(text: &str, width: usize) -> String {
+    let mut fitted: String = text.chars().take(width).collect();
+    let len = fitted.chars().count();
+    fitted.extend((len..width).map(|_| ' '));
+    fitted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn typed(tui: &mut Tui, text: &str) {
+        for c in text.chars() {
+            tui.key(Key::Char(c));
+        }
+    }
+
+    fn values(tui: &Tui) -> Vec<Elt> {
+        tui.repl.stack.iter().cloned().collect()
+    }
+
+    #[test]
+    fn test_line_editor() {
+        let mut e = LineEditor::default();
+        for c in "1 3 +".chars() {
+            e.insert(c);
+        }
+        e.home();
+        e.right();
+        e.right();
+        e.delete();
+        e.insert('2');
+        assert_eq!(e.text(), "1 2 +");
+        assert_eq!(e.word(), "2");
+        e.end();
+        e.backspace();
+        e.backspace();
+        assert_eq!(e.word(), "2");
+        e.insert_token("pi");
+        assert_eq!(e.text(), "1 2 pi ");
+        e.left();
+        assert_eq!(e.word(), "pi");
+        assert_eq!(e.take(), "1 2 pi ");
+        e.left();
+        e.backspace();
+        assert_eq!(e, LineEditor::default());
+    }
+
+    #[test]
+    fn test_submit() {
+        let mut tui = Tui::new(Repl::new());
+        typed(&mut tui, "1 2 + 5 .\n");
+        assert_eq!(values(&tui), vec![Elt::Int(3)]);
+        typed(&mut tui, "true +\n");
+        typed(&mut tui, ":word\n");
+        assert_eq!(tui.log_lines(), vec!["> 1 2 + 5 .", "  5", "> true +", "  Error: Type",
+                                         "> :word", "  64"]);
+        assert!(tui.input.text().is_empty());
+
+        tui.key(Key::Up);
+        tui.key(Key::Up);
+        assert_eq!(tui.input.text(), "true +");
+        tui.key(Key::Down);
+        tui.key(Key::Down);
+        assert_eq!(tui.input.text(), "");
+
+        // The console stays the log's across a reset.
+        typed(&mut tui, ":reset\n7 .\n");
+        assert_eq!(tui.log.last().unwrap().output, "7");
+        typed(&mut tui, "quit\n");
+        assert!(tui.done);
+    }
+
+    #[test]
+    fn test_debugger_refused() {
+        let mut tui = Tui::new(Repl::new());
+        typed(&mut tui, ":trace\n1\n");
+        assert!(tui.log[1].output.starts_with("Error: the debugger"));
+        assert!(values(&tui).is_empty());
+    }
+
+    #[test]
+    fn test_select_stack() {
+        let mut tui = Tui::new(Repl::new());
+        tui.key(Key::Char('\t'));
+        assert_eq!(tui.focus, Focus::Input);
+        typed(&mut tui, "1 2 3\n");
+
+        tui.key(Key::Char('\t'));
+        tui.key(Key::Up);
+        assert_eq!(tui.selected, 1);
+        tui.key(Key::Char('c'));
+        assert_eq!(tui.focus, Focus::Input);
+        assert_eq!(tui.input.text(), "2 ");
+
+        tui.key(Key::Char('\t'));
+        tui.key(Key::Up);
+        tui.key(Key::Up);
+        tui.key(Key::Up);
+        tui.key(Key::Char('d'));
+        assert_eq!(values(&tui), vec![Elt::Int(3), Elt::Int(2)]);
+        assert_eq!(tui.selected, 1);
+        tui.key(Key::Down);
+        tui.key(Key::Char('d'));
+        tui.key(Key::Char('d'));
+        assert!(values(&tui).is_empty());
+        assert_eq!(tui.focus, Focus::Input);
+
+        // Drops are undone like lines.
+        tui.key(Key::Esc);
+        typed(&mut tui, ":undo\n");
+        assert_eq!(values(&tui), vec![Elt::Int(2)]);
+    }
+
+    #[test]
+    fn test_help_lines() {
+        let mut tui = Tui::new(Repl::new());
+        typed(&mut tui, ": inc 1 + ;\nsq");
+        assert_eq!(tui.help_lines(), vec![format!("{:<12} {:<16} {}", "sqrt", "x -- √x", "Square root.")]);
+        typed(&mut tui, "rt");
+        assert_eq!(tui.help_lines()[0], "sqrt  ( x -- √x )");
+        tui.input.set("2 inc");
+        assert_eq!(tui.help_lines(), vec![": inc 1 + ;"]);
+    }
+
+    #[test]
+    fn test_render() {
+        let mut tui = Tui::new(Repl::new());
+        typed(&mut tui, "1 2\n3");
+        let screen = tui.render(30, 6);
+        assert_eq!(screen, vec![
+            " Stack    │ History           ",
+            "          │                   ",
+            "  2: 1    │                   ",
+            "  1: 2    │> 1 2              ",
+            "> 3                           ",
+            " Enter run  ↑↓ recall  Tab sta",
+        ]);
+        assert_eq!(tui.cursor(30, 6), (3, 4));
+
+        tui.key(Key::F(1));
+        tui.key(Key::Char('\t'));
+        assert!(tui.render(30, 6)[0].ends_with(" Help              "));
+        assert_eq!(tui.render(30, 6)[3], "> 1: 2    │                   ");
+
+        // A long line scrolls to keep the cursor in view.
+        tui.key(Key::Esc);
+        typed(&mut tui, &"1 ".repeat(20));
+        assert_eq!(tui.render(30, 6)[4], "> 1 1 1 1 1 1 1 1 1 1 1 1 1   ");
+        assert_eq!(tui.cursor(30, 6), (28, 4));
+    }
+}
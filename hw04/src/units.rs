@@ -0,0 +1,363 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::rc::Rc;
+
+/// The SI base units, in the order of the exponents of a `Dim`.
+const BASE_UNITS: [&str; 7] = ["m", "kg", "s", "A", "K", "mol", "cd"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd)]
+/// A physical dimension: the exponent of each of the `BASE_UNITS`, so
+/// an acceleration (m/s^2) is `[1, 0, -2, 0, 0, 0, 0]`.
+pub struct Dim([i8; 7]);
+
+const NONE: Dim = Dim([0, 0, 0, 0, 0, 0, 0]);
+const LENGTH: Dim = Dim([1, 0, 0, 0, 0, 0, 0]);
+const MASS: Dim = Dim([0, 1, 0, 0, 0, 0, 0]);
+const TIME: Dim = Dim([0, 0, 1, 0, 0, 0, 0]);
+const TEMPERATURE: Dim = Dim([0, 0, 0, 0, 1, 0, 0]);
+
+impl Dim {
+    /// The dimension of a product of quantities of dimension `self` and
+    /// `other`.
+    fn product(self, other: Dim) -> Option<Dim> {
+        let mut dim = self;
+        for (e, &o) in dim.0.iter_mut().zip(&other.0) {
+            *e = e.checked_add(o)?;
+        }
+        Some(dim)
+    }
+
+    fn power(self, n: i8) -> Option<Dim> {
+        let mut dim = self;
+        for e in dim.0.iter_mut() {
+            *e = e.checked_mul(n)?;
+        }
+        Some(dim)
+    }
+
+    /// The dimension whose square is `self`, if there is one.
+    fn root(self) -> Option<Dim> {
+        let mut dim = self;
+        for e in dim.0.iter_mut() {
+            if *e % 2 != 0 {
+                return None;
+            }
+            *e /= 2;
+        }
+        Some(dim)
+    }
+}
+
+/// The named units: symbol, size in SI base units, dimension and whether
+/// SI prefixes apply to it.
+const UNITS: &[(&str, f64, Dim, bool)] = &[
+    ("m", 1.0, LENGTH, true),
+    ("g", 1e-3, MASS, true),
+    ("s", 1.0, TIME, true),
+    ("A", 1.0, Dim([0, 0, 0, 1, 0, 0, 0]), true),
+    ("K", 1.0, TEMPERATURE, true),
+    ("mol", 1.0, Dim([0, 0, 0, 0, 0, 1, 0]), true),
+    ("cd", 1.0, Dim([0, 0, 0, 0, 0, 0, 1]), true),
+    ("N", 1.0, Dim([1, 1, -2, 0, 0, 0, 0]), true),
+    ("Pa", 1.0, Dim([-1, 1, -2, 0, 0, 0, 0]), true),
+    ("J", 1.0, Dim([2, 1, -2, 0, 0, 0, 0]), true),
+    ("W", 1.0, Dim([2, 1, -3, 0, 0, 0, 0]), true),
+    ("C", 1.0, Dim([0, 0, 1, 1, 0, 0, 0]), true),
+    ("V", 1.0, Dim([2, 1, -3, -1, 0, 0, 0]), true),
+    ("ohm", 1.0, Dim([2, 1, -3, -2, 0, 0, 0]), true),
+    ("Hz", 1.0, Dim([0, 0, -1, 0, 0, 0, 0]), true),
+    ("L", 1e-3, Dim([3, 0, 0, 0, 0, 0, 0]), true),
+    ("min", 60.0, TIME, false),
+    ("h", 3600.0, TIME, false),
+    ("in", 0.0254, LENGTH, false),
+    ("ft", 0.3048, LENGTH, false),
+    ("yd", 0.9144, LENGTH, false),
+    ("mi", 1609.344, LENGTH, false),
+    ("lb", 0.45359237, MASS, false),
+    ("oz", 0.028349523125, MASS, false),
+];
+
+/// The derived units results are shown in, before falling back to base
+/// units: `N` rather than `kg*m/s^2`. `Hz` and `L` are left out, `1/s`
+/// and `m^3` say more.
+const DERIVED: [&str; 7] = ["N", "Pa", "J", "W", "C", "V", "ohm"];
+
+/// Temperature scales, which do not start at zero: symbol, size of a
+/// degree and zero of the scale, in kelvin.
+const SCALES: &[(&str, f64, f64)] = &[
+    ("degC", 1.0, 273.15),
+    ("degF", 5.0 / 9.0, 459.67 * 5.0 / 9.0),
+];
+
+/// The SI prefixes and their factors.
+const PREFIXES: &[(&str, f64)] = &[
+    ("T", 1e12), ("G", 1e9), ("M", 1e6), ("k", 1e3),
+    ("c", 1e-2), ("m", 1e-3), ("u", 1e-6), ("µ", 1e-6), ("n", 1e-9), ("p", 1e-12),
+];
+
+/// The size and dimension of a unit symbol, maybe with a prefix: `km`.
+fn lookup(symbol: &str) -> Option<(f64, Dim)> {
+    if let Some(&(_, factor, dim, _)) = UNITS.iter().find(|u| u.0 == symbol) {
+        return Some((factor, dim));
+    }
+    PREFIXES.iter().find_map(|&(prefix, scale)| {
+        let rest = symbol.strip_prefix(prefix)?;
+        UNITS.iter()
+            .find(|u| u.0 == rest && u.3)
+            .map(|&(_, factor, dim, _)| (scale * factor, dim))
+    })
+}
+
+#[derive(Debug, Clone, PartialEq, PartialOrd)]
+/// A unit of measure, as written: a product of unit symbols with
+/// optional SI prefixes and integer powers (`m/s^2`, `kg*m^2`, `1/s`),
+/// or a temperature scale (`degC`, `degF`) on its own.
+pub struct Unit {
+    name: String,
+    /// The size of the unit in SI base units.
+    factor: f64,
+    /// The zero of the unit in SI base units, only not 0 for temperature
+    /// scales.
+    offset: f64,
+    dim: Dim,
+}
+
+impl Unit {
+    /// Reads a unit. Each `/` divides by the symbol that follows it only,
+    /// so `m/s/s` is `m/s^2`.
+    pub fn parse(text: &str) -> Option<Unit> {
+        if let Some(&(name, factor, offset)) = SCALES.iter().find(|s| s.0 == text) {
+            return Some(Unit { name: name.to_string(), factor, offset, dim: TEMPERATURE });
+        }
+
+        let (mut rest, mut divide) = match text.strip_prefix("1/") {
+            Some(rest) => (rest, true),
+            None => (text, false),
+        };
+        let mut factor = 1.0;
+        let mut dim = NONE;
+        loop {
+            let end = rest.find(['*', '/']).unwrap_or(rest.len());
+            let (symbol, power) = match rest[..end].split_once('^') {
+                Some((symbol, power)) => (symbol, power.parse::<i8>().ok()?),
+                None => (&rest[..end], 1),
+            };
+            let power = if divide { power.checked_neg()? } else { power };
+            let (size, d) = lookup(symbol)?;
+            factor *= size.powi(i32::from(power));
+            dim = dim.product(d.power(power)?)?;
+
+            if end == rest.len() {
+                break;
+            }
+            divide = rest[end..].starts_with('/');
+            rest = &rest[end + 1..];
+        }
+        Some(Unit { name: text.to_string(), factor, offset: 0.0, dim })
+    }
+
+    /// The SI unit of a dimension: a derived unit if one has that
+    /// dimension, otherwise a product of base units.
+    fn si(dim: Dim) -> Unit {
+        let derived = DERIVED.iter().find(|&&name| lookup(name).map(|u| u.1) == Some(dim));
+        let name = match derived {
+            Some(name) => name.to_string(),
+            None => {
+                let power = |(unit, &e): (&&str, &i8)| match e.abs() {
+                    1 => unit.to_string(),
+                    e => format!("{}^{}", unit, e),
+                };
+                let up: Vec<String> = BASE_UNITS.iter().zip(&dim.0).filter(|u| *u.1 > 0).map(power).collect();
+                let down: Vec<String> = BASE_UNITS.iter().zip(&dim.0).filter(|u| *u.1 < 0).map(power).collect();
+                let up = if up.is_empty() { "1".to_string() } else { up.join("*") };
+                if down.is_empty() { up } else { format!("{}/{}", up, down.join("/")) }
+            },
+        };
+        Unit { name, factor: 1.0, offset: 0.0, dim }
+    }
+
+    /// Whether the unit is a temperature scale, whose zero is not the
+    /// zero of kelvin.
+    pub fn is_scale(&self) -> bool {
+        self.offset != 0.0
+    }
+}
+
+impl fmt::Display for Unit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, PartialOrd)]
+/// A number with a unit: `9.81 m/s^2`.
+///
+/// Quantities of the same dimension add up and compare whatever their
+/// units; a sum is in the unit of its first operand. Products and powers
+/// are in SI units, and are not defined for temperature scales: a
+/// product of degrees Celsius means nothing. Operations return `None`
+/// when the dimensions do not allow them.
+pub struct Quantity {
+    /// The value in `unit`.
+    value: f64,
+    unit: Rc<Unit>,
+}
+
+impl Quantity {
+    pub fn new(value: f64, unit: Unit) -> Quantity {
+        Quantity { value, unit: Rc::new(unit) }
+    }
+
+    /// The value in the unit of the quantity.
+    pub fn value(&self) -> f64 {
+        self.value
+    }
+
+    pub fn unit(&self) -> &Unit {
+        &self.unit
+    }
+
+    /// The value in SI base units.
+    pub fn si_value(&self) -> f64 {
+        self.value * self.unit.factor + self.unit.offset
+    }
+
+    /// Whether the units cancelled out, as in `m/ft`.
+    pub fn is_dimensionless(&self) -> bool {
+        self.unit.dim == NONE
+    }
+
+    /// The same quantity in another unit of the same dimension.
+    pub fn to(&self, unit: &Unit) -> Option<Quantity> {
+        if unit.dim != self.unit.dim {
+            return None;
+        }
+        Some(Quantity::new((self.si_value() - unit.offset) / unit.factor, unit.clone()))
+    }
+
+    /// Applies `f` to the value, keeping the unit.
+    pub fn map<F: FnOnce(f64) -> f64>(&self, f: F) -> Quantity {
+        Quantity { value: f(self.value), unit: self.unit.clone() }
+    }
+
+    /// The sum, in the unit of `self`.
+    pub fn checked_add(&self, other: &Quantity) -> Option<Quantity> {
+        let other = other.to(&self.unit)?;
+        Some(self.map(|v| v + other.value))
+    }
+
+    pub fn product(&self, other: &Quantity) -> Option<Quantity> {
+        if self.unit.is_scale() || other.unit.is_scale() {
+            return None;
+        }
+        let dim = self.unit.dim.product(other.unit.dim)?;
+        Some(Quantity::new(self.si_value() * other.si_value(), Unit::si(dim)))
+    }
+
+    pub fn powi(&self, n: i8) -> Option<Quantity> {
+        if self.unit.is_scale() {
+            return None;
+        }
+        let dim = self.unit.dim.power(n)?;
+        Some(Quantity::new(self.si_value().powi(i32::from(n)), Unit::si(dim)))
+    }
+
+    /// The square root, if the dimension has one: `m^2` does, `m` not.
+    pub fn sqrt(&self) -> Option<Quantity> {
+        if self.unit.is_scale() {
+            return None;
+        }
+        let dim = self.unit.dim.root()?;
+        Some(Quantity::new(self.si_value().sqrt(), Unit::si(dim)))
+    }
+
+    /// Compares two quantities of the same dimension by value, `None` for
+    /// quantities of different dimensions and NaN.
+    pub fn compare(&self, other: &Quantity) -> Option<Ordering> {
+        if self.unit.dim != other.unit.dim {
+            return None;
+        }
+        self.si_value().partial_cmp(&other.si_value())
+    }
+
+    pub fn same_dim(&self, other: &Quantity) -> bool {
+        self.unit.dim == other.unit.dim
+    }
+}
+
+impl fmt::Display for Quantity {
+    /// Formats the quantity as it is written in the input: `9.81 m/s^2`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.value, self.unit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn q(value: f64, unit: &str) -> Quantity {
+        Quantity::new(value, Unit::parse(unit).unwrap())
+    }
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9 * b.abs().max(1.0)
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Unit::parse("m/s^2").unwrap().dim, Dim([1, 0, -2, 0, 0, 0, 0]));
+        assert_eq!(Unit::parse("m/s/s").unwrap().dim, Dim([1, 0, -2, 0, 0, 0, 0]));
+        assert_eq!(Unit::parse("kg*m^2/s^2").unwrap().dim, Unit::parse("J").unwrap().dim);
+        assert_eq!(Unit::parse("1/s").unwrap().dim, Unit::parse("Hz").unwrap().dim);
+        assert_eq!(Unit::parse("s^-1").unwrap().dim, Unit::parse("Hz").unwrap().dim);
+        for u in &["", "x", "m/", "m^", "m^x", "kft", "degC*s", "1/"] {
+            assert!(Unit::parse(u).is_none(), "{}", u);
+        }
+    }
+
+    #[test]
+    fn test_prefixes() {
+        assert!(close(Unit::parse("km").unwrap().factor, 1e3));
+        assert!(close(Unit::parse("kg").unwrap().factor, 1.0));
+        assert!(close(Unit::parse("mm^2").unwrap().factor, 1e-6));
+        assert!(close(Unit::parse("ms").unwrap().factor, 1e-3));
+        assert!(close(Unit::parse("uA").unwrap().factor, 1e-6));
+        // Symbols come before prefixes.
+        assert_eq!(Unit::parse("min").unwrap().factor, 60.0);
+        assert_eq!(Unit::parse("cd").unwrap().dim, Dim([0, 0, 0, 0, 0, 0, 1]));
+    }
+
+    #[test]
+    fn test_convert() {
+        assert!(close(q(10.0, "ft").to(&Unit::parse("m").unwrap()).unwrap().value(), 3.048));
+        assert!(close(q(100.0, "degC").to(&Unit::parse("K").unwrap()).unwrap().value(), 373.15));
+        assert!(close(q(212.0, "degF").to(&Unit::parse("degC").unwrap()).unwrap().value(), 100.0));
+        assert!(close(q(90.0, "km/h").to(&Unit::parse("m/s").unwrap()).unwrap().value(), 25.0));
+        assert!(q(1.0, "m").to(&Unit::parse("s").unwrap()).is_none());
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        let sum = q(5.0, "m").checked_add(&q(100.0, "cm")).unwrap();
+        assert_eq!(sum.to_string(), "6 m");
+        assert!(q(5.0, "m").checked_add(&q(3.0, "s")).is_none());
+
+        let force = q(2.0, "kg").product(&q(9.81, "m/s^2")).unwrap();
+        assert_eq!(force.to_string(), "19.62 N");
+        let area = q(3.0, "m").powi(2).unwrap();
+        assert_eq!(area.to_string(), "9 m^2");
+        assert_eq!(area.sqrt().unwrap().to_string(), "3 m");
+        assert!(q(3.0, "m").sqrt().is_none());
+        assert_eq!(q(2.0, "s").powi(-2).unwrap().to_string(), "0.25 1/s^2");
+        assert!(q(2.0, "m").product(&q(1.0, "1/m")).unwrap().is_dimensionless());
+        assert!(q(20.0, "degC").product(&q(2.0, "s")).is_none());
+    }
+
+    #[test]
+    fn test_compare() {
+        assert_eq!(q(1.0, "km").compare(&q(1000.0, "m")), Some(Ordering::Equal));
+        assert_eq!(q(1.0, "mi").compare(&q(1.0, "km")), Some(Ordering::Greater));
+        assert_eq!(q(1.0, "m").compare(&q(1.0, "s")), None);
+    }
+}